@@ -0,0 +1,35 @@
+use std::{
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use futures::{channel::mpsc, Future, StreamExt};
+
+use crate::{DatagramConnection, DatagramContext};
+
+/// Returns next process wide connection sequence id.
+pub fn next_connection_id() -> usize {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+    NEXT_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+/// Accept future of gateways whose listeners push connections into a channel.
+pub struct ChannelAccepable<'cx, C: DatagramContext> {
+    receiver: &'cx mut mpsc::Receiver<DatagramConnection<C>>,
+}
+
+impl<'cx, C: DatagramContext> ChannelAccepable<'cx, C> {
+    pub fn new(receiver: &'cx mut mpsc::Receiver<DatagramConnection<C>>) -> Self {
+        Self { receiver }
+    }
+}
+
+impl<'cx, C: DatagramContext> Future for ChannelAccepable<'cx, C> {
+    type Output = Option<DatagramConnection<C>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
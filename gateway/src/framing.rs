@@ -0,0 +1,266 @@
+//! Length-delimited [`SyncMessage`] framing over byte streams.
+//!
+//! Every frame is a big endian `u32` payload length followed by one DagCbor encoded message.
+
+use std::{
+    fmt::Debug,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use dimsp_types::{MNSAccount, SyncMessage};
+use futures::{
+    sink,
+    stream::{self, BoxStream},
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Sink, StreamExt,
+};
+use libipld::{cbor::DagCborCodec, codec::Codec};
+use thiserror::Error;
+
+use crate::DatagramContext;
+
+/// Default max payload length of one frame.
+pub const DEFAULT_MAX_FRAME: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum FrameError {
+    #[error("Io: {0}")]
+    Io(#[from] io::Error),
+    #[error("FrameTooLarge: frame length({0}) exceeds max frame size({1})")]
+    FrameTooLarge(usize, usize),
+    #[error("Codec: sync message codec error, {0}")]
+    Codec(String),
+}
+
+pub type FramedInput = BoxStream<'static, Result<SyncMessage, FrameError>>;
+
+pub type FramedOutput = Pin<Box<dyn Sink<SyncMessage, Error = FrameError> + Send>>;
+
+/// Context of gateways exchanging length-delimited frames.
+pub struct FramedContext;
+
+impl DatagramContext for FramedContext {
+    type StreamError = FrameError;
+    type SinkError = FrameError;
+    type Item = SyncMessage;
+    type Context = MNSAccount;
+    type Input = FramedInput;
+    type Output = FramedOutput;
+}
+
+/// DagCbor encode `message` into one frame payload.
+pub fn encode_message(message: &SyncMessage) -> Result<Vec<u8>, FrameError> {
+    DagCborCodec
+        .encode(message)
+        .map_err(|err| FrameError::Codec(err.to_string()))
+}
+
+/// Decode one frame payload written by [`encode_message`].
+pub fn decode_message(buff: &[u8]) -> Result<SyncMessage, FrameError> {
+    DagCborCodec
+        .decode(buff)
+        .map_err(|err| FrameError::Codec(err.to_string()))
+}
+
+/// Observes the frames of one connection, see [`FrameMeter::observe`].
+pub trait FrameObserver: Send + Sync {
+    /// One frame of `len` payload bytes was read.
+    fn frame_in(&self, len: usize);
+
+    /// One frame of `len` payload bytes is sent.
+    fn frame_out(&self, len: usize);
+}
+
+/// Max frame length and frame observers shared by the input and output of one connection.
+///
+/// Layers learn frame lengths from it instead of encoding messages again, and may lower the max
+/// frame length, so oversized frames are refused before they are read or decoded.
+pub struct FrameMeter {
+    max_frame: AtomicUsize,
+    observers: Mutex<Vec<Arc<dyn FrameObserver>>>,
+}
+
+impl FrameMeter {
+    pub fn new(max_frame: usize) -> Self {
+        Self {
+            max_frame: AtomicUsize::new(max_frame),
+            observers: Default::default(),
+        }
+    }
+
+    pub fn max_frame(&self) -> usize {
+        self.max_frame.load(Ordering::Relaxed)
+    }
+
+    /// Lower the max frame length to `max_frame`, a higher value keeps the current one.
+    pub fn limit(&self, max_frame: usize) {
+        self.max_frame.fetch_min(max_frame, Ordering::Relaxed);
+    }
+
+    /// Report the length of every following frame to `observer`.
+    pub fn observe(&self, observer: Arc<dyn FrameObserver>) {
+        self.observers.lock().unwrap().push(observer);
+    }
+
+    fn check(&self, len: usize) -> Result<(), FrameError> {
+        let max_frame = self.max_frame();
+
+        if len > max_frame {
+            return Err(FrameError::FrameTooLarge(len, max_frame));
+        }
+
+        Ok(())
+    }
+
+    /// Check and decode one received frame payload.
+    pub fn recv(&self, buff: &[u8]) -> Result<SyncMessage, FrameError> {
+        self.check(buff.len())?;
+
+        for observer in self.observers.lock().unwrap().iter() {
+            observer.frame_in(buff.len());
+        }
+
+        decode_message(buff)
+    }
+
+    /// Encode `message` into one frame payload to send.
+    pub fn send(&self, message: &SyncMessage) -> Result<Vec<u8>, FrameError> {
+        let buff = encode_message(message)?;
+
+        self.check(buff.len())?;
+
+        for observer in self.observers.lock().unwrap().iter() {
+            observer.frame_out(buff.len());
+        }
+
+        Ok(buff)
+    }
+}
+
+impl Debug for FrameMeter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FrameMeter")
+            .field("max_frame", &self.max_frame())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Read one frame payload, returns [`None`] if the peer closed between frames.
+pub async fn read_frame<R>(reader: &mut R, max_frame: usize) -> Result<Option<Vec<u8>>, FrameError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len = [0u8; 4];
+
+    match reader.read_exact(&mut len).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+
+    if len > max_frame {
+        return Err(FrameError::FrameTooLarge(len, max_frame));
+    }
+
+    let mut buff = vec![0u8; len];
+
+    reader.read_exact(&mut buff).await?;
+
+    Ok(Some(buff))
+}
+
+/// Write `buff` as one frame and flush `writer`.
+pub async fn write_frame<W>(writer: &mut W, buff: &[u8], max_frame: usize) -> Result<(), FrameError>
+where
+    W: AsyncWrite + Unpin,
+{
+    if buff.len() > max_frame {
+        return Err(FrameError::FrameTooLarge(buff.len(), max_frame));
+    }
+
+    writer.write_all(&(buff.len() as u32).to_be_bytes()).await?;
+    writer.write_all(buff).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Decode frames read from `reader`, the stream ends when the peer closes between frames.
+pub fn framed_input<R>(reader: R, max_frame: usize) -> FramedInput
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    metered_input(reader, Arc::new(FrameMeter::new(max_frame)))
+}
+
+/// Encode messages as frames written to `writer`, flushing after every frame.
+pub fn framed_output<W>(writer: W, max_frame: usize) -> FramedOutput
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    metered_output(writer, Arc::new(FrameMeter::new(max_frame)))
+}
+
+/// [`framed_input`] limited and observed by `meter`.
+pub fn metered_input<R>(reader: R, meter: Arc<FrameMeter>) -> FramedInput
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    stream::try_unfold(reader, move |mut reader| {
+        let meter = meter.clone();
+
+        async move {
+            match read_frame(&mut reader, meter.max_frame()).await? {
+                Some(buff) => Ok(Some((meter.recv(&buff)?, reader))),
+                None => Ok(None),
+            }
+        }
+    })
+    .boxed()
+}
+
+/// [`framed_output`] limited and observed by `meter`.
+pub fn metered_output<W>(writer: W, meter: Arc<FrameMeter>) -> FramedOutput
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    Box::pin(sink::unfold(
+        writer,
+        move |mut writer, message: SyncMessage| {
+            let meter = meter.clone();
+
+            async move {
+                let buff = meter.send(&message)?;
+
+                write_frame(&mut writer, &buff, buff.len()).await?;
+
+                Ok(writer)
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::message;
+
+    use super::{decode_message, encode_message, FrameError};
+
+    #[test]
+    fn test_codec() {
+        let buff = encode_message(&message(1)).unwrap();
+
+        assert_eq!(decode_message(&buff).unwrap().id(), 1);
+
+        assert!(matches!(
+            decode_message(&buff[..buff.len() - 1]),
+            Err(FrameError::Codec(_))
+        ));
+    }
+}
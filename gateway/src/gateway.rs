@@ -1,6 +1,10 @@
+use std::sync::Arc;
+
 use dimsp_types::{MNSAccount, SyncMessage};
 use futures::{Future, Sink, SinkExt, Stream, TryStreamExt};
 
+use crate::framing::FrameMeter;
+
 /// Datagram connection implementation
 pub trait DatagramContext {
     type StreamError: std::error::Error + Send + Sync + 'static;
@@ -22,6 +26,8 @@ pub struct DatagramConnection<C: DatagramContext> {
     pub input: C::Input,
     /// Connection output stream.
     pub output: C::Output,
+    /// Frame limit and lengths of framed transports, [`None`] for in-process connections.
+    pub frames: Option<Arc<FrameMeter>>,
 }
 
 impl<C: DatagramContext> DatagramConnection<C> {
@@ -0,0 +1,430 @@
+//! Challenge/response handshake binding a connection to the [`MNSAccount`] owning a public key.
+//!
+//! The server sends a random nonce, the client signs [`handshake_payload`] with the account
+//! private key, and the server resolves the account by public key before handing the connection
+//! out. Messages are DagCbor encoded [`Handshake`] values, one per transport frame.
+//!
+//! Over a secure channel both sides pass its binding, e.g.
+//! [`NoiseStream::handshake_hash`](crate::noise::NoiseStream), or keying material exported from a
+//! TLS or QUIC session with [`EXPORTER_LABEL`], which the client signs along with the nonce. A
+//! signature obtained by a man in the middle is useless in any other channel.
+
+use std::{fmt::Debug, future::Future, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use dimsp_spnetwork::{SpNetwork, SpNetworkError};
+use dimsp_types::{
+    handshake_payload, Handshake, MNSAccount, PublicKey, PublicKeyError, HANDSHAKE_VERSION,
+};
+use futures::{
+    future::{self, Either},
+    AsyncRead, AsyncWrite,
+};
+use futures_timer::Delay;
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid};
+use thiserror::Error;
+
+use crate::framing::{read_frame, write_frame, FrameError};
+
+/// Exporter label (RFC 5705) of the binding of TLS and QUIC sessions.
+pub const EXPORTER_LABEL: &[u8] = b"EXPORTER-dimsp-handshake";
+
+/// Length of the binding exported from TLS and QUIC sessions.
+pub const EXPORTER_LEN: usize = 32;
+
+/// Max length of one handshake frame, unauthenticated peers can't make the server buffer more.
+pub const MAX_HANDSHAKE_FRAME: usize = 16 * 1024;
+
+/// Default deadline of [`server_handshake`].
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("Frame: {0}")]
+    Frame(#[from] FrameError),
+    #[error("Transport: {0}")]
+    Transport(String),
+    #[error("Codec: handshake message codec error, {0}")]
+    Codec(String),
+    #[error("Closed: peer closed during handshake")]
+    Closed,
+    #[error("Unexpected: unexpected handshake message {0}")]
+    Unexpected(String),
+    #[error("Version: unsupported handshake version({0})")]
+    Version(u32),
+    #[error("Server: challenge from server({0}), expect {1}")]
+    Server(String, String),
+    #[error("PublicKey: {0}")]
+    PublicKey(#[from] PublicKeyError),
+    #[error("Authenticate: {0}")]
+    Authenticate(anyhow::Error),
+    #[error("Rejected: server rejected handshake, {0}")]
+    Rejected(String),
+    #[error("Timeout: not established in {0:?}")]
+    Timeout(Duration),
+}
+
+/// Frame transport of the handshake, before the connection exchanges sync messages.
+#[async_trait]
+pub trait HandshakeChannel: Send {
+    async fn send_frame(&mut self, buff: Vec<u8>) -> Result<(), HandshakeError>;
+
+    /// Returns [`None`] if the peer closed the transport.
+    async fn recv_frame(&mut self) -> Result<Option<Vec<u8>>, HandshakeError>;
+}
+
+/// [`HandshakeChannel`] over a byte stream exchanging [`framing`](crate::framing) frames.
+pub struct FramedChannel<'a, R, W> {
+    reader: &'a mut R,
+    writer: &'a mut W,
+}
+
+impl<'a, R, W> FramedChannel<'a, R, W> {
+    pub fn new(reader: &'a mut R, writer: &'a mut W) -> Self {
+        Self { reader, writer }
+    }
+}
+
+#[async_trait]
+impl<'a, R, W> HandshakeChannel for FramedChannel<'a, R, W>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn send_frame(&mut self, buff: Vec<u8>) -> Result<(), HandshakeError> {
+        Ok(write_frame(&mut *self.writer, &buff, MAX_HANDSHAKE_FRAME).await?)
+    }
+
+    async fn recv_frame(&mut self) -> Result<Option<Vec<u8>>, HandshakeError> {
+        Ok(read_frame(&mut *self.reader, MAX_HANDSHAKE_FRAME).await?)
+    }
+}
+
+/// Resolve the account of a public key proven by the handshake.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, pub_key: &PublicKey) -> anyhow::Result<MNSAccount>;
+}
+
+#[async_trait]
+impl<N> Authenticator for N
+where
+    N: SpNetwork + Send + Sync,
+{
+    async fn authenticate(&self, pub_key: &PublicKey) -> anyhow::Result<MNSAccount> {
+        match self.mns_by_pubkey(pub_key).await? {
+            Some(account) => Ok(account),
+            None => Err(SpNetworkError::MNSByPubKey(pub_key.clone()).into()),
+        }
+    }
+}
+
+/// Server side handshake settings shared by gateways.
+#[derive(Clone)]
+pub struct HandshakeConfig {
+    /// Server name signed by clients, so a challenge can't be relayed from another SP.
+    pub server: String,
+    pub authenticator: Arc<dyn Authenticator>,
+    /// Peers that don't finish the handshake in time are dropped, so they can't pin server tasks.
+    pub timeout: Duration,
+}
+
+impl HandshakeConfig {
+    pub fn new<S, A>(server: S, authenticator: A) -> Self
+    where
+        S: Into<String>,
+        A: Authenticator + 'static,
+    {
+        Self {
+            server: server.into(),
+            authenticator: Arc::new(authenticator),
+            timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+}
+
+impl Debug for HandshakeConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HandshakeConfig")
+            .field("server", &self.server)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+async fn send<C: HandshakeChannel>(
+    channel: &mut C,
+    message: &Handshake,
+) -> Result<(), HandshakeError> {
+    let buff = DagCborCodec
+        .encode(message)
+        .map_err(|err| HandshakeError::Codec(err.to_string()))?;
+
+    channel.send_frame(buff).await
+}
+
+async fn recv<C: HandshakeChannel>(channel: &mut C) -> Result<Handshake, HandshakeError> {
+    let buff = channel.recv_frame().await?.ok_or(HandshakeError::Closed)?;
+
+    DagCborCodec
+        .decode(&buff)
+        .map_err(|err| HandshakeError::Codec(err.to_string()))
+}
+
+/// Challenge the peer and returns its resolved account, bound to the client id it signed.
+///
+/// Rejected peers are told the reason before the error returns, peers still handshaking after
+/// [`HandshakeConfig::timeout`] fail with [`HandshakeError::Timeout`].
+pub async fn server_handshake<C: HandshakeChannel>(
+    channel: &mut C,
+    config: &HandshakeConfig,
+    binding: &[u8],
+) -> Result<MNSAccount, HandshakeError> {
+    deadline(config.timeout, challenge(channel, config, binding)).await?
+}
+
+/// Run `future` until `timeout` passed, then fail with [`HandshakeError::Timeout`].
+///
+/// Gateways bound their whole connection setup with it, tls, noise and upgrades included.
+pub async fn deadline<F: Future>(
+    timeout: Duration,
+    future: F,
+) -> Result<F::Output, HandshakeError> {
+    match future::select(Box::pin(future), Delay::new(timeout)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(HandshakeError::Timeout(timeout)),
+    }
+}
+
+async fn challenge<C: HandshakeChannel>(
+    channel: &mut C,
+    config: &HandshakeConfig,
+    binding: &[u8],
+) -> Result<MNSAccount, HandshakeError> {
+    let nonce: [u8; 32] = rand::random();
+
+    send(
+        channel,
+        &Handshake::Challenge(HANDSHAKE_VERSION, config.server.clone(), nonce.to_vec()),
+    )
+    .await?;
+
+    let (pub_key, client_id, signature) = match recv(channel).await? {
+        Handshake::Response(pub_key, client_id, signature) => (pub_key, client_id, signature),
+        message => return Err(HandshakeError::Unexpected(format!("{:?}", message))),
+    };
+
+    let payload = handshake_payload(
+        HANDSHAKE_VERSION,
+        &config.server,
+        &nonce,
+        binding,
+        &client_id,
+    );
+
+    let account = match pub_key.verify(&payload, &signature) {
+        Ok(()) => config
+            .authenticator
+            .authenticate(&pub_key)
+            .await
+            .map_err(HandshakeError::Authenticate),
+        Err(err) => Err(err.into()),
+    };
+
+    match account {
+        Ok(mut account) => {
+            account.client_id = client_id;
+
+            send(channel, &Handshake::Accepted(account.clone())).await?;
+
+            Ok(account)
+        }
+        Err(err) => {
+            _ = send(channel, &Handshake::Rejected(err.to_string())).await;
+
+            Err(err)
+        }
+    }
+}
+
+/// Answer the challenge of `server` with the account key, `sign` signs the challenge payload.
+///
+/// `binding` must match the one the server passes to [`server_handshake`], `client_id` becomes
+/// the [`MNSAccount::client_id`] of the connection.
+pub async fn client_handshake<C, F>(
+    channel: &mut C,
+    server: &str,
+    binding: &[u8],
+    pub_key: PublicKey,
+    client_id: Cid,
+    sign: F,
+) -> Result<MNSAccount, HandshakeError>
+where
+    C: HandshakeChannel,
+    F: FnOnce(&[u8]) -> Vec<u8>,
+{
+    let (version, name, nonce) = match recv(channel).await? {
+        Handshake::Challenge(version, name, nonce) => (version, name, nonce),
+        message => return Err(HandshakeError::Unexpected(format!("{:?}", message))),
+    };
+
+    if version != HANDSHAKE_VERSION {
+        return Err(HandshakeError::Version(version));
+    }
+
+    if name != server {
+        return Err(HandshakeError::Server(name, server.to_owned()));
+    }
+
+    let signature = sign(&handshake_payload(
+        version, &name, &nonce, binding, &client_id,
+    ));
+
+    send(channel, &Handshake::Response(pub_key, client_id, signature)).await?;
+
+    match recv(channel).await? {
+        Handshake::Accepted(account) => Ok(account),
+        Handshake::Rejected(reason) => Err(HandshakeError::Rejected(reason)),
+        message => Err(HandshakeError::Unexpected(format!("{:?}", message))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_std::net::{TcpListener, TcpStream};
+    use dimsp_spnetwork::{mock::MockSpNetwork, SpNetworkError};
+    use dimsp_types::{MNSAccount, PublicKey, PublicKeyBuff};
+    use ed25519_dalek::{Signer, SigningKey};
+    use libipld::{
+        cbor::DagCborCodec,
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use super::{
+        client_handshake, server_handshake, FramedChannel, HandshakeConfig, HandshakeError,
+    };
+
+    async fn handshake(
+        config: &HandshakeConfig,
+        client_binding: &[u8],
+        pub_key: PublicKey,
+        signing_key: &SigningKey,
+    ) -> (
+        Result<MNSAccount, HandshakeError>,
+        Result<MNSAccount, HandshakeError>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let (server, _) = listener.accept().await.unwrap();
+
+        let (mut server_reader, mut server_writer) = (server.clone(), server);
+        let (mut client_reader, mut client_writer) = (client.clone(), client);
+
+        let mut server_channel = FramedChannel::new(&mut server_reader, &mut server_writer);
+        let mut client_channel = FramedChannel::new(&mut client_reader, &mut client_writer);
+
+        futures::join!(
+            server_handshake(&mut server_channel, config, b"channel"),
+            client_handshake(
+                &mut client_channel,
+                "sp.agoramail.io",
+                client_binding,
+                pub_key,
+                client_id(),
+                |payload| signing_key.sign(payload).to_bytes().to_vec()
+            )
+        )
+    }
+
+    fn client_id() -> Cid {
+        Cid::new_v1(DagCborCodec.into(), Code::Blake2b256.digest(b"phone"))
+    }
+
+    fn ed25519(signing_key: &SigningKey) -> PublicKey {
+        PublicKey::Ed25519(PublicKeyBuff(signing_key.verifying_key().to_bytes()))
+    }
+
+    #[async_std::test]
+    async fn test_handshake() {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let unknown_key = SigningKey::from_bytes(&[8u8; 32]);
+
+        let mut account = MNSAccount::default();
+        account.uns.id = 7;
+        account.pub_key = ed25519(&signing_key);
+
+        let mut network = MockSpNetwork::default();
+        network.add_mns(account);
+
+        let config = HandshakeConfig::new("sp.agoramail.io", network);
+
+        let (server, client) =
+            handshake(&config, b"channel", ed25519(&signing_key), &signing_key).await;
+
+        let (server, client) = (server.unwrap(), client.unwrap());
+
+        assert_eq!(server.uns.id, 7);
+        assert_eq!(server.client_id, client_id());
+        assert_eq!(client.client_id, client_id());
+
+        let (server, client) =
+            handshake(&config, b"channel", ed25519(&unknown_key), &unknown_key).await;
+
+        match server {
+            Err(HandshakeError::Authenticate(err)) => assert!(matches!(
+                err.downcast_ref::<SpNetworkError>(),
+                Some(SpNetworkError::MNSByPubKey(_))
+            )),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        assert!(matches!(client, Err(HandshakeError::Rejected(_))));
+
+        // signature must come from the claimed key.
+        let (server, client) =
+            handshake(&config, b"channel", ed25519(&signing_key), &unknown_key).await;
+
+        assert!(matches!(server, Err(HandshakeError::PublicKey(_))));
+        assert!(matches!(client, Err(HandshakeError::Rejected(_))));
+
+        // signatures relayed from another channel are rejected.
+        let (server, client) =
+            handshake(&config, b"relayed", ed25519(&signing_key), &signing_key).await;
+
+        assert!(matches!(server, Err(HandshakeError::PublicKey(_))));
+        assert!(matches!(client, Err(HandshakeError::Rejected(_))));
+
+        // silent peers time out.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let (server, _) = listener.accept().await.unwrap();
+
+        let (mut reader, mut writer) = (server.clone(), server);
+
+        let config = HandshakeConfig {
+            timeout: Duration::from_millis(100),
+            ..config
+        };
+
+        assert!(matches!(
+            server_handshake(
+                &mut FramedChannel::new(&mut reader, &mut writer),
+                &config,
+                &[]
+            )
+            .await,
+            Err(HandshakeError::Timeout(_))
+        ));
+    }
+}
@@ -0,0 +1,518 @@
+//! Heartbeats and inactivity timeouts for connections whose transport can't tell a dead peer,
+//! e.g. half-open TCP or suspended mobile clients.
+//!
+//! Heartbeats are [`SyncMessage::Ping`] and [`SyncMessage::Pong`], a pong echoes the ping id.
+//! [`HeartbeatLayer`] pings silent peers, answers their pings and keeps heartbeats out of the
+//! connection input. A connection that times out yields one [`LayerError`] and then ends.
+
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use dimsp_types::SyncMessage;
+use futures::{FutureExt, Sink, Stream, StreamExt};
+use futures_timer::Delay;
+
+use crate::{
+    layer::{Layer, LayerError},
+    DatagramConnection, MultiContext, MultiError, MultiInput, MultiOutput,
+};
+
+/// Max heartbeats waiting for the output, pongs beyond it are dropped.
+const MAX_PENDING_HEARTBEATS: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct HeartbeatConfig {
+    /// Ping the peer after this long without input, none disables pings.
+    pub ping_interval: Option<Duration>,
+    /// Close after this long without input, pongs included.
+    pub read_timeout: Option<Duration>,
+    /// Close after this long without sync messages in either direction, heartbeats excluded.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Some(Duration::from_secs(15)),
+            read_timeout: Some(Duration::from_secs(45)),
+            idle_timeout: Some(Duration::from_secs(600)),
+        }
+    }
+}
+
+/// Counters shared by every connection of a [`HeartbeatLayer`].
+#[derive(Debug, Default)]
+pub struct HeartbeatCounters {
+    pings_sent: AtomicU64,
+    read_timeout_closed: AtomicU64,
+    idle_closed: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeartbeatSnapshot {
+    pub pings_sent: u64,
+    /// Connections closed because the peer stopped answering.
+    pub read_timeout_closed: u64,
+    /// Connections closed because they carried no sync messages.
+    pub idle_closed: u64,
+}
+
+impl HeartbeatCounters {
+    pub fn snapshot(&self) -> HeartbeatSnapshot {
+        HeartbeatSnapshot {
+            pings_sent: self.pings_sent.load(Ordering::Relaxed),
+            read_timeout_closed: self.read_timeout_closed.load(Ordering::Relaxed),
+            idle_closed: self.idle_closed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Output shared by the connection output and the heartbeats sent from the input.
+struct SharedOutput {
+    state: Mutex<OutputState>,
+    epoch: Instant,
+    /// Millis from `epoch` to the last sync message sent.
+    last_send: AtomicU64,
+}
+
+struct OutputState {
+    output: MultiOutput,
+    /// The connection side saw the output ready, its next send owns the slot heartbeats must
+    /// leave free until then.
+    reserved: bool,
+    /// Input waiting for the reservation to end, with heartbeats to send.
+    heartbeat_waker: Option<Waker>,
+}
+
+impl SharedOutput {
+    fn last_send(&self) -> Instant {
+        self.epoch + Duration::from_millis(self.last_send.load(Ordering::Relaxed))
+    }
+}
+
+struct HeartbeatOutput {
+    shared: Arc<SharedOutput>,
+}
+
+impl Sink<SyncMessage> for HeartbeatOutput {
+    type Error = MultiError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state.reserved {
+            return Poll::Ready(Ok(()));
+        }
+
+        let ready = state.output.as_mut().poll_ready(cx);
+
+        if let Poll::Ready(Ok(())) = ready {
+            state.reserved = true;
+        }
+
+        ready
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SyncMessage) -> Result<(), Self::Error> {
+        self.shared.last_send.store(
+            self.shared.epoch.elapsed().as_millis() as u64,
+            Ordering::Relaxed,
+        );
+
+        let mut state = self.shared.state.lock().unwrap();
+
+        state.reserved = false;
+
+        if let Some(waker) = state.heartbeat_waker.take() {
+            waker.wake();
+        }
+
+        state.output.as_mut().start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .output
+            .as_mut()
+            .poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .output
+            .as_mut()
+            .poll_close(cx)
+    }
+}
+
+struct HeartbeatInput {
+    id: usize,
+    inner: MultiInput,
+    shared: Arc<SharedOutput>,
+    config: HeartbeatConfig,
+    counters: Arc<HeartbeatCounters>,
+    /// Heartbeats waiting for the output.
+    pending: VecDeque<SyncMessage>,
+    flushing: bool,
+    last_input: Instant,
+    last_receive: Instant,
+    next_ping: Option<Instant>,
+    pings: u64,
+    timer: Delay,
+    closed: bool,
+}
+
+impl HeartbeatInput {
+    fn push_heartbeat(&mut self, message: SyncMessage) {
+        if self.pending.len() < MAX_PENDING_HEARTBEATS {
+            self.pending.push_back(message);
+        }
+    }
+
+    /// Write pending heartbeats, without waiting for a busy output.
+    ///
+    /// A slot the connection output saw ready is left to it, the input is woken once it is used.
+    fn poll_heartbeats(&mut self, cx: &mut Context<'_>) -> Result<(), MultiError> {
+        if self.pending.is_empty() && !self.flushing {
+            return Ok(());
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+
+        while !self.pending.is_empty() {
+            if state.reserved {
+                state.heartbeat_waker = Some(cx.waker().clone());
+                break;
+            }
+
+            match state.output.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    let message = self.pending.pop_front().unwrap();
+
+                    state.output.as_mut().start_send(message)?;
+
+                    self.flushing = true;
+                }
+                Poll::Ready(Err(err)) => return Err(err),
+                Poll::Pending => return Ok(()),
+            }
+        }
+
+        if let Poll::Ready(result) = state.output.as_mut().poll_flush(cx) {
+            self.flushing = false;
+            result?;
+        }
+
+        Ok(())
+    }
+
+    /// Check timeouts and pings, returns the error closing the connection.
+    fn poll_timers(&mut self, cx: &mut Context<'_>) -> Option<LayerError> {
+        loop {
+            let now = Instant::now();
+
+            let mut deadline: Option<Instant> = None;
+
+            let mut earliest = |instant: Instant| {
+                deadline = Some(deadline.map_or(instant, |deadline| deadline.min(instant)));
+            };
+
+            if let Some(timeout) = self.config.read_timeout {
+                if now >= self.last_input + timeout {
+                    self.counters
+                        .read_timeout_closed
+                        .fetch_add(1, Ordering::Relaxed);
+
+                    return Some(LayerError::ReadTimeout(timeout));
+                }
+
+                earliest(self.last_input + timeout);
+            }
+
+            if let Some(timeout) = self.config.idle_timeout {
+                let last_active = self.last_receive.max(self.shared.last_send());
+
+                if now >= last_active + timeout {
+                    self.counters.idle_closed.fetch_add(1, Ordering::Relaxed);
+
+                    return Some(LayerError::IdleTimeout(timeout));
+                }
+
+                earliest(last_active + timeout);
+            }
+
+            if let (Some(interval), Some(next_ping)) = (self.config.ping_interval, self.next_ping) {
+                if now >= next_ping {
+                    self.pings += 1;
+
+                    self.push_heartbeat(SyncMessage::Ping(self.pings));
+
+                    self.counters.pings_sent.fetch_add(1, Ordering::Relaxed);
+
+                    self.next_ping = Some(now + interval);
+                }
+
+                earliest(self.next_ping.unwrap());
+            }
+
+            let deadline = deadline?;
+
+            self.timer.reset(deadline.saturating_duration_since(now));
+
+            if self.timer.poll_unpin(cx).is_pending() {
+                return None;
+            }
+        }
+    }
+}
+
+impl Stream for HeartbeatInput {
+    type Item = Result<SyncMessage, MultiError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.closed {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            match this.inner.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(message))) => {
+                    let now = Instant::now();
+
+                    this.last_input = now;
+                    this.next_ping = this.config.ping_interval.map(|interval| now + interval);
+
+                    match message {
+                        SyncMessage::Ping(id) => {
+                            this.push_heartbeat(SyncMessage::Pong(id));
+                            continue;
+                        }
+                        SyncMessage::Pong(_) => continue,
+                        _ => {}
+                    }
+
+                    this.last_receive = now;
+
+                    // heartbeats answered above go out with the next poll.
+                    if let Err(err) = this.poll_heartbeats(cx) {
+                        return Poll::Ready(Some(Err(err)));
+                    }
+
+                    return Poll::Ready(Some(Ok(message)));
+                }
+                Poll::Ready(result) => return Poll::Ready(result),
+                Poll::Pending => break,
+            }
+        }
+
+        if let Some(err) = this.poll_timers(cx) {
+            log::debug!("Connection({}) closed, {}", this.id, err);
+
+            this.closed = true;
+
+            return Poll::Ready(Some(Err(MultiError::new(err))));
+        }
+
+        if let Err(err) = this.poll_heartbeats(cx) {
+            return Poll::Ready(Some(Err(err)));
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Heartbeats and timeouts of [`HeartbeatConfig`].
+pub struct HeartbeatLayer {
+    config: HeartbeatConfig,
+    counters: Arc<HeartbeatCounters>,
+}
+
+impl HeartbeatLayer {
+    pub fn new(config: HeartbeatConfig) -> Self {
+        Self {
+            config,
+            counters: Default::default(),
+        }
+    }
+
+    pub fn counters(&self) -> Arc<HeartbeatCounters> {
+        self.counters.clone()
+    }
+}
+
+impl Layer for HeartbeatLayer {
+    fn layer(&self, conn: DatagramConnection<MultiContext>) -> DatagramConnection<MultiContext> {
+        let now = Instant::now();
+
+        let shared = Arc::new(SharedOutput {
+            state: Mutex::new(OutputState {
+                output: conn.output,
+                reserved: false,
+                heartbeat_waker: None,
+            }),
+            epoch: now,
+            last_send: AtomicU64::new(0),
+        });
+
+        let input = HeartbeatInput {
+            id: conn.id,
+            inner: conn.input,
+            shared: shared.clone(),
+            config: self.config.clone(),
+            counters: self.counters.clone(),
+            pending: VecDeque::new(),
+            flushing: false,
+            last_input: now,
+            last_receive: now,
+            next_ping: self.config.ping_interval.map(|interval| now + interval),
+            pings: 0,
+            timer: Delay::new(Duration::ZERO),
+            closed: false,
+        };
+
+        DatagramConnection {
+            id: conn.id,
+            context: conn.context,
+            input: input.boxed(),
+            output: Box::pin(HeartbeatOutput { shared }),
+            frames: conn.frames,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use dimsp_types::{MNSAccount, SyncMessage};
+    use futures::{channel::mpsc, future, SinkExt, StreamExt};
+
+    use crate::{
+        layer::{Layer, LayerError},
+        test_support::{connection, message},
+        DatagramConnection, MultiError, MultiOutput,
+    };
+
+    use super::{HeartbeatConfig, HeartbeatLayer};
+
+    #[async_std::test]
+    async fn test_heartbeat() {
+        let layer = HeartbeatLayer::new(HeartbeatConfig {
+            ping_interval: Some(Duration::from_millis(50)),
+            read_timeout: Some(Duration::from_millis(200)),
+            idle_timeout: None,
+        });
+
+        let (conn, mut input, mut output) = connection(0);
+
+        let mut conn = layer.layer(conn);
+
+        let reader = async_std::task::spawn(async move {
+            assert_eq!(conn.try_next().await.unwrap().unwrap().id(), 7);
+
+            conn.try_next().await.map(|_| ())
+        });
+
+        // peer pings are answered, not delivered.
+        input.send(SyncMessage::Ping(1)).await.unwrap();
+
+        assert!(matches!(output.next().await, Some(SyncMessage::Pong(1))));
+
+        input.send(message(7)).await.unwrap();
+
+        // silent peers are pinged, a pong keeps the connection open.
+        let Some(SyncMessage::Ping(id)) = output.next().await else {
+            panic!("expect ping");
+        };
+
+        input.send(SyncMessage::Pong(id)).await.unwrap();
+
+        let start = Instant::now();
+
+        let err = reader.await.unwrap_err();
+
+        assert!(start.elapsed() >= Duration::from_millis(150));
+
+        assert!(matches!(
+            err.downcast_ref::<LayerError>(),
+            Some(LayerError::ReadTimeout(_))
+        ));
+
+        let snapshot = layer.counters().snapshot();
+
+        assert!(snapshot.pings_sent >= 2);
+        assert_eq!(snapshot.read_timeout_closed, 1);
+
+        // connections without sync messages are closed, even with a live peer.
+        let layer = HeartbeatLayer::new(HeartbeatConfig {
+            ping_interval: None,
+            read_timeout: None,
+            idle_timeout: Some(Duration::from_millis(100)),
+        });
+
+        let (conn, _input, _output) = connection(0);
+
+        let mut conn = layer.layer(conn);
+
+        let start = Instant::now();
+
+        assert!(matches!(
+            conn.try_next()
+                .await
+                .unwrap_err()
+                .downcast_ref::<LayerError>(),
+            Some(LayerError::IdleTimeout(_))
+        ));
+
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        assert!(conn.try_next().await.unwrap().is_none());
+
+        assert_eq!(layer.counters().snapshot().idle_closed, 1);
+
+        // a pong doesn't take the output slot the connection side saw ready.
+        let (mut input, conn_input) = mpsc::channel(10);
+        let (conn_output, mut output) = mpsc::channel::<SyncMessage>(0);
+
+        let mut conn = HeartbeatLayer::new(HeartbeatConfig::default()).layer(DatagramConnection {
+            id: crate::next_connection_id(),
+            context: MNSAccount::default(),
+            input: conn_input.map(Ok).boxed(),
+            output: Box::pin(conn_output.sink_map_err(MultiError::new)) as MultiOutput,
+            frames: None,
+        });
+
+        future::poll_fn(|cx| conn.output.as_mut().poll_ready(cx))
+            .await
+            .unwrap();
+
+        input.send(SyncMessage::Ping(1)).await.unwrap();
+
+        assert!(futures::poll!(conn.input.next()).is_pending());
+
+        conn.output.as_mut().start_send(message(7)).unwrap();
+
+        assert_eq!(output.next().await.unwrap().id(), 7);
+
+        // the pong goes out once the input is polled again.
+        assert!(futures::poll!(conn.input.next()).is_pending());
+
+        assert!(matches!(output.next().await, Some(SyncMessage::Pong(1))));
+    }
+}
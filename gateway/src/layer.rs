@@ -0,0 +1,643 @@
+//! Middleware wrapping connection input and output, in the spirit of tower layers.
+//!
+//! [`LayeredGateway`] erases the connections of any gateway into [`MultiContext`] and wraps them
+//! with its layers, the first added layer is the innermost: it sees input first and output last.
+//! Order matters, e.g. a [`CounterLayer`] outside an [`AuthLayer`] also counts the errors of
+//! rejected connections. Byte sizes come from the [`FrameMeter`](crate::framing::FrameMeter) of framed connections, so no
+//! layer encodes messages again.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use dimsp_types::{MNSAccount, SyncMessage};
+use futures::{future, future::BoxFuture, stream, SinkExt, StreamExt, TryStreamExt};
+use futures_timer::Delay;
+use thiserror::Error;
+
+use crate::{
+    boxed_connection, framing::FrameObserver, DatagramConnection, DatagramContext, DatagramGateway,
+    MultiContext, MultiError,
+};
+
+#[derive(Debug, Error)]
+pub enum LayerError {
+    #[error("Unauthorized: connection({0}) rejected by auth layer")]
+    Unauthorized(usize),
+    #[error("ReadTimeout: nothing read for {0:?}")]
+    ReadTimeout(Duration),
+    #[error("IdleTimeout: no sync message for {0:?}")]
+    IdleTimeout(Duration),
+}
+
+/// Wrap one accepted connection.
+pub trait Layer: Send + Sync {
+    fn layer(&self, conn: DatagramConnection<MultiContext>) -> DatagramConnection<MultiContext>;
+}
+
+/// Gateway applying layers to every connection accepted by `G`.
+pub struct LayeredGateway<G> {
+    gateway: G,
+    layers: Vec<Arc<dyn Layer>>,
+}
+
+impl<G> LayeredGateway<G> {
+    pub fn new(gateway: G) -> Self {
+        Self {
+            gateway,
+            layers: vec![],
+        }
+    }
+
+    /// Add `layer` outside of the previously added ones.
+    pub fn layer<L: Layer + 'static>(mut self, layer: L) -> Self {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+}
+
+impl<G> DatagramGateway for LayeredGateway<G>
+where
+    G: DatagramGateway + Send,
+    <G::Context as DatagramContext>::Input: 'static,
+    <G::Context as DatagramContext>::Output: 'static,
+{
+    type Context = MultiContext;
+
+    type Accepable<'cx>
+        = BoxFuture<'cx, Option<DatagramConnection<MultiContext>>>
+    where
+        Self: 'cx;
+
+    fn accept<'a, 'cx>(&'a mut self) -> Self::Accepable<'cx>
+    where
+        'a: 'cx,
+    {
+        Box::pin(async move {
+            let conn = boxed_connection(self.gateway.accept().await?);
+
+            Some(
+                self.layers
+                    .iter()
+                    .fold(conn, |conn, layer| layer.layer(conn)),
+            )
+        })
+    }
+}
+
+/// Fail connections whose account is rejected by the predicate.
+pub struct AuthLayer<F> {
+    predicate: F,
+}
+
+impl<F> AuthLayer<F>
+where
+    F: Fn(&MNSAccount) -> bool + Send + Sync,
+{
+    pub fn new(predicate: F) -> Self {
+        Self { predicate }
+    }
+}
+
+impl AuthLayer<fn(&MNSAccount) -> bool> {
+    /// Reject connections their gateway accepted without authentication.
+    pub fn require_account() -> Self {
+        Self::new(|account| !account.is_anonymous())
+    }
+}
+
+impl<F> Layer for AuthLayer<F>
+where
+    F: Fn(&MNSAccount) -> bool + Send + Sync,
+{
+    fn layer(&self, conn: DatagramConnection<MultiContext>) -> DatagramConnection<MultiContext> {
+        if (self.predicate)(&conn.context) {
+            return conn;
+        }
+
+        log::warn!(
+            "Connection({}) rejected, mns({}) unauthorized",
+            conn.id,
+            conn.context.uns.id
+        );
+
+        DatagramConnection {
+            input: stream::once(future::ready(Err(MultiError::new(
+                LayerError::Unauthorized(conn.id),
+            ))))
+            .boxed(),
+            ..conn
+        }
+    }
+}
+
+/// Cap the encoded size of messages in both directions.
+///
+/// Lowers the max frame length of the connection [`FrameMeter`](crate::framing::FrameMeter), so oversized frames fail the
+/// connection with [`FrameTooLarge`](crate::framing::FrameError::FrameTooLarge) before they are
+/// read. In-process connections have no frames and are left as they are.
+pub struct MessageSizeLayer {
+    max_size: usize,
+}
+
+impl MessageSizeLayer {
+    pub fn new(max_size: usize) -> Self {
+        Self { max_size }
+    }
+}
+
+impl Layer for MessageSizeLayer {
+    fn layer(&self, conn: DatagramConnection<MultiContext>) -> DatagramConnection<MultiContext> {
+        if let Some(frames) = &conn.frames {
+            frames.limit(self.max_size);
+        }
+
+        conn
+    }
+}
+
+struct TokenBucket {
+    burst: f64,
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            burst: burst as f64,
+            rate,
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take one token, returns how long the caller must wait for it.
+    fn acquire(&mut self) -> Duration {
+        let now = Instant::now();
+
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+
+        // tokens go negative, so waiting callers are served in order.
+        self.tokens -= 1.0;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Per account token bucket on incoming messages.
+///
+/// Connections of one account share a bucket, anonymous connections get one each.
+/// Messages over the limit are delayed rather than dropped.
+pub struct RateLimitLayer {
+    rate: f64,
+    burst: u32,
+    buckets: Mutex<HashMap<u64, Weak<Mutex<TokenBucket>>>>,
+}
+
+impl RateLimitLayer {
+    /// Allow `rate` messages per second, and bursts of `burst` messages.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        assert!(rate > 0.0, "rate limit must be positive");
+
+        Self {
+            rate,
+            burst: burst.max(1),
+            buckets: Default::default(),
+        }
+    }
+
+    fn bucket(&self, account: &MNSAccount) -> Arc<Mutex<TokenBucket>> {
+        let new_bucket = || Arc::new(Mutex::new(TokenBucket::new(self.rate, self.burst)));
+
+        if account.is_anonymous() {
+            return new_bucket();
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // buckets live as long as a connection of their account.
+        buckets.retain(|_, bucket| bucket.strong_count() > 0);
+
+        if let Some(bucket) = buckets.get(&account.uns.id).and_then(Weak::upgrade) {
+            return bucket;
+        }
+
+        let bucket = new_bucket();
+
+        buckets.insert(account.uns.id, Arc::downgrade(&bucket));
+
+        bucket
+    }
+}
+
+impl Layer for RateLimitLayer {
+    fn layer(&self, conn: DatagramConnection<MultiContext>) -> DatagramConnection<MultiContext> {
+        let bucket = self.bucket(&conn.context);
+
+        DatagramConnection {
+            input: conn
+                .input
+                .and_then(move |message| {
+                    let wait = bucket.lock().unwrap().acquire();
+
+                    async move {
+                        if !wait.is_zero() {
+                            Delay::new(wait).await;
+                        }
+
+                        Ok(message)
+                    }
+                })
+                .boxed(),
+            ..conn
+        }
+    }
+}
+
+/// Log connection lifecycle and traffic as `key=value` records.
+///
+/// Frame sizes of framed connections are logged as `frame_in` and `frame_out` records.
+pub struct LogLayer {
+    level: log::Level,
+}
+
+impl LogLayer {
+    /// Traffic is logged at `level`, lifecycle events at debug or above.
+    pub fn new(level: log::Level) -> Self {
+        Self { level }
+    }
+}
+
+const LOG_TARGET: &str = "dimsp_gateway::traffic";
+
+struct CloseLog {
+    id: usize,
+    uns: u64,
+}
+
+impl Drop for CloseLog {
+    fn drop(&mut self) {
+        log::debug!(target: LOG_TARGET, "event=closed conn={} uns={}", self.id, self.uns);
+    }
+}
+
+struct FrameLog {
+    id: usize,
+    uns: u64,
+    level: log::Level,
+}
+
+impl FrameObserver for FrameLog {
+    fn frame_in(&self, len: usize) {
+        log::log!(
+            target: LOG_TARGET,
+            self.level,
+            "event=frame_in conn={} uns={} size={}",
+            self.id,
+            self.uns,
+            len
+        );
+    }
+
+    fn frame_out(&self, len: usize) {
+        log::log!(
+            target: LOG_TARGET,
+            self.level,
+            "event=frame_out conn={} uns={} size={}",
+            self.id,
+            self.uns,
+            len
+        );
+    }
+}
+
+impl Layer for LogLayer {
+    fn layer(&self, conn: DatagramConnection<MultiContext>) -> DatagramConnection<MultiContext> {
+        let (id, uns) = (conn.id, conn.context.uns.id);
+
+        let level = self.level;
+
+        log::debug!(target: LOG_TARGET, "event=accepted conn={} uns={}", id, uns);
+
+        if let Some(frames) = &conn.frames {
+            frames.observe(Arc::new(FrameLog { id, uns, level }));
+        }
+
+        let close = CloseLog { id, uns };
+
+        DatagramConnection {
+            id,
+            context: conn.context,
+            input: conn
+                .input
+                .inspect(move |result| {
+                    let _close = &close;
+
+                    match result {
+                        Ok(message) => log::log!(
+                            target: LOG_TARGET,
+                            level,
+                            "event=recv conn={} uns={} id={} type={}",
+                            id,
+                            uns,
+                            message.id(),
+                            message.name()
+                        ),
+                        Err(err) => log::warn!(
+                            target: LOG_TARGET,
+                            "event=recv_error conn={} uns={} error=\"{}\"",
+                            id,
+                            uns,
+                            err
+                        ),
+                    }
+                })
+                .boxed(),
+            output: Box::pin(
+                conn.output
+                    .with(move |message: SyncMessage| {
+                        log::log!(
+                            target: LOG_TARGET,
+                            level,
+                            "event=send conn={} uns={} id={} type={}",
+                            id,
+                            uns,
+                            message.id(),
+                            message.name()
+                        );
+
+                        future::ready(Ok::<_, MultiError>(message))
+                    })
+                    .sink_map_err(move |err: MultiError| {
+                        log::warn!(
+                            target: LOG_TARGET,
+                            "event=send_error conn={} uns={} error=\"{}\"",
+                            id,
+                            uns,
+                            err
+                        );
+
+                        err
+                    }),
+            ),
+            frames: conn.frames,
+        }
+    }
+}
+
+/// Counters shared by every connection of a [`CounterLayer`].
+#[derive(Debug, Default)]
+pub struct GatewayCounters {
+    connections: AtomicU64,
+    active_connections: AtomicU64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Point in time copy of [`GatewayCounters`], byte counts are frame payload lengths.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CounterSnapshot {
+    pub connections: u64,
+    pub active_connections: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub errors: u64,
+}
+
+impl GatewayCounters {
+    pub fn snapshot(&self) -> CounterSnapshot {
+        CounterSnapshot {
+            connections: self.connections.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl FrameObserver for GatewayCounters {
+    fn frame_in(&self, len: usize) {
+        self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn frame_out(&self, len: usize) {
+        self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+struct ActiveConnection(Arc<GatewayCounters>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Count connections, messages, bytes and errors.
+///
+/// Bytes are counted by the [`FrameMeter`](crate::framing::FrameMeter) of framed connections, whatever the layer order.
+#[derive(Default)]
+pub struct CounterLayer {
+    counters: Arc<GatewayCounters>,
+}
+
+impl CounterLayer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn counters(&self) -> Arc<GatewayCounters> {
+        self.counters.clone()
+    }
+}
+
+impl Layer for CounterLayer {
+    fn layer(&self, conn: DatagramConnection<MultiContext>) -> DatagramConnection<MultiContext> {
+        let counters = self.counters.clone();
+
+        counters.connections.fetch_add(1, Ordering::Relaxed);
+        counters.active_connections.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(frames) = &conn.frames {
+            frames.observe(counters.clone());
+        }
+
+        let active = ActiveConnection(counters.clone());
+
+        let input = conn.input.inspect(move |result| match result {
+            Ok(_) => {
+                active.0.messages_in.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {
+                active.0.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        let output_counters = counters.clone();
+
+        let output = conn
+            .output
+            .with(move |message: SyncMessage| {
+                output_counters.messages_out.fetch_add(1, Ordering::Relaxed);
+
+                future::ready(Ok::<_, MultiError>(message))
+            })
+            .sink_map_err(move |err: MultiError| {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+                err
+            });
+
+        DatagramConnection {
+            id: conn.id,
+            context: conn.context,
+            input: input.boxed(),
+            output: Box::pin(output),
+            frames: conn.frames,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use dimsp_types::ANONYMOUS_ID;
+    use futures::{channel::mpsc, SinkExt, StreamExt};
+
+    use crate::{
+        framing::FrameError,
+        test_support::{connection, message},
+        ChannelAccepable, DatagramConnection, DatagramGateway, MultiContext,
+    };
+
+    use super::{
+        AuthLayer, CounterLayer, LayerError, LayeredGateway, LogLayer, MessageSizeLayer,
+        RateLimitLayer,
+    };
+
+    struct TestGateway {
+        receiver: mpsc::Receiver<DatagramConnection<MultiContext>>,
+    }
+
+    impl DatagramGateway for TestGateway {
+        type Context = MultiContext;
+
+        type Accepable<'cx> = ChannelAccepable<'cx, MultiContext>;
+
+        fn accept<'a, 'cx>(&'a mut self) -> Self::Accepable<'cx>
+        where
+            'a: 'cx,
+        {
+            ChannelAccepable::new(&mut self.receiver)
+        }
+    }
+
+    #[async_std::test]
+    async fn test_layers() {
+        let counter = CounterLayer::new();
+        let counters = counter.counters();
+
+        let (mut sender, receiver) = mpsc::channel(10);
+
+        let mut gateway = LayeredGateway::new(TestGateway { receiver })
+            .layer(AuthLayer::require_account())
+            .layer(MessageSizeLayer::new(17))
+            .layer(RateLimitLayer::new(20.0, 2))
+            .layer(LogLayer::new(log::Level::Trace))
+            .layer(counter);
+
+        // anonymous connection fails on first read.
+        let (conn, _input, _output) = connection(ANONYMOUS_ID);
+
+        sender.send(conn).await.unwrap();
+
+        let mut conn = gateway.accept().await.unwrap();
+
+        let err = conn.try_next().await.unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<LayerError>(),
+            Some(LayerError::Unauthorized(_))
+        ));
+
+        let (conn, mut input, mut output) = connection(7);
+
+        sender.send(conn).await.unwrap();
+
+        let mut conn = gateway.accept().await.unwrap();
+
+        // burst of 2, then 20 messages/s.
+        for _ in 0..4 {
+            input.send(message(1)).await.unwrap();
+        }
+
+        let start = Instant::now();
+
+        for _ in 0..4 {
+            let message = conn.try_next().await.unwrap().unwrap();
+
+            conn.send(message).await.unwrap();
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(90));
+
+        assert_eq!(output.next().await.unwrap().id(), 1);
+
+        // oversized frames are refused in both directions, a max u64 id takes 8 more bytes.
+        assert!(matches!(
+            conn.send(message(u64::MAX))
+                .await
+                .unwrap_err()
+                .downcast_ref::<FrameError>(),
+            Some(FrameError::FrameTooLarge(25, 17))
+        ));
+
+        input.send(message(u64::MAX)).await.unwrap();
+
+        assert!(matches!(
+            conn.try_next()
+                .await
+                .unwrap_err()
+                .downcast_ref::<FrameError>(),
+            Some(FrameError::FrameTooLarge(25, 17))
+        ));
+
+        let snapshot = counters.snapshot();
+
+        assert_eq!(snapshot.connections, 2);
+        assert_eq!(snapshot.active_connections, 2);
+        assert_eq!(snapshot.messages_in, 4);
+        // the counter is outermost, so it saw the oversized output too.
+        assert_eq!(snapshot.messages_out, 5);
+        // refused frames are never counted.
+        assert_eq!(snapshot.bytes_in, 68);
+        assert_eq!(snapshot.bytes_out, 68);
+        assert_eq!(snapshot.errors, 3);
+
+        drop(conn);
+
+        assert_eq!(counters.snapshot().active_connections, 1);
+    }
+}
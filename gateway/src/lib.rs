@@ -1,5 +1,48 @@
 mod gateway;
 pub use gateway::*;
 
+mod channel;
+pub use channel::*;
+
+pub mod framing;
+
+pub mod handshake;
+
+mod multi;
+pub use multi::*;
+
+pub mod layer;
+
+pub mod heartbeat;
+
+#[cfg(any(
+    feature = "tcp",
+    feature = "websocket",
+    feature = "quic",
+    all(unix, feature = "unix")
+))]
+mod threadpool;
+
+#[cfg(test)]
+mod test_support;
+
 #[cfg(feature = "mock")]
 pub mod mock;
+
+#[cfg(feature = "noise")]
+pub mod noise;
+
+#[cfg(feature = "tls")]
+pub mod tls;
+
+#[cfg(feature = "tcp")]
+pub mod tcp;
+
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+#[cfg(feature = "quic")]
+pub mod quic;
+
+#[cfg(all(unix, feature = "unix"))]
+pub mod unix;
@@ -1,14 +1,14 @@
-use dimsp_types::{
-    keccack256, open_write_stream_ack, sync_message, write_fragment_ack, Hash32, IdGenerator,
-    MNSAccount, SyncError, SyncMessage, SyncMessageBuilder, WriteFragment,
-};
+use std::collections::HashMap;
+
+use dimsp_types::{Envelope, EnvelopeQuery, IdGenerator, MNSAccount, Mime, SyncMessage};
 use futures::{
     channel::mpsc::{self, SendError},
     stream::BoxStream,
-    Future, SinkExt, StreamExt,
+    SinkExt, StreamExt,
 };
+use libipld::Cid;
 
-use crate::{DatagramConnection, DatagramContext, DatagramGateway};
+use crate::{ChannelAccepable, DatagramConnection, DatagramContext, DatagramGateway};
 
 pub struct MockDatagramContext;
 
@@ -30,137 +30,84 @@ pub struct MockSession {
 }
 
 impl MockSession {
-    /// Send message
-    pub async fn send_message<B: AsRef<[u8]>>(
+    /// Send message `root` to the inbox of mns `to`, answering the hub pulls from `objects`.
+    pub async fn send_message(
         &mut self,
         to: u64,
-        buff: B,
-        fragment_len: usize,
+        root: Cid,
+        objects: &HashMap<Cid, Mime>,
     ) -> anyhow::Result<()> {
-        // prepare send messages
-        let buff = buff.as_ref();
-
-        let split_count = if buff.len() % fragment_len == 0 {
-            buff.len() / fragment_len
-        } else {
-            buff.len() / fragment_len + 1
-        };
-
-        let mut fragments = vec![];
-        let mut fragment_hashes: Vec<Hash32> = vec![];
+        let id = (&mut self.id_gen).into();
 
-        for i in 1..=split_count {
-            let buff = if i * fragment_len > buff.len() {
-                &buff[(i - 1) * fragment_len..]
-            } else {
-                &buff[(i - 1) * fragment_len..i * fragment_len]
-            };
+        self.conn.send(SyncMessage::Push(id, to, root)).await?;
 
-            let mut write_fragment = WriteFragment::new();
+        loop {
+            match self.recv_ack(id).await? {
+                SyncMessage::PullMultipart(_, cid) => {
+                    let mime = objects
+                        .get(&cid)
+                        .ok_or(anyhow::format_err!("pull unknown mime({})", cid))?;
 
-            write_fragment.offset = (i - 1) as u64;
-            write_fragment.content = buff.to_vec();
+                    log::debug!("Push({}) send mime({})", id, cid);
 
-            fragments.push(write_fragment);
-            fragment_hashes.push(keccack256(buff).into());
+                    self.conn
+                        .send(SyncMessage::PullMultipartContent(id, mime.clone()))
+                        .await?;
+                }
+                SyncMessage::Push(_, _, cid) if cid == root => return Ok(()),
+                message => return Err(unexpected(message)),
+            }
         }
+    }
 
-        log::debug!("Open write stream, fragments({})", fragments.len());
-
-        let message = if fragments.len() == 1 {
-            SyncMessageBuilder::build(&mut self.id_gen).open_write_stream(
-                buff.len() as u64,
-                to,
-                0,
-                fragment_hashes,
-                fragments.pop(),
-            )
-        } else {
-            SyncMessageBuilder::build(&mut self.id_gen).open_write_stream(
-                buff.len() as u64,
-                to,
-                0,
-                fragment_hashes,
-                None,
-            )
-        };
-
-        let ack = self.request(message).await?;
-
-        log::debug!("Open write stream, ack: {}", ack);
-
-        assert_eq!(ack.type_, sync_message::Type::OpenWriteStreamAck.into());
+    /// Pull mime object `cid`.
+    pub async fn pull(&mut self, cid: Cid) -> anyhow::Result<Mime> {
+        let id = (&mut self.id_gen).into();
 
-        if fragments.is_empty() {
-            assert_eq!(
-                ack.open_write_stream_ack().ack_type,
-                open_write_stream_ack::Type::Noneed.into()
-            );
-        } else {
-            assert_eq!(
-                ack.open_write_stream_ack().ack_type,
-                open_write_stream_ack::Type::Accept.into()
-            );
+        match self.request(SyncMessage::PullMultipart(id, cid)).await? {
+            SyncMessage::PullMultipartContent(_, mime) => Ok(mime),
+            message => Err(unexpected(message)),
         }
+    }
 
-        let next_fragment = ack.open_write_stream_ack().next_fragment;
-        let stream_handle = ack.open_write_stream_ack().stream_handle;
-
-        let max_index = fragments.len();
-
-        for (index, mut fragment) in fragments.into_iter().enumerate() {
-            if index < next_fragment as usize {
-                continue;
-            }
-
-            fragment.stream_handle = stream_handle;
-            fragment.offset = index as u64;
-
-            log::debug!("Write fragment({}) {}", index, fragment);
-
-            let message = SyncMessageBuilder::build(&mut self.id_gen).from_write_fragment(fragment);
-
-            let ack = self.request(message).await?;
-
-            assert_eq!(ack.type_, sync_message::Type::WriteFragmentAck.into());
-
-            if index + 1 == max_index {
-                assert_eq!(
-                    ack.write_fragment_ack().ack_type,
-                    write_fragment_ack::Type::Nomore.into()
-                );
-            } else {
-                assert_eq!(
-                    ack.write_fragment_ack().ack_type,
-                    write_fragment_ack::Type::Continue.into()
-                );
-            }
+    /// Search inbox envelopes matching `query`.
+    pub async fn search(&mut self, query: EnvelopeQuery) -> anyhow::Result<Vec<Envelope>> {
+        let id = (&mut self.id_gen).into();
 
-            log::debug!("Write fragment({}), ack: {}", index as u64, ack);
+        match self.request(SyncMessage::Search(id, query)).await? {
+            SyncMessage::SearchResult(_, envelopes) => Ok(envelopes),
+            message => Err(unexpected(message)),
         }
+    }
 
-        log::debug!("Close write stream");
-
-        let message = SyncMessageBuilder::build(&mut self.id_gen).close_write_stream(stream_handle);
+    /// Replace flags of message `cid`.
+    pub async fn set_flags(&mut self, cid: Cid, flags: u32) -> anyhow::Result<()> {
+        let id = (&mut self.id_gen).into();
 
-        let ack = self.request(message).await?;
+        match self.request(SyncMessage::SetFlags(id, cid, flags)).await? {
+            SyncMessage::SetFlags(..) => Ok(()),
+            message => Err(unexpected(message)),
+        }
+    }
 
-        assert_eq!(ack.type_, sync_message::Type::CloseWriteStreamAck.into());
-        assert_eq!(
-            ack.close_write_stream_ack().sync_error,
-            SyncError::Success.into()
-        );
+    pub async fn ping(&mut self) -> anyhow::Result<()> {
+        let id = (&mut self.id_gen).into();
 
-        Ok(())
+        match self.request(SyncMessage::Ping(id)).await? {
+            SyncMessage::Pong(_) => Ok(()),
+            message => Err(unexpected(message)),
+        }
     }
 
-    async fn request(&mut self, message: SyncMessage) -> anyhow::Result<SyncMessage> {
-        let id = message.id;
+    /// Send `message`, returns the reply of the same id.
+    pub async fn request(&mut self, message: SyncMessage) -> anyhow::Result<SyncMessage> {
+        let id = message.id();
 
         self.conn.send(message).await?;
 
         self.recv_ack(id).await
     }
+
     async fn recv_ack(&mut self, id: u64) -> anyhow::Result<SyncMessage> {
         let msg = self
             .conn
@@ -168,16 +115,20 @@ impl MockSession {
             .await?
             .ok_or(anyhow::format_err!("broken piple"))?;
 
-        log::debug!("msg id {}, expect {}", msg.id, id);
+        log::debug!("msg id {}, expect {}", msg.id(), id);
 
-        if msg.id != id {
-            return Err(anyhow::format_err!("ack seq id mismatch !!!"));
+        match msg {
+            _ if msg.id() != id => Err(anyhow::format_err!("ack seq id mismatch !!!")),
+            SyncMessage::Error(_, reason) => Err(anyhow::format_err!("{}", reason)),
+            msg => Ok(msg),
         }
-
-        Ok(msg)
     }
 }
 
+fn unexpected(message: SyncMessage) -> anyhow::Error {
+    anyhow::format_err!("unexpected ack {}({})", message.name(), message.id())
+}
+
 pub struct MockClient {
     seq: usize,
     sender: mpsc::Sender<DatagramConnection<MockDatagramContext>>,
@@ -193,15 +144,17 @@ impl MockClient {
         let server_conn = DatagramConnection {
             id: self.seq,
             context: mns.clone(),
-            input: server_receiver.map(|c| Ok(c)).boxed(),
+            input: server_receiver.map(Ok).boxed(),
             output: server_sender,
+            frames: None,
         };
 
         let client_conn = DatagramConnection {
             id: self.seq,
             context: mns,
-            input: client_receiver.map(|c| Ok(c)).boxed(),
+            input: client_receiver.map(Ok).boxed(),
             output: client_sender,
+            frames: None,
         };
 
         self.sender.send(server_conn).await?;
@@ -228,28 +181,12 @@ impl MockGateway {
 impl DatagramGateway for MockGateway {
     type Context = MockDatagramContext;
 
-    type Accepable<'cx> = MockAccepable<'cx>;
+    type Accepable<'cx> = ChannelAccepable<'cx, MockDatagramContext>;
 
     fn accept<'a, 'cx>(&'a mut self) -> Self::Accepable<'cx>
     where
         'a: 'cx,
     {
-        MockAccepable {
-            receiver: &mut self.receiver,
-        }
-    }
-}
-
-pub struct MockAccepable<'cx> {
-    receiver: &'cx mut mpsc::Receiver<DatagramConnection<MockDatagramContext>>,
-}
-
-impl<'cx> Future for MockAccepable<'cx> {
-    type Output = Option<DatagramConnection<MockDatagramContext>>;
-    fn poll(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Self::Output> {
-        self.receiver.poll_next_unpin(cx)
+        ChannelAccepable::new(&mut self.receiver)
     }
 }
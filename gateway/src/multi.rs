@@ -0,0 +1,245 @@
+//! [`DatagramGateway`] merging the connections of several gateways, e.g. TCP, WebSocket and QUIC
+//! served by one hub.
+
+use std::{error::Error, fmt::Display, pin::Pin, sync::Mutex};
+
+use dimsp_types::{MNSAccount, SyncMessage};
+use futures::{
+    stream::{self, BoxStream, Next, SelectAll},
+    Sink, SinkExt, StreamExt, TryStreamExt,
+};
+
+use crate::{DatagramConnection, DatagramContext, DatagramGateway};
+
+/// Type erased error of [`MultiGateway`] connections.
+#[derive(Debug)]
+pub struct MultiError(Box<dyn Error + Send + Sync>);
+
+impl MultiError {
+    /// Erase `err`, an already erased error is kept as is.
+    pub fn new<E: Error + Send + Sync + 'static>(err: E) -> Self {
+        let err: Box<dyn Error + Send + Sync> = Box::new(err);
+
+        match err.downcast::<MultiError>() {
+            Ok(err) => *err,
+            Err(err) => Self(err),
+        }
+    }
+
+    /// Returns the transport error, if it is an `E`.
+    pub fn downcast_ref<E: Error + 'static>(&self) -> Option<&E> {
+        self.0.downcast_ref()
+    }
+}
+
+impl Display for MultiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl Error for MultiError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.0)
+    }
+}
+
+pub type MultiInput = BoxStream<'static, Result<SyncMessage, MultiError>>;
+
+pub type MultiOutput = Pin<Box<dyn Sink<SyncMessage, Error = MultiError> + Send>>;
+
+/// Context of [`MultiGateway`] connections.
+pub struct MultiContext;
+
+impl DatagramContext for MultiContext {
+    type StreamError = MultiError;
+    type SinkError = MultiError;
+    type Item = SyncMessage;
+    type Context = MNSAccount;
+    type Input = MultiInput;
+    type Output = MultiOutput;
+}
+
+/// Erase the transport types of `conn`.
+pub fn boxed_connection<C>(conn: DatagramConnection<C>) -> DatagramConnection<MultiContext>
+where
+    C: DatagramContext<Item = SyncMessage, Context = MNSAccount>,
+    C::Input: 'static,
+    C::Output: 'static,
+{
+    DatagramConnection {
+        id: conn.id,
+        context: conn.context,
+        input: conn.input.map_err(MultiError::new).boxed(),
+        output: Box::pin(conn.output.sink_map_err(MultiError::new)),
+        frames: conn.frames,
+    }
+}
+
+type Incoming = SelectAll<BoxStream<'static, DatagramConnection<MultiContext>>>;
+
+/// Accepts from every added gateway, until all of them shut down.
+#[derive(Default)]
+pub struct MultiGateway {
+    /// Only makes the gateway `Sync`, `accept` reaches it through `get_mut` without locking.
+    gateways: Mutex<Incoming>,
+}
+
+impl MultiGateway {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add `gateway`, [`accept`](DatagramGateway::accept) polls it along with the others.
+    pub fn add<G>(&mut self, gateway: G)
+    where
+        G: DatagramGateway + Send + 'static,
+        <G::Context as DatagramContext>::Input: 'static,
+        <G::Context as DatagramContext>::Output: 'static,
+    {
+        let incoming = stream::unfold(gateway, |mut gateway| async move {
+            let conn = gateway.accept().await?;
+
+            Some((boxed_connection(conn), gateway))
+        });
+
+        self.gateways.get_mut().unwrap().push(incoming.boxed());
+    }
+
+    /// Builder style [`add`](Self::add).
+    pub fn with<G>(mut self, gateway: G) -> Self
+    where
+        G: DatagramGateway + Send + 'static,
+        <G::Context as DatagramContext>::Input: 'static,
+        <G::Context as DatagramContext>::Output: 'static,
+    {
+        self.add(gateway);
+        self
+    }
+
+    /// Returns the number of gateways still accepting.
+    pub fn len(&self) -> usize {
+        self.gateways.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gateways.lock().unwrap().is_empty()
+    }
+}
+
+impl DatagramGateway for MultiGateway {
+    type Context = MultiContext;
+
+    type Accepable<'cx> = Next<'cx, Incoming>;
+
+    fn accept<'a, 'cx>(&'a mut self) -> Self::Accepable<'cx>
+    where
+        'a: 'cx,
+    {
+        self.gateways.get_mut().unwrap().next()
+    }
+}
+
+#[cfg(all(test, feature = "tcp", feature = "websocket"))]
+mod tests {
+    use std::error::Error;
+
+    use async_std::net::TcpStream;
+    use async_tungstenite::tungstenite::Message;
+    use futures::{AsyncWriteExt, SinkExt, StreamExt, TryStreamExt};
+
+    use crate::{
+        framing::{encode_message, framed_input, framed_output, FrameError},
+        tcp::{TcpGateway, TcpGatewayConfig},
+        test_support,
+        websocket::{WebSocketGateway, WebSocketGatewayConfig},
+        DatagramGateway,
+    };
+
+    use super::MultiGateway;
+
+    #[async_std::test]
+    async fn test_multi_gateway() {
+        let config = TcpGatewayConfig {
+            addrs: vec!["127.0.0.1:0".parse().unwrap()],
+            max_frame: 1024,
+            allow_anonymous: true,
+            ..Default::default()
+        };
+
+        let tcp = TcpGateway::bind(config).await.unwrap();
+
+        let tcp_addr = tcp.local_addrs()[0];
+
+        let websocket = WebSocketGateway::bind(WebSocketGatewayConfig {
+            addrs: vec!["127.0.0.1:0".parse().unwrap()],
+            allow_anonymous: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let websocket_addr = websocket.local_addrs()[0];
+
+        let mut gateway = MultiGateway::new().with(tcp).with(websocket);
+
+        assert_eq!(gateway.len(), 2);
+
+        let message = test_support::message(1);
+
+        let stream = TcpStream::connect(tcp_addr).await.unwrap();
+
+        let mut tcp_input = framed_input(stream.clone(), 1024);
+        let mut tcp_output = framed_output(stream, 1024);
+
+        tcp_output.send(message.clone()).await.unwrap();
+
+        let mut tcp_conn = gateway.accept().await.unwrap();
+
+        assert_eq!(tcp_conn.try_next().await.unwrap().unwrap().id(), 1);
+
+        tcp_conn.send(message.clone()).await.unwrap();
+
+        assert_eq!(tcp_input.try_next().await.unwrap().unwrap().id(), 1);
+
+        let (mut client, _) =
+            async_tungstenite::async_std::connect_async(format!("ws://{}/", websocket_addr))
+                .await
+                .unwrap();
+
+        let mut websocket_conn = gateway.accept().await.unwrap();
+
+        client
+            .send(Message::Binary(encode_message(&message).unwrap()))
+            .await
+            .unwrap();
+
+        assert_eq!(websocket_conn.try_next().await.unwrap().unwrap().id(), 1);
+
+        websocket_conn.send(message).await.unwrap();
+
+        assert!(matches!(client.next().await, Some(Ok(Message::Binary(_)))));
+
+        assert_ne!(tcp_conn.id, websocket_conn.id);
+
+        // transport errors stay reachable through the erased error.
+        let mut stream = TcpStream::connect(tcp_addr).await.unwrap();
+
+        stream.write_all(&2048u32.to_be_bytes()).await.unwrap();
+
+        let mut conn = gateway.accept().await.unwrap();
+
+        let err = conn.try_next().await.unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<FrameError>(),
+            Some(FrameError::FrameTooLarge(2048, 1024))
+        ));
+
+        assert!(matches!(
+            err.source()
+                .and_then(|err| err.downcast_ref::<FrameError>()),
+            Some(FrameError::FrameTooLarge(2048, 1024))
+        ));
+    }
+}
@@ -0,0 +1,521 @@
+//! Noise secure channel over byte streams, keyed by the SP endpoint key.
+//!
+//! The static key is the X25519 form of the ed25519 key advertised as
+//! [`SPRSEndpoint::pub_key`](dimsp_types::SPRSEndpoint), so clients that resolved the endpoint
+//! through SPRS authenticate the SP without a CA. Clients pick `XX` to learn the SP key during the
+//! handshake, or `IK` when they already know it. Every noise message is prefixed by a big endian
+//! `u16` length.
+
+use std::{
+    fmt::Debug,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use dimsp_types::{PublicKey, PublicKeyBuff};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use futures::{ready, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use snow::{params::NoiseParams, Builder, HandshakeState, TransportState};
+use thiserror::Error;
+
+/// Max length of one noise message.
+const MAX_NOISE_MESSAGE: usize = 65535;
+
+const TAG_LEN: usize = 16;
+
+/// Max plaintext carried by one transport message.
+const MAX_NOISE_PAYLOAD: usize = MAX_NOISE_MESSAGE - TAG_LEN;
+
+#[derive(Debug, Error)]
+pub enum NoiseError {
+    #[error("Io: {0}")]
+    Io(#[from] io::Error),
+    #[error("Noise: {0}")]
+    Noise(#[from] snow::Error),
+    #[error("UnsupportedKey: noise static key must be an ed25519 key, {0}")]
+    UnsupportedKey(String),
+    #[error("Pattern: unknown noise pattern id({0})")]
+    Pattern(u8),
+    #[error("RemoteKey: IK pattern requires the endpoint key")]
+    RemoteKeyRequired,
+    #[error("RemoteKey: remote static key doesn't match endpoint key {0}")]
+    RemoteKey(Box<PublicKey>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoisePattern {
+    /// Both sides transmit their static keys during the handshake.
+    XX,
+    /// The client knows the SP key in advance and saves one round trip.
+    IK,
+}
+
+impl NoisePattern {
+    fn id(&self) -> u8 {
+        match self {
+            Self::XX => 0,
+            Self::IK => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, NoiseError> {
+        match id {
+            0 => Ok(Self::XX),
+            1 => Ok(Self::IK),
+            id => Err(NoiseError::Pattern(id)),
+        }
+    }
+
+    fn params(&self) -> NoiseParams {
+        let name = match self {
+            Self::XX => "Noise_XX_25519_ChaChaPoly_BLAKE2s",
+            Self::IK => "Noise_IK_25519_ChaChaPoly_BLAKE2s",
+        };
+
+        name.parse().expect("valid noise params")
+    }
+
+    /// Binds the pattern choice, sent in clear, to the handshake.
+    fn prologue(&self) -> Vec<u8> {
+        let mut prologue = b"dimsp-noise".to_vec();
+
+        prologue.push(self.id());
+
+        prologue
+    }
+}
+
+/// Noise static keypair derived from an ed25519 key.
+#[derive(Clone)]
+pub struct NoiseKeypair {
+    private: [u8; 32],
+    pub_key: PublicKey,
+}
+
+impl NoiseKeypair {
+    /// Derive keypair from ed25519 secret key bytes.
+    pub fn from_ed25519(secret: &[u8; 32]) -> Self {
+        let signing_key = SigningKey::from_bytes(secret);
+
+        let verifying_key = signing_key.verifying_key();
+
+        Self {
+            private: signing_key.to_scalar_bytes(),
+            pub_key: PublicKey::Ed25519(PublicKeyBuff(verifying_key.to_bytes())),
+        }
+    }
+
+    /// Random keypair, for clients without a long term key.
+    pub fn generate() -> Self {
+        Self::from_ed25519(&rand::random())
+    }
+
+    /// Returns the ed25519 public key to advertise in [`SPRSEndpoint`](dimsp_types::SPRSEndpoint).
+    pub fn public_key(&self) -> &PublicKey {
+        &self.pub_key
+    }
+}
+
+impl Debug for NoiseKeypair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseKeypair")
+            .field("pub_key", &self.pub_key)
+            .finish()
+    }
+}
+
+/// Returns X25519 form of ed25519 `pub_key`.
+pub fn x25519_public_key(pub_key: &PublicKey) -> Result<[u8; 32], NoiseError> {
+    match pub_key {
+        PublicKey::Ed25519(buff) => VerifyingKey::from_bytes(&buff.0)
+            .map(|key| key.to_montgomery().to_bytes())
+            .map_err(|err| NoiseError::UnsupportedKey(err.to_string())),
+        pub_key => Err(NoiseError::UnsupportedKey(pub_key.to_string())),
+    }
+}
+
+async fn write_message<S>(stream: &mut S, buff: &[u8]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_all(&(buff.len() as u16).to_be_bytes()).await?;
+    stream.write_all(buff).await?;
+    stream.flush().await
+}
+
+async fn read_message<S>(stream: &mut S) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut len = [0u8; 2];
+
+    stream.read_exact(&mut len).await?;
+
+    let mut buff = vec![0u8; u16::from_be_bytes(len) as usize];
+
+    stream.read_exact(&mut buff).await?;
+
+    Ok(buff)
+}
+
+/// Returns the transport state and the handshake hash.
+async fn run_handshake<S>(
+    stream: &mut S,
+    mut state: HandshakeState,
+) -> Result<(TransportState, Vec<u8>), NoiseError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buff = vec![0u8; MAX_NOISE_MESSAGE];
+
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state.write_message(&[], &mut buff)?;
+
+            write_message(stream, &buff[..len]).await?;
+        } else {
+            let message = read_message(stream).await?;
+
+            state.read_message(&message, &mut buff)?;
+        }
+    }
+
+    let handshake_hash = state.get_handshake_hash().to_vec();
+
+    Ok((state.into_transport_mode()?, handshake_hash))
+}
+
+/// Run the responder side of the handshake with the SP endpoint `keypair`.
+pub async fn noise_accept<S>(
+    mut stream: S,
+    keypair: &NoiseKeypair,
+) -> Result<NoiseStream<S>, NoiseError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut id = [0u8];
+
+    stream.read_exact(&mut id).await?;
+
+    let pattern = NoisePattern::from_id(id[0])?;
+
+    let prologue = pattern.prologue();
+
+    let state = Builder::new(pattern.params())
+        .local_private_key(&keypair.private)
+        .prologue(&prologue)
+        .build_responder()?;
+
+    let (transport, handshake_hash) = run_handshake(&mut stream, state).await?;
+
+    Ok(NoiseStream::new(stream, transport, handshake_hash))
+}
+
+/// Run the initiator side of the handshake.
+///
+/// `endpoint_key` is the [`SPRSEndpoint`](dimsp_types::SPRSEndpoint) key of the SP, `XX`
+/// handshakes without it don't authenticate the SP.
+pub async fn noise_connect<S>(
+    mut stream: S,
+    pattern: NoisePattern,
+    keypair: &NoiseKeypair,
+    endpoint_key: Option<&PublicKey>,
+) -> Result<NoiseStream<S>, NoiseError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let remote = endpoint_key.map(x25519_public_key).transpose()?;
+
+    let prologue = pattern.prologue();
+
+    let builder = Builder::new(pattern.params())
+        .local_private_key(&keypair.private)
+        .prologue(&prologue);
+
+    let state = match (pattern, &remote) {
+        (NoisePattern::IK, Some(remote)) => builder.remote_public_key(remote).build_initiator()?,
+        (NoisePattern::IK, None) => return Err(NoiseError::RemoteKeyRequired),
+        (NoisePattern::XX, _) => builder.build_initiator()?,
+    };
+
+    stream.write_all(&[pattern.id()]).await?;
+
+    let (transport, handshake_hash) = run_handshake(&mut stream, state).await?;
+
+    if let (Some(remote), Some(endpoint_key)) = (remote, endpoint_key) {
+        if transport.get_remote_static() != Some(&remote[..]) {
+            return Err(NoiseError::RemoteKey(Box::new(endpoint_key.clone())));
+        }
+    }
+
+    Ok(NoiseStream::new(stream, transport, handshake_hash))
+}
+
+/// Byte stream encrypted by an established noise session.
+pub struct NoiseStream<S> {
+    inner: S,
+    transport: TransportState,
+    handshake_hash: Vec<u8>,
+    /// Received bytes not yet decrypted.
+    read_buf: Vec<u8>,
+    plain: Vec<u8>,
+    plain_pos: usize,
+    /// Encrypted message not yet written to `inner`.
+    write_buf: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S> NoiseStream<S> {
+    fn new(inner: S, transport: TransportState, handshake_hash: Vec<u8>) -> Self {
+        Self {
+            inner,
+            transport,
+            handshake_hash,
+            read_buf: vec![],
+            plain: vec![],
+            plain_pos: 0,
+            write_buf: vec![],
+            write_pos: 0,
+        }
+    }
+
+    /// Returns X25519 static key of the peer.
+    pub fn remote_static(&self) -> Option<&[u8]> {
+        self.transport.get_remote_static()
+    }
+
+    /// Returns the noise handshake hash, equal on both ends of one session only.
+    ///
+    /// Pass it as [`server_handshake`](crate::handshake::server_handshake) binding.
+    pub fn handshake_hash(&self) -> &[u8] {
+        &self.handshake_hash
+    }
+
+    /// Pop next complete message from `read_buf`.
+    fn take_message(&mut self) -> Option<Vec<u8>> {
+        if self.read_buf.len() < 2 {
+            return None;
+        }
+
+        let len = u16::from_be_bytes([self.read_buf[0], self.read_buf[1]]) as usize;
+
+        if self.read_buf.len() < 2 + len {
+            return None;
+        }
+
+        let message = self.read_buf[2..2 + len].to_vec();
+
+        self.read_buf.drain(..2 + len);
+
+        Some(message)
+    }
+}
+
+fn invalid_data(err: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+impl<S: AsyncWrite + Unpin> NoiseStream<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.write_buf[self.write_pos..])
+            )?;
+
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+
+            self.write_pos += n;
+        }
+
+        self.write_buf.clear();
+        self.write_pos = 0;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = buf.len().min(this.plain.len() - this.plain_pos);
+
+                buf[..n].copy_from_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+
+                this.plain_pos += n;
+
+                return Poll::Ready(Ok(n));
+            }
+
+            if let Some(message) = this.take_message() {
+                this.plain.resize(message.len(), 0);
+
+                let len = this
+                    .transport
+                    .read_message(&message, &mut this.plain)
+                    .map_err(invalid_data)?;
+
+                this.plain.truncate(len);
+                this.plain_pos = 0;
+
+                continue;
+            }
+
+            let mut chunk = [0u8; 4096];
+
+            let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+
+            if n == 0 {
+                if this.read_buf.is_empty() {
+                    return Poll::Ready(Ok(0));
+                }
+
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            this.read_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_drain(cx))?;
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(MAX_NOISE_PAYLOAD);
+
+        let mut message = vec![0u8; n + TAG_LEN];
+
+        let len = this
+            .transport
+            .write_message(&buf[..n], &mut message)
+            .map_err(invalid_data)?;
+
+        this.write_buf
+            .extend_from_slice(&(len as u16).to_be_bytes());
+        this.write_buf.extend_from_slice(&message[..len]);
+
+        // the message is buffered, pending bytes go out on the next write or flush.
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_drain(cx))?;
+
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_drain(cx))?;
+
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::{TcpListener, TcpStream};
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{noise_accept, noise_connect, NoiseError, NoiseKeypair, NoisePattern};
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        (listener.accept().await.unwrap().0, client)
+    }
+
+    #[async_std::test]
+    async fn test_noise() {
+        let sp = NoiseKeypair::from_ed25519(&[7u8; 32]);
+        let client_key = NoiseKeypair::generate();
+
+        for pattern in [NoisePattern::XX, NoisePattern::IK] {
+            let (server, client) = pair().await;
+
+            let (server, client) = futures::join!(
+                noise_accept(server, &sp),
+                noise_connect(client, pattern, &client_key, Some(sp.public_key()))
+            );
+
+            let (mut server, mut client) = (server.unwrap(), client.unwrap());
+
+            assert!(server.remote_static().is_some());
+            assert_eq!(server.handshake_hash(), client.handshake_hash());
+
+            // spans several noise messages.
+            let payload = (0..200_000).map(|i| i as u8).collect::<Vec<_>>();
+
+            let mut received = vec![0u8; payload.len()];
+
+            let (written, read) = futures::join!(
+                async {
+                    client.write_all(&payload).await?;
+                    client.flush().await
+                },
+                server.read_exact(&mut received)
+            );
+
+            written.unwrap();
+            read.unwrap();
+
+            assert_eq!(received, payload);
+
+            server.write_all(b"pong").await.unwrap();
+            server.flush().await.unwrap();
+
+            let mut pong = [0u8; 4];
+
+            client.read_exact(&mut pong).await.unwrap();
+
+            assert_eq!(&pong, b"pong");
+        }
+
+        // XX handshake with an impostor SP.
+        let impostor = NoiseKeypair::from_ed25519(&[8u8; 32]);
+
+        let (server, client) = pair().await;
+
+        let (_, client) = futures::join!(
+            noise_accept(server, &impostor),
+            noise_connect(client, NoisePattern::XX, &client_key, Some(sp.public_key()))
+        );
+
+        assert!(matches!(client, Err(NoiseError::RemoteKey(_))));
+    }
+}
@@ -0,0 +1,361 @@
+//! QUIC [`DatagramGateway`] multiplexing one connection over several streams.
+//!
+//! The client opens one bidirectional control stream with its first message, the server answers
+//! every message on it. Gateways requiring the handshake open the control stream themselves with
+//! the challenge, signed over the [`exporter_binding`] of the connection. Bulk
+//! [`PullMultipartContent`](dimsp_types::SyncMessage::PullMultipartContent) transfers go through
+//! client opened unidirectional streams, so a large upload doesn't stall control messages. Every
+//! stream carries [`framing`](crate::framing) frames.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use dimsp_types::MNSAccount;
+use futures::{
+    channel::mpsc,
+    stream::{self, StreamExt},
+    SinkExt,
+};
+use quinn::{Connecting, Connection, Endpoint, RecvStream, SendStream, ServerConfig};
+
+use crate::{
+    framing::{metered_input, metered_output, FrameMeter, FramedContext, DEFAULT_MAX_FRAME},
+    handshake::{
+        deadline, server_handshake, FramedChannel, HandshakeConfig, DEFAULT_HANDSHAKE_TIMEOUT,
+        EXPORTER_LABEL, EXPORTER_LEN,
+    },
+    threadpool::run_background,
+    ChannelAccepable, DatagramConnection, DatagramGateway,
+};
+
+#[derive(Debug, Clone)]
+pub struct QuicGatewayConfig {
+    /// Listening udp address.
+    pub addr: SocketAddr,
+    /// Server certificate chain, leaf first.
+    pub cert_chain: Vec<rustls::Certificate>,
+    /// Private key of the leaf certificate.
+    pub private_key: rustls::PrivateKey,
+    /// Keep connections alive when the client address changes, e.g. wifi to cellular.
+    pub migration: bool,
+    /// Max payload length of one frame.
+    pub max_frame: usize,
+    /// Accepted connections buffered until [`accept`](DatagramGateway::accept) takes them.
+    pub accept_queue: usize,
+    /// Authenticate peers on the server opened control stream before accepting them.
+    pub handshake: Option<HandshakeConfig>,
+    /// Accept peers left unauthenticated, with an [`anonymous`](MNSAccount::anonymous) context.
+    pub allow_anonymous: bool,
+    /// Peers not through the quic handshake and the control stream setup in time are dropped.
+    pub establish_timeout: Duration,
+}
+
+impl QuicGatewayConfig {
+    pub fn new(
+        addr: SocketAddr,
+        cert_chain: Vec<rustls::Certificate>,
+        private_key: rustls::PrivateKey,
+    ) -> Self {
+        Self {
+            addr,
+            cert_chain,
+            private_key,
+            migration: true,
+            max_frame: DEFAULT_MAX_FRAME,
+            accept_queue: 100,
+            handshake: None,
+            allow_anonymous: false,
+            establish_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+}
+
+pub struct QuicGateway {
+    receiver: mpsc::Receiver<DatagramConnection<FramedContext>>,
+    endpoint: Endpoint,
+}
+
+impl QuicGateway {
+    /// Bind udp socket and start accepting connections.
+    pub fn bind(config: QuicGatewayConfig) -> anyhow::Result<Self> {
+        let mut server_config =
+            ServerConfig::with_single_cert(config.cert_chain.clone(), config.private_key.clone())?;
+
+        server_config.migration(config.migration);
+
+        let endpoint = Endpoint::server(server_config, config.addr)?;
+
+        let (sender, receiver) = mpsc::channel(config.accept_queue);
+
+        run_background(accept_loop(endpoint.clone(), sender, Arc::new(config)))?;
+
+        Ok(Self { receiver, endpoint })
+    }
+
+    /// Returns bound udp address.
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.endpoint.local_addr()?)
+    }
+}
+
+async fn accept_loop(
+    endpoint: Endpoint,
+    sender: mpsc::Sender<DatagramConnection<FramedContext>>,
+    config: Arc<QuicGatewayConfig>,
+) {
+    while let Some(connecting) = endpoint.accept().await {
+        if sender.is_closed() {
+            log::debug!("Quic gateway dropped, stop accept loop");
+            break;
+        }
+
+        if let Err(err) = run_background(establish(connecting, sender.clone(), config.clone())) {
+            log::error!("Quic spawn establish failed, {}", err);
+        }
+    }
+}
+
+/// Finish the quic handshake and set up the control stream in time.
+async fn establish(
+    connecting: Connecting,
+    mut sender: mpsc::Sender<DatagramConnection<FramedContext>>,
+    config: Arc<QuicGatewayConfig>,
+) {
+    let remote = connecting.remote_address();
+
+    let established = deadline(config.establish_timeout, connect(connecting, &config)).await;
+
+    let conn = match established
+        .map_err(anyhow::Error::from)
+        .and_then(|conn| conn)
+    {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("Quic connection({}) rejected, {}", remote, err);
+            return;
+        }
+    };
+
+    log::debug!("Quic connection({}) accepted", conn.id);
+
+    _ = sender.send(conn).await;
+}
+
+async fn connect(
+    connecting: Connecting,
+    config: &QuicGatewayConfig,
+) -> anyhow::Result<DatagramConnection<FramedContext>> {
+    let meter = Arc::new(FrameMeter::new(config.max_frame));
+
+    let connection = connecting.await?;
+
+    let (send, recv, context) = match &config.handshake {
+        Some(handshake) => authenticate(&connection, handshake).await?,
+        None if config.allow_anonymous => connection
+            .accept_bi()
+            .await
+            .map(|(send, recv)| (send, recv, MNSAccount::anonymous()))
+            .map_err(|err| anyhow::anyhow!("closed before control stream, {}", err))?,
+        None => anyhow::bail!("anonymous peers are not allowed"),
+    };
+
+    // every stream of the connection shares the meter, and so its limit.
+    let bulk_meter = meter.clone();
+
+    let bulk = stream::unfold(connection, move |connection: Connection| {
+        let meter = bulk_meter.clone();
+
+        async move {
+            match connection.accept_uni().await {
+                Ok(recv) => Some((metered_input(recv, meter), connection)),
+                Err(err) => {
+                    log::debug!("Quic connection closed, {}", err);
+                    None
+                }
+            }
+        }
+    })
+    .flatten_unordered(None);
+
+    Ok(DatagramConnection {
+        id: crate::next_connection_id(),
+        context,
+        input: stream::select(metered_input(recv, meter.clone()), bulk).boxed(),
+        output: metered_output(send, meter.clone()),
+        frames: Some(meter),
+    })
+}
+
+/// Open the control stream with the handshake challenge, peers only see a stream once it
+/// carries data.
+async fn authenticate(
+    connection: &Connection,
+    handshake: &HandshakeConfig,
+) -> anyhow::Result<(SendStream, RecvStream, MNSAccount)> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+
+    let binding = exporter_binding(connection)?;
+
+    let account = server_handshake(
+        &mut FramedChannel::new(&mut recv, &mut send),
+        handshake,
+        &binding,
+    )
+    .await?;
+
+    Ok((send, recv, account))
+}
+
+/// Handshake binding of the tls session of `connection`, both sides derive the same bytes.
+pub fn exporter_binding(connection: &Connection) -> anyhow::Result<Vec<u8>> {
+    let mut binding = vec![0u8; EXPORTER_LEN];
+
+    connection
+        .export_keying_material(&mut binding, EXPORTER_LABEL, &[])
+        .map_err(|_| anyhow::anyhow!("export keying material failed"))?;
+
+    Ok(binding)
+}
+
+impl DatagramGateway for QuicGateway {
+    type Context = FramedContext;
+
+    type Accepable<'cx> = ChannelAccepable<'cx, FramedContext>;
+
+    fn accept<'a, 'cx>(&'a mut self) -> Self::Accepable<'cx>
+    where
+        'a: 'cx,
+    {
+        ChannelAccepable::new(&mut self.receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dimsp_spnetwork::mock::MockSpNetwork;
+    use dimsp_types::{MNSAccount, PublicKey, PublicKeyBuff};
+    use ed25519_dalek::{Signer, SigningKey};
+    use futures::{SinkExt, TryStreamExt};
+    use libipld::Cid;
+    use quinn::{ClientConfig, Endpoint};
+
+    use crate::{
+        framing::{framed_input, framed_output},
+        handshake::{client_handshake, FramedChannel, HandshakeConfig},
+        test_support::message,
+        DatagramGateway,
+    };
+
+    use super::{exporter_binding, QuicGateway, QuicGatewayConfig};
+
+    #[async_std::test]
+    async fn test_quic_gateway() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+
+        let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+
+        let mut config = QuicGatewayConfig::new(
+            "127.0.0.1:0".parse().unwrap(),
+            vec![cert_der.clone()],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        );
+
+        config.allow_anonymous = true;
+
+        let mut gateway = QuicGateway::bind(config).unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+
+        roots.add(&cert_der).unwrap();
+
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+
+        let connection = client
+            .connect(gateway.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+
+        let (send, recv) = connection.open_bi().await.unwrap();
+
+        let mut control_output = framed_output(send, 1024);
+        let mut control_input = framed_input(recv, 1024);
+
+        control_output.send(message(1)).await.unwrap();
+
+        let mut conn = gateway.accept().await.unwrap();
+
+        assert_eq!(conn.try_next().await.unwrap().unwrap().id(), 1);
+
+        // bulk stream shares the connection input.
+        let mut bulk_output = framed_output(connection.open_uni().await.unwrap(), 1024);
+
+        bulk_output.send(message(2)).await.unwrap();
+
+        assert_eq!(conn.try_next().await.unwrap().unwrap().id(), 2);
+
+        conn.send(message(2)).await.unwrap();
+
+        assert_eq!(control_input.try_next().await.unwrap().unwrap().id(), 2);
+
+        // client moves to another local address.
+        client
+            .rebind(std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+            .unwrap();
+
+        control_output.send(message(3)).await.unwrap();
+
+        assert_eq!(conn.try_next().await.unwrap().unwrap().id(), 3);
+
+        conn.send(message(3)).await.unwrap();
+
+        assert_eq!(control_input.try_next().await.unwrap().unwrap().id(), 3);
+
+        // the handshake signs the exported binding of the connection.
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let pub_key = PublicKey::Ed25519(PublicKeyBuff(signing_key.verifying_key().to_bytes()));
+
+        let mut account = MNSAccount::default();
+        account.uns.id = 7;
+        account.pub_key = pub_key.clone();
+
+        let mut network = MockSpNetwork::default();
+        network.add_mns(account);
+
+        let mut config = QuicGatewayConfig::new(
+            "127.0.0.1:0".parse().unwrap(),
+            vec![cert_der],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        );
+
+        config.handshake = Some(HandshakeConfig::new("localhost", network));
+
+        let mut gateway = QuicGateway::bind(config).unwrap();
+
+        let connection = client
+            .connect(gateway.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+
+        let binding = exporter_binding(&connection).unwrap();
+
+        let (mut send, mut recv) = connection.accept_bi().await.unwrap();
+
+        let account = client_handshake(
+            &mut FramedChannel::new(&mut recv, &mut send),
+            "localhost",
+            &binding,
+            pub_key,
+            Cid::default(),
+            |payload| signing_key.sign(payload).to_bytes().to_vec(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(account.uns.id, 7);
+
+        assert_eq!(gateway.accept().await.unwrap().context.uns.id, 7);
+    }
+}
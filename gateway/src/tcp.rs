@@ -0,0 +1,389 @@
+//! TCP [`DatagramGateway`] exchanging length-delimited frames.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use async_std::net::{TcpListener, TcpStream};
+use dimsp_types::MNSAccount;
+use futures::{channel::mpsc, AsyncRead, AsyncReadExt, AsyncWrite, SinkExt, StreamExt};
+
+#[cfg(feature = "noise")]
+use crate::noise::{noise_accept, NoiseKeypair};
+#[cfg(feature = "tls")]
+use crate::tls::{exporter_binding, TlsAcceptor};
+use crate::{
+    framing::{metered_input, metered_output, FrameMeter, FramedContext, DEFAULT_MAX_FRAME},
+    handshake::{
+        deadline, server_handshake, FramedChannel, HandshakeConfig, DEFAULT_HANDSHAKE_TIMEOUT,
+    },
+    threadpool::run_background,
+    ChannelAccepable, DatagramConnection, DatagramGateway,
+};
+
+#[derive(Debug, Clone)]
+pub struct TcpGatewayConfig {
+    /// Listening addresses.
+    pub addrs: Vec<SocketAddr>,
+    /// Max payload length of one frame, larger frames close the connection.
+    pub max_frame: usize,
+    /// Accepted connections buffered until [`accept`](DatagramGateway::accept) takes them.
+    pub accept_queue: usize,
+    /// Authenticate peers before accepting them.
+    pub handshake: Option<HandshakeConfig>,
+    /// Accept peers left unauthenticated, with an [`anonymous`](MNSAccount::anonymous) context.
+    pub allow_anonymous: bool,
+    /// Peers not through tls or noise and the handshake in time are dropped.
+    pub establish_timeout: Duration,
+    /// Encrypt connections with the SP endpoint key, before the handshake.
+    #[cfg(feature = "noise")]
+    pub noise: Option<NoiseKeypair>,
+    /// Serve TLS, takes precedence over `noise`. Mapped client certificates skip the handshake.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsAcceptor>,
+}
+
+impl TcpGatewayConfig {
+    fn secured(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return true;
+        }
+
+        #[cfg(feature = "noise")]
+        if self.noise.is_some() {
+            return true;
+        }
+
+        self.handshake.is_some()
+    }
+}
+
+impl Default for TcpGatewayConfig {
+    fn default() -> Self {
+        Self {
+            addrs: vec![],
+            max_frame: DEFAULT_MAX_FRAME,
+            accept_queue: 100,
+            handshake: None,
+            allow_anonymous: false,
+            establish_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            #[cfg(feature = "noise")]
+            noise: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+/// Build framed connection of `context` account from accepted `stream`.
+fn tcp_connection(
+    stream: TcpStream,
+    max_frame: usize,
+    context: MNSAccount,
+) -> DatagramConnection<FramedContext> {
+    let meter = Arc::new(FrameMeter::new(max_frame));
+
+    DatagramConnection {
+        id: crate::next_connection_id(),
+        context,
+        input: metered_input(stream.clone(), meter.clone()),
+        output: metered_output(stream, meter.clone()),
+        frames: Some(meter),
+    }
+}
+
+pub struct TcpGateway {
+    receiver: mpsc::Receiver<DatagramConnection<FramedContext>>,
+    local_addrs: Vec<SocketAddr>,
+}
+
+impl TcpGateway {
+    /// Listen on every configured address.
+    pub async fn bind(config: TcpGatewayConfig) -> anyhow::Result<Self> {
+        let (sender, receiver) = mpsc::channel(config.accept_queue);
+
+        let config = Arc::new(config);
+
+        let mut local_addrs = vec![];
+
+        for addr in &config.addrs {
+            let listener = TcpListener::bind(addr).await?;
+
+            local_addrs.push(listener.local_addr()?);
+
+            run_background(accept_loop(listener, sender.clone(), config.clone()))?;
+        }
+
+        Ok(Self {
+            receiver,
+            local_addrs,
+        })
+    }
+
+    /// Returns bound addresses, in config order.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    mut sender: mpsc::Sender<DatagramConnection<FramedContext>>,
+    config: Arc<TcpGatewayConfig>,
+) {
+    let mut incoming = listener.incoming();
+
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("Tcp accept failed, {}", err);
+                continue;
+            }
+        };
+
+        _ = stream.set_nodelay(true);
+
+        if config.secured() {
+            if sender.is_closed() {
+                log::debug!("Tcp gateway dropped, stop accept loop");
+                break;
+            }
+
+            // peers may stall the handshake, don't block the listener.
+            if let Err(err) = run_background(establish(stream, sender.clone(), config.clone())) {
+                log::error!("Tcp spawn handshake failed, {}", err);
+            }
+
+            continue;
+        }
+
+        if !config.allow_anonymous {
+            log::debug!("Tcp peer rejected, anonymous peers are not allowed");
+            continue;
+        }
+
+        let conn = tcp_connection(stream, config.max_frame, MNSAccount::anonymous());
+
+        log::debug!("Tcp connection({}) accepted", conn.id);
+
+        if sender.send(conn).await.is_err() {
+            log::debug!("Tcp gateway dropped, stop accept loop");
+            break;
+        }
+    }
+}
+
+async fn establish(
+    stream: TcpStream,
+    mut sender: mpsc::Sender<DatagramConnection<FramedContext>>,
+    config: Arc<TcpGatewayConfig>,
+) {
+    let established = deadline(config.establish_timeout, secure(stream, &config)).await;
+
+    let conn = match established
+        .map_err(anyhow::Error::from)
+        .and_then(|conn| conn)
+    {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("Tcp handshake failed, {}", err);
+            return;
+        }
+    };
+
+    log::debug!(
+        "Tcp connection({}) accepted, mns({})",
+        conn.id,
+        conn.context.uns.id
+    );
+
+    _ = sender.send(conn).await;
+}
+
+/// Run the configured tls or noise layer, then the handshake.
+async fn secure(
+    stream: TcpStream,
+    config: &TcpGatewayConfig,
+) -> anyhow::Result<DatagramConnection<FramedContext>> {
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &config.tls {
+        let (stream, account) = tls.accept(stream).await?;
+
+        let binding = exporter_binding(stream.get_ref().1)?;
+
+        return authenticate(stream, config, account, &binding).await;
+    }
+
+    #[cfg(feature = "noise")]
+    if let Some(keypair) = &config.noise {
+        let stream = noise_accept(stream, keypair).await?;
+
+        let binding = stream.handshake_hash().to_vec();
+
+        return authenticate(stream, config, None, &binding).await;
+    }
+
+    authenticate(stream, config, None, &[]).await
+}
+
+/// Handshake unless `account` is already known, `binding` identifies the secure channel.
+async fn authenticate<S>(
+    stream: S,
+    config: &TcpGatewayConfig,
+    account: Option<MNSAccount>,
+    binding: &[u8],
+) -> anyhow::Result<DatagramConnection<FramedContext>>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = stream.split();
+
+    let context = match (account, &config.handshake) {
+        (Some(account), _) => account,
+        (None, Some(handshake)) => {
+            server_handshake(
+                &mut FramedChannel::new(&mut reader, &mut writer),
+                handshake,
+                binding,
+            )
+            .await?
+        }
+        (None, None) if config.allow_anonymous => MNSAccount::anonymous(),
+        (None, None) => anyhow::bail!("anonymous peers are not allowed"),
+    };
+
+    let meter = Arc::new(FrameMeter::new(config.max_frame));
+
+    Ok(DatagramConnection {
+        id: crate::next_connection_id(),
+        context,
+        input: metered_input(reader, meter.clone()),
+        output: metered_output(writer, meter.clone()),
+        frames: Some(meter),
+    })
+}
+
+impl DatagramGateway for TcpGateway {
+    type Context = FramedContext;
+
+    type Accepable<'cx> = ChannelAccepable<'cx, FramedContext>;
+
+    fn accept<'a, 'cx>(&'a mut self) -> Self::Accepable<'cx>
+    where
+        'a: 'cx,
+    {
+        ChannelAccepable::new(&mut self.receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_std::net::TcpStream;
+    use dimsp_spnetwork::mock::MockSpNetwork;
+    use futures::{
+        future::{self, Either},
+        AsyncWriteExt, SinkExt, TryStreamExt,
+    };
+    use futures_timer::Delay;
+
+    use crate::{
+        framing::{framed_input, framed_output, read_frame, FrameError},
+        handshake::{HandshakeConfig, MAX_HANDSHAKE_FRAME},
+        test_support::message,
+        DatagramGateway,
+    };
+
+    use super::{TcpGateway, TcpGatewayConfig};
+
+    #[async_std::test]
+    async fn test_tcp_gateway() {
+        let mut gateway = TcpGateway::bind(TcpGatewayConfig {
+            addrs: vec!["127.0.0.1:0".parse().unwrap()],
+            max_frame: 1024,
+            allow_anonymous: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let addr = gateway.local_addrs()[0];
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+
+        let mut client_input = framed_input(stream.clone(), 1024);
+        let mut client_output = framed_output(stream, 1024);
+
+        let mut conn = gateway.accept().await.unwrap();
+
+        assert!(conn.context.is_anonymous());
+
+        client_output.send(message(1)).await.unwrap();
+
+        let echo = conn.try_next().await.unwrap().unwrap();
+
+        assert_eq!(echo.id(), 1);
+
+        conn.send(echo).await.unwrap();
+
+        assert_eq!(client_input.try_next().await.unwrap().unwrap().id(), 1);
+
+        // oversized frame fails the connection input.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        stream.write_all(&2048u32.to_be_bytes()).await.unwrap();
+
+        let mut conn = gateway.accept().await.unwrap();
+
+        assert!(matches!(
+            conn.try_next().await,
+            Err(FrameError::FrameTooLarge(2048, 1024))
+        ));
+
+        // unauthenticated peers are closed unless anonymous peers are allowed.
+        let closed = TcpGateway::bind(TcpGatewayConfig {
+            addrs: vec!["127.0.0.1:0".parse().unwrap()],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let stream = TcpStream::connect(closed.local_addrs()[0]).await.unwrap();
+
+        assert!(framed_input(stream, 1024)
+            .try_next()
+            .await
+            .unwrap()
+            .is_none());
+
+        // silent peers are dropped at the establish deadline, before the handshake timeout.
+        let silent = TcpGateway::bind(TcpGatewayConfig {
+            addrs: vec!["127.0.0.1:0".parse().unwrap()],
+            handshake: Some(HandshakeConfig::new(
+                "sp.agoramail.io",
+                MockSpNetwork::default(),
+            )),
+            establish_timeout: Duration::from_millis(100),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let mut stream = TcpStream::connect(silent.local_addrs()[0]).await.unwrap();
+
+        // the challenge.
+        read_frame(&mut stream, MAX_HANDSHAKE_FRAME)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let closed = future::select(
+            Box::pin(read_frame(&mut stream, MAX_HANDSHAKE_FRAME)),
+            Delay::new(Duration::from_secs(5)),
+        )
+        .await;
+
+        assert!(matches!(closed, Either::Left((Ok(None), _))));
+    }
+}
@@ -0,0 +1,65 @@
+//! Connection and message fixtures shared by the gateway tests.
+
+use std::sync::Arc;
+
+use dimsp_types::{MNSAccount, SyncMessage};
+use futures::{channel::mpsc, future, SinkExt, StreamExt};
+use libipld::Cid;
+
+use crate::{
+    framing::{encode_message, FrameMeter, DEFAULT_MAX_FRAME},
+    DatagramConnection, MultiContext, MultiError, MultiOutput,
+};
+
+/// Returns connection of `uns` with the peer side input sender and output receiver.
+///
+/// Messages pass through the frame meter of the connection, as if they crossed a framed transport.
+pub fn connection(
+    uns: u64,
+) -> (
+    DatagramConnection<MultiContext>,
+    mpsc::Sender<SyncMessage>,
+    mpsc::Receiver<SyncMessage>,
+) {
+    let (input_sender, input) = mpsc::channel(100);
+    let (output, output_receiver) = mpsc::channel(100);
+
+    let mut context = MNSAccount::default();
+    context.uns.id = uns;
+
+    let meter = Arc::new(FrameMeter::new(DEFAULT_MAX_FRAME));
+
+    let (input_meter, output_meter) = (meter.clone(), meter.clone());
+
+    let input = input.map(move |message| {
+        let buff = encode_message(&message).map_err(MultiError::new)?;
+
+        input_meter.recv(&buff).map_err(MultiError::new)
+    });
+
+    let output = output
+        .sink_map_err(MultiError::new)
+        .with(move |message: SyncMessage| {
+            future::ready(
+                output_meter
+                    .send(&message)
+                    .map(|_| message)
+                    .map_err(MultiError::new),
+            )
+        });
+
+    let conn = DatagramConnection {
+        id: crate::next_connection_id(),
+        context,
+        input: input.boxed(),
+        output: Box::pin(output) as MultiOutput,
+        frames: Some(meter),
+    };
+
+    (conn, input_sender, output_receiver)
+}
+
+/// Sync message with request `id`, never a heartbeat.
+pub fn message(id: u64) -> SyncMessage {
+    SyncMessage::Push(id, 0, Cid::default())
+}
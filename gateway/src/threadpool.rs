@@ -0,0 +1,16 @@
+use futures::{task::SpawnExt, Future};
+use once_cell::sync::OnceCell;
+
+/// Run gateway accept loops and connection tasks on the gateway thread pool.
+pub(crate) fn run_background<Fut>(fut: Fut) -> anyhow::Result<()>
+where
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    use futures::executor::ThreadPool;
+
+    static THREAD_POOL: OnceCell<ThreadPool> = OnceCell::new();
+
+    Ok(THREAD_POOL
+        .get_or_try_init(|| ThreadPool::builder().name_prefix("dimsp-gateway-").create())?
+        .spawn(fut)?)
+}
@@ -0,0 +1,399 @@
+//! rustls server side shared by byte-stream gateways.
+//!
+//! Certificates are picked by SNI server name. [`TlsAcceptor::reload`] swaps certificates and
+//! client settings for new handshakes, established sessions are left untouched. Verified client
+//! certificates found in [`TlsConfig::identities`] skip the handshake and bind their account.
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io,
+    ops::Deref,
+    sync::{Arc, RwLock},
+};
+
+use dimsp_types::MNSAccount;
+use futures::{AsyncRead, AsyncWrite};
+use futures_rustls::server::TlsStream;
+use rustls::{
+    server::{
+        AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+        ResolvesServerCert,
+    },
+    sign::CertifiedKey,
+    Certificate, ConnectionCommon, PrivateKey, RootCertStore, ServerConfig,
+};
+use thiserror::Error;
+
+use crate::handshake::{EXPORTER_LABEL, EXPORTER_LEN};
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Io: {0}")]
+    Io(#[from] io::Error),
+    #[error("Rustls: {0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("PrivateKey: unsupported private key of server({0}), {1}")]
+    PrivateKey(String, String),
+    #[error("NoCertificate: tls config without any certificate")]
+    NoCertificate,
+}
+
+/// Certificate chain and its private key.
+#[derive(Debug, Clone)]
+pub struct TlsCertificate {
+    /// Leaf first.
+    pub cert_chain: Vec<Certificate>,
+    pub private_key: PrivateKey,
+}
+
+impl TlsCertificate {
+    pub fn new(cert_chain: Vec<Certificate>, private_key: PrivateKey) -> Self {
+        Self {
+            cert_chain,
+            private_key,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// Certificates keyed by SNI server name.
+    pub certs: HashMap<String, TlsCertificate>,
+    /// Certificate of clients without SNI or with an unknown server name.
+    pub default_cert: Option<TlsCertificate>,
+    /// Roots of client certificates, none disables client certificates.
+    pub client_roots: Option<RootCertStore>,
+    /// Reject clients without a certificate, only used with `client_roots`.
+    pub require_client_cert: bool,
+    /// Accounts of client certificates, keyed by leaf certificate.
+    pub identities: HashMap<Certificate, MNSAccount>,
+}
+
+impl TlsConfig {
+    /// Config serving `cert` for every server name.
+    pub fn new(cert: TlsCertificate) -> Self {
+        Self {
+            default_cert: Some(cert),
+            ..Default::default()
+        }
+    }
+}
+
+fn certified_key(name: &str, cert: &TlsCertificate) -> Result<Arc<CertifiedKey>, TlsError> {
+    let key = rustls::sign::any_supported_type(&cert.private_key)
+        .map_err(|err| TlsError::PrivateKey(name.to_owned(), err.to_string()))?;
+
+    Ok(Arc::new(CertifiedKey::new(cert.cert_chain.clone(), key)))
+}
+
+/// Resolve certificate by SNI server name.
+struct SniResolver {
+    certs: HashMap<String, Arc<CertifiedKey>>,
+    default_cert: Option<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.certs.get(&name.to_ascii_lowercase()))
+            .or(self.default_cert.as_ref())
+            .cloned()
+    }
+}
+
+struct TlsState {
+    server_config: Arc<ServerConfig>,
+    identities: HashMap<Certificate, MNSAccount>,
+}
+
+impl TlsState {
+    fn new(config: TlsConfig) -> Result<Self, TlsError> {
+        if config.certs.is_empty() && config.default_cert.is_none() {
+            return Err(TlsError::NoCertificate);
+        }
+
+        let mut resolver = SniResolver {
+            certs: HashMap::new(),
+            default_cert: None,
+        };
+
+        for (name, cert) in &config.certs {
+            resolver
+                .certs
+                .insert(name.to_ascii_lowercase(), certified_key(name, cert)?);
+        }
+
+        if let Some(cert) = &config.default_cert {
+            resolver.default_cert = Some(certified_key("*", cert)?);
+        }
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+
+        let builder = match config.client_roots {
+            Some(roots) if config.require_client_cert => {
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            }
+            Some(roots) => builder.with_client_cert_verifier(
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+            ),
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(Self {
+            server_config: Arc::new(builder.with_cert_resolver(Arc::new(resolver))),
+            identities: config.identities,
+        })
+    }
+}
+
+/// Reloadable TLS server side, clones share the same certificates.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    state: Arc<RwLock<Arc<TlsState>>>,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: TlsConfig) -> Result<Self, TlsError> {
+        Ok(Self {
+            state: Arc::new(RwLock::new(Arc::new(TlsState::new(config)?))),
+        })
+    }
+
+    /// Replace certificates and client settings, an invalid `config` keeps the current one.
+    pub fn reload(&self, config: TlsConfig) -> Result<(), TlsError> {
+        let state = Arc::new(TlsState::new(config)?);
+
+        *self.state.write().unwrap() = state;
+
+        Ok(())
+    }
+
+    /// Run the server side TLS handshake.
+    ///
+    /// Returns the account of the client certificate, if it has one.
+    pub async fn accept<S>(&self, stream: S) -> Result<(TlsStream<S>, Option<MNSAccount>), TlsError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let state = self.state.read().unwrap().clone();
+
+        let stream = futures_rustls::TlsAcceptor::from(state.server_config.clone())
+            .accept(stream)
+            .await?;
+
+        let account = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|leaf| state.identities.get(leaf))
+            .cloned();
+
+        Ok((stream, account))
+    }
+}
+
+/// Handshake binding of the TLS session of `conn`, both sides derive the same bytes.
+pub fn exporter_binding<C, D>(conn: &C) -> Result<Vec<u8>, TlsError>
+where
+    C: Deref<Target = ConnectionCommon<D>>,
+{
+    Ok(conn.export_keying_material(vec![0u8; EXPORTER_LEN], EXPORTER_LABEL, None)?)
+}
+
+impl Debug for TlsAcceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsAcceptor").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_std::net::{TcpListener, TcpStream};
+    use dimsp_types::MNSAccount;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use futures_rustls::{client, TlsConnector};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa};
+    use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore};
+
+    use super::{exporter_binding, TlsAcceptor, TlsCertificate, TlsConfig};
+
+    fn self_signed(name: &str) -> TlsCertificate {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+
+        TlsCertificate::new(
+            vec![Certificate(cert.serialize_der().unwrap())],
+            PrivateKey(cert.serialize_private_key_der()),
+        )
+    }
+
+    fn roots(cert: &Certificate) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+
+        roots.add(cert).unwrap();
+
+        roots
+    }
+
+    async fn connect(
+        acceptor: &TlsAcceptor,
+        config: ClientConfig,
+        name: &str,
+    ) -> (
+        anyhow::Result<client::TlsStream<TcpStream>>,
+        Option<MNSAccount>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let (server, _) = listener.accept().await.unwrap();
+
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let (server, client) = futures::join!(
+            acceptor.accept(server),
+            connector.connect(name.try_into().unwrap(), stream)
+        );
+
+        match server {
+            Ok((mut server, account)) => {
+                if let Ok(client) = &client {
+                    assert_eq!(
+                        exporter_binding(server.get_ref().1).unwrap(),
+                        exporter_binding(client.get_ref().1).unwrap()
+                    );
+                }
+
+                // echo one message, so the client sees server side failures.
+                async_std::task::spawn(async move {
+                    let mut buff = [0u8; 4];
+
+                    if server.read_exact(&mut buff).await.is_ok() {
+                        _ = server.write_all(&buff).await;
+                        _ = server.flush().await;
+                    }
+                });
+
+                (client.map_err(Into::into), account)
+            }
+            Err(err) => (Err(err.into()), None),
+        }
+    }
+
+    async fn echo(stream: &mut client::TlsStream<TcpStream>) {
+        stream.write_all(b"ping").await.unwrap();
+        stream.flush().await.unwrap();
+
+        let mut buff = [0u8; 4];
+
+        stream.read_exact(&mut buff).await.unwrap();
+
+        assert_eq!(&buff, b"ping");
+    }
+
+    #[async_std::test]
+    async fn test_tls_acceptor() {
+        let first = self_signed("sp.agoramail.io");
+        let second = self_signed("sp.agoramail.io");
+
+        // client certificate signed by a private CA.
+        let mut ca_params = CertificateParams::new(vec![]);
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+
+        let client_cert = rcgen::generate_simple_self_signed(vec!["alice".to_owned()]).unwrap();
+
+        let client_der = Certificate(client_cert.serialize_der_with_signer(&ca).unwrap());
+
+        let mut account = MNSAccount::default();
+        account.uns.id = 7;
+
+        let mut config = TlsConfig::default();
+
+        config
+            .certs
+            .insert("sp.agoramail.io".to_owned(), first.clone());
+        config.client_roots = Some(roots(&Certificate(ca.serialize_der().unwrap())));
+        config.identities.insert(client_der.clone(), account);
+
+        let acceptor = TlsAcceptor::new(config.clone()).unwrap();
+
+        let client_config = |trusted: &TlsCertificate| {
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots(&trusted.cert_chain[0]))
+        };
+
+        let (stream, account) = connect(
+            &acceptor,
+            client_config(&first)
+                .with_client_auth_cert(
+                    vec![client_der],
+                    PrivateKey(client_cert.serialize_private_key_der()),
+                )
+                .unwrap(),
+            "sp.agoramail.io",
+        )
+        .await;
+
+        let mut established = stream.unwrap();
+
+        assert_eq!(account.unwrap().uns.id, 7);
+
+        // anonymous clients are allowed, but have no account.
+        let (stream, account) = connect(
+            &acceptor,
+            client_config(&first).with_no_client_auth(),
+            "sp.agoramail.io",
+        )
+        .await;
+
+        stream.unwrap();
+        assert!(account.is_none());
+
+        // no certificate for unknown server names.
+        let (stream, _) = connect(
+            &acceptor,
+            client_config(&first).with_no_client_auth(),
+            "other.agoramail.io",
+        )
+        .await;
+
+        assert!(stream.is_err());
+
+        config
+            .certs
+            .insert("sp.agoramail.io".to_owned(), second.clone());
+
+        acceptor.reload(config).unwrap();
+
+        // new handshakes get the new certificate.
+        let (stream, _) = connect(
+            &acceptor,
+            client_config(&first).with_no_client_auth(),
+            "sp.agoramail.io",
+        )
+        .await;
+
+        assert!(stream.is_err());
+
+        let (stream, _) = connect(
+            &acceptor,
+            client_config(&second).with_no_client_auth(),
+            "sp.agoramail.io",
+        )
+        .await;
+
+        echo(&mut stream.unwrap()).await;
+
+        // sessions established before reload keep working.
+        echo(&mut established).await;
+    }
+}
@@ -0,0 +1,513 @@
+//! Unix domain socket [`DatagramGateway`] for frontends running on the SP host.
+//!
+//! Access is limited by the socket file mode, and peers are identified by their uid instead of
+//! a network handshake. Peers without uid identity may still authenticate with the handshake.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use dimsp_types::MNSAccount;
+use futures::{channel::mpsc, AsyncRead, AsyncReadExt, AsyncWrite, SinkExt, StreamExt};
+
+#[cfg(feature = "noise")]
+use crate::noise::{noise_accept, NoiseKeypair};
+use crate::{
+    framing::{metered_input, metered_output, FrameMeter, FramedContext, DEFAULT_MAX_FRAME},
+    handshake::{
+        deadline, server_handshake, FramedChannel, HandshakeConfig, DEFAULT_HANDSHAKE_TIMEOUT,
+    },
+    threadpool::run_background,
+    ChannelAccepable, DatagramConnection, DatagramGateway,
+};
+
+#[derive(Debug, Clone)]
+pub struct UnixGatewayConfig {
+    /// Socket file path, a stale socket file is replaced.
+    pub path: PathBuf,
+    /// Socket file mode, connecting requires write permission.
+    pub mode: u32,
+    /// Accounts of trusted local users, keyed by uid.
+    pub identities: HashMap<u32, MNSAccount>,
+    /// Authenticate peers without identity, takes precedence over `allow_anonymous`.
+    pub handshake: Option<HandshakeConfig>,
+    /// Accept peers without identity, with an [`anonymous`](MNSAccount::anonymous) context.
+    pub allow_anonymous: bool,
+    /// Peers not through noise and the handshake in time are dropped.
+    pub establish_timeout: Duration,
+    /// Max payload length of one frame.
+    pub max_frame: usize,
+    /// Accepted connections buffered until [`accept`](DatagramGateway::accept) takes them.
+    pub accept_queue: usize,
+    /// Encrypt connections with the SP endpoint key, e.g. when the socket is forwarded off host.
+    #[cfg(feature = "noise")]
+    pub noise: Option<NoiseKeypair>,
+}
+
+impl UnixGatewayConfig {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            mode: 0o600,
+            identities: Default::default(),
+            handshake: None,
+            allow_anonymous: false,
+            establish_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_frame: DEFAULT_MAX_FRAME,
+            accept_queue: 100,
+            #[cfg(feature = "noise")]
+            noise: None,
+        }
+    }
+
+    fn secured(&self) -> bool {
+        #[cfg(feature = "noise")]
+        if self.noise.is_some() {
+            return true;
+        }
+
+        false
+    }
+}
+
+/// Credentials of the process on the other end of a unix socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+    pub uid: u32,
+    pub gid: u32,
+    /// Not reported on platforms without `SO_PEERCRED`.
+    pub pid: Option<i32>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peer_cred<S: AsRawFd>(socket: &S) -> io::Result<PeerCred> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(PeerCred {
+        uid: cred.uid,
+        gid: cred.gid,
+        pid: Some(cred.pid),
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn peer_cred<S: AsRawFd>(socket: &S) -> io::Result<PeerCred> {
+    let mut uid = 0;
+    let mut gid = 0;
+
+    if unsafe { libc::getpeereid(socket.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(PeerCred {
+        uid,
+        gid,
+        pid: None,
+    })
+}
+
+pub struct UnixGateway {
+    receiver: mpsc::Receiver<DatagramConnection<FramedContext>>,
+    path: PathBuf,
+}
+
+impl UnixGateway {
+    /// Bind socket file and start accepting connections.
+    pub async fn bind(config: UnixGatewayConfig) -> anyhow::Result<Self> {
+        if let Ok(metadata) = fs::symlink_metadata(&config.path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is not a socket file", config.path.display()),
+                )
+                .into());
+            }
+
+            fs::remove_file(&config.path)?;
+        }
+
+        let listener = bind_private(&config.path, config.mode).await?;
+
+        let (sender, receiver) = mpsc::channel(config.accept_queue);
+
+        let path = config.path.clone();
+
+        run_background(accept_loop(listener, sender, Arc::new(config)))?;
+
+        Ok(Self { receiver, path })
+    }
+
+    /// Returns socket file path.
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+/// Bind in a private directory, so the socket is never reachable before it has `mode`.
+async fn bind_private(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "socket path has no file name")
+    })?;
+
+    let mut private = name.to_os_string();
+    private.push(format!(".{}.tmp", std::process::id()));
+
+    let dir = path.with_file_name(private);
+
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let staged = dir.join("socket");
+
+    let listener = async {
+        let listener = UnixListener::bind(&staged).await?;
+
+        fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+
+        Ok(listener)
+    }
+    .await;
+
+    _ = fs::remove_file(&staged);
+    _ = fs::remove_dir(&dir);
+
+    listener
+}
+
+impl Drop for UnixGateway {
+    fn drop(&mut self) {
+        _ = fs::remove_file(&self.path);
+    }
+}
+
+async fn accept_loop(
+    listener: UnixListener,
+    mut sender: mpsc::Sender<DatagramConnection<FramedContext>>,
+    config: Arc<UnixGatewayConfig>,
+) {
+    let mut incoming = listener.incoming();
+
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("Unix accept failed, {}", err);
+                continue;
+            }
+        };
+
+        let cred = match peer_cred(&stream) {
+            Ok(cred) => cred,
+            Err(err) => {
+                log::warn!("Unix read peer credentials failed, {}", err);
+                continue;
+            }
+        };
+
+        let identity = config.identities.get(&cred.uid).cloned();
+
+        if identity.is_none() && config.handshake.is_none() && !config.allow_anonymous {
+            log::warn!(
+                "Unix peer rejected, uid({}) pid({:?}) has no identity",
+                cred.uid,
+                cred.pid
+            );
+            continue;
+        }
+
+        // noise and handshakes wait for the peer, so they run off the accept loop.
+        if config.secured() || identity.is_none() && config.handshake.is_some() {
+            if sender.is_closed() {
+                log::debug!("Unix gateway dropped, stop accept loop");
+                break;
+            }
+
+            let establish = establish(stream, sender.clone(), config.clone(), identity);
+
+            if let Err(err) = run_background(establish) {
+                log::error!("Unix spawn handshake failed, {}", err);
+            }
+
+            continue;
+        }
+
+        let conn = unix_connection(
+            stream,
+            config.max_frame,
+            identity.unwrap_or_else(MNSAccount::anonymous),
+        );
+
+        log::debug!(
+            "Unix connection({}) accepted, mns({})",
+            conn.id,
+            conn.context.uns.id
+        );
+
+        if sender.send(conn).await.is_err() {
+            log::debug!("Unix gateway dropped, stop accept loop");
+            break;
+        }
+    }
+}
+
+/// Run the configured noise layer and the handshake in time, then hand the connection out.
+async fn establish(
+    stream: UnixStream,
+    mut sender: mpsc::Sender<DatagramConnection<FramedContext>>,
+    config: Arc<UnixGatewayConfig>,
+    identity: Option<MNSAccount>,
+) {
+    let established = deadline(config.establish_timeout, secure(stream, &config, identity)).await;
+
+    let conn = match established
+        .map_err(anyhow::Error::from)
+        .and_then(|conn| conn)
+    {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("Unix handshake failed, {}", err);
+            return;
+        }
+    };
+
+    log::debug!(
+        "Unix connection({}) accepted, mns({})",
+        conn.id,
+        conn.context.uns.id
+    );
+
+    _ = sender.send(conn).await;
+}
+
+/// Run the configured noise layer, then the handshake unless `identity` is known.
+async fn secure(
+    stream: UnixStream,
+    config: &UnixGatewayConfig,
+    identity: Option<MNSAccount>,
+) -> anyhow::Result<DatagramConnection<FramedContext>> {
+    #[cfg(feature = "noise")]
+    if let Some(keypair) = &config.noise {
+        let stream = noise_accept(stream, keypair).await?;
+
+        let binding = stream.handshake_hash().to_vec();
+
+        return authenticate(stream, config, identity, &binding).await;
+    }
+
+    authenticate(stream, config, identity, &[]).await
+}
+
+async fn authenticate<S>(
+    stream: S,
+    config: &UnixGatewayConfig,
+    identity: Option<MNSAccount>,
+    binding: &[u8],
+) -> anyhow::Result<DatagramConnection<FramedContext>>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = stream.split();
+
+    let context = match (identity, &config.handshake) {
+        (Some(account), _) => account,
+        (None, Some(handshake)) => {
+            server_handshake(
+                &mut FramedChannel::new(&mut reader, &mut writer),
+                handshake,
+                binding,
+            )
+            .await?
+        }
+        (None, None) => MNSAccount::anonymous(),
+    };
+
+    let meter = Arc::new(FrameMeter::new(config.max_frame));
+
+    Ok(DatagramConnection {
+        id: crate::next_connection_id(),
+        context,
+        input: metered_input(reader, meter.clone()),
+        output: metered_output(writer, meter.clone()),
+        frames: Some(meter),
+    })
+}
+
+fn unix_connection(
+    stream: UnixStream,
+    max_frame: usize,
+    context: MNSAccount,
+) -> DatagramConnection<FramedContext> {
+    let meter = Arc::new(FrameMeter::new(max_frame));
+
+    DatagramConnection {
+        id: crate::next_connection_id(),
+        context,
+        input: metered_input(stream.clone(), meter.clone()),
+        output: metered_output(stream, meter.clone()),
+        frames: Some(meter),
+    }
+}
+
+impl DatagramGateway for UnixGateway {
+    type Context = FramedContext;
+
+    type Accepable<'cx> = ChannelAccepable<'cx, FramedContext>;
+
+    fn accept<'a, 'cx>(&'a mut self) -> Self::Accepable<'cx>
+    where
+        'a: 'cx,
+    {
+        ChannelAccepable::new(&mut self.receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use async_std::os::unix::net::UnixStream;
+    use dimsp_types::MNSAccount;
+    use futures::{SinkExt, TryStreamExt};
+
+    use crate::{
+        framing::{framed_input, framed_output},
+        test_support::message,
+        DatagramGateway,
+    };
+
+    use super::{peer_cred, UnixGateway, UnixGatewayConfig};
+
+    #[async_std::test]
+    async fn test_unix_gateway() {
+        let dir = std::env::temp_dir().join(format!("dimsp-unix-{}", std::process::id()));
+
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let uid = unsafe { libc::getuid() };
+
+        let mut account = MNSAccount::default();
+        account.uns.id = 7;
+
+        let mut config = UnixGatewayConfig::new(dir.join("trusted.sock"));
+
+        config.identities.insert(uid, account.clone());
+
+        let mut gateway = UnixGateway::bind(config).await.unwrap();
+
+        let mode = fs::metadata(gateway.path()).unwrap().permissions().mode();
+
+        assert_eq!(mode & 0o777, 0o600);
+
+        // the private bind directory is gone.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let stream = UnixStream::connect(gateway.path()).await.unwrap();
+
+        assert_eq!(peer_cred(&stream).unwrap().uid, uid);
+
+        let mut client_input = framed_input(stream.clone(), 1024);
+        let mut client_output = framed_output(stream, 1024);
+
+        let mut conn = gateway.accept().await.unwrap();
+
+        assert_eq!(conn.context.uns.id, 7);
+
+        client_output.send(message(1)).await.unwrap();
+
+        let echo = conn.try_next().await.unwrap().unwrap();
+
+        conn.send(echo).await.unwrap();
+
+        assert_eq!(client_input.try_next().await.unwrap().unwrap().id(), 1);
+
+        #[cfg(feature = "noise")]
+        {
+            use futures::AsyncReadExt;
+
+            use crate::noise::{noise_connect, NoiseKeypair, NoisePattern};
+
+            let keypair = NoiseKeypair::from_ed25519(&[7u8; 32]);
+
+            let mut config = UnixGatewayConfig::new(dir.join("noise.sock"));
+
+            config.identities.insert(uid, account.clone());
+            config.noise = Some(keypair.clone());
+
+            let mut gateway = UnixGateway::bind(config).await.unwrap();
+
+            let stream = UnixStream::connect(gateway.path()).await.unwrap();
+
+            let client_key = NoiseKeypair::generate();
+
+            let stream = noise_connect(
+                stream,
+                NoisePattern::IK,
+                &client_key,
+                Some(keypair.public_key()),
+            )
+            .await
+            .unwrap();
+
+            let (reader, writer) = stream.split();
+
+            let mut client_input = framed_input(reader, 1024);
+            let mut client_output = framed_output(writer, 1024);
+
+            let mut conn = gateway.accept().await.unwrap();
+
+            assert_eq!(conn.context.uns.id, 7);
+
+            client_output.send(message(2)).await.unwrap();
+
+            let echo = conn.try_next().await.unwrap().unwrap();
+
+            conn.send(echo).await.unwrap();
+
+            assert_eq!(client_input.try_next().await.unwrap().unwrap().id(), 2);
+        }
+
+        // peers without identity are closed.
+        let untrusted = UnixGateway::bind(UnixGatewayConfig::new(dir.join("untrusted.sock")))
+            .await
+            .unwrap();
+
+        let stream = UnixStream::connect(untrusted.path()).await.unwrap();
+
+        assert!(framed_input(stream, 1024)
+            .try_next()
+            .await
+            .unwrap()
+            .is_none());
+
+        let path = untrusted.path().clone();
+
+        drop(untrusted);
+
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,482 @@
+//! WebSocket [`DatagramGateway`] for browser and mobile web clients.
+//!
+//! Every binary message carries one DagCbor encoded [`SyncMessage`], pings are answered while
+//! the connection input is polled.
+
+use std::{net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+
+use async_std::net::{TcpListener, TcpStream};
+use async_trait::async_trait;
+use async_tungstenite::{
+    tungstenite::{
+        self,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::StatusCode,
+        protocol::WebSocketConfig,
+        Message,
+    },
+    WebSocketStream,
+};
+use dimsp_types::{MNSAccount, SyncMessage};
+use futures::{channel::mpsc, future, stream::BoxStream, Sink, SinkExt, StreamExt, TryStreamExt};
+use thiserror::Error;
+
+#[cfg(feature = "noise")]
+use crate::noise::{noise_accept, NoiseKeypair};
+#[cfg(feature = "tls")]
+use crate::tls::{exporter_binding, TlsAcceptor};
+use crate::{
+    framing::{FrameError, FrameMeter, DEFAULT_MAX_FRAME},
+    handshake::{
+        deadline, server_handshake, HandshakeChannel, HandshakeConfig, HandshakeError,
+        DEFAULT_HANDSHAKE_TIMEOUT,
+    },
+    threadpool::run_background,
+    ChannelAccepable, DatagramConnection, DatagramContext, DatagramGateway,
+};
+
+#[derive(Debug, Error)]
+pub enum WebSocketError {
+    #[error("WebSocket: {0}")]
+    WebSocket(#[from] tungstenite::Error),
+    #[error("TextMessage: text messages are not supported")]
+    TextMessage,
+    #[error("Frame: {0}")]
+    Frame(#[from] FrameError),
+}
+
+pub type WebSocketInput = BoxStream<'static, Result<SyncMessage, WebSocketError>>;
+
+pub type WebSocketOutput = Pin<Box<dyn Sink<SyncMessage, Error = WebSocketError> + Send>>;
+
+/// Context of [`WebSocketGateway`] connections.
+pub struct WebSocketContext;
+
+impl DatagramContext for WebSocketContext {
+    type StreamError = WebSocketError;
+    type SinkError = WebSocketError;
+    type Item = SyncMessage;
+    type Context = MNSAccount;
+    type Input = WebSocketInput;
+    type Output = WebSocketOutput;
+}
+
+#[derive(Debug, Clone)]
+pub struct WebSocketGatewayConfig {
+    /// Listening addresses.
+    pub addrs: Vec<SocketAddr>,
+    /// Request path of the upgrade request, other paths are answered with 404.
+    pub path: String,
+    /// Allowed `Origin` header values, empty allows every origin.
+    ///
+    /// Requests without `Origin` come from non-browser clients and are always allowed.
+    pub origins: Vec<String>,
+    /// Max payload length of one message.
+    pub max_frame: usize,
+    /// Accepted connections buffered until [`accept`](DatagramGateway::accept) takes them.
+    pub accept_queue: usize,
+    /// Authenticate peers after the upgrade.
+    pub handshake: Option<HandshakeConfig>,
+    /// Accept peers left unauthenticated, with an [`anonymous`](MNSAccount::anonymous) context.
+    pub allow_anonymous: bool,
+    /// Peers not through tls or noise, the upgrade and the handshake in time are dropped.
+    pub establish_timeout: Duration,
+    /// Encrypt the TCP stream with the SP endpoint key, before the upgrade.
+    #[cfg(feature = "noise")]
+    pub noise: Option<NoiseKeypair>,
+    /// Serve `wss`, takes precedence over `noise`. Mapped client certificates skip the handshake.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsAcceptor>,
+}
+
+impl Default for WebSocketGatewayConfig {
+    fn default() -> Self {
+        Self {
+            addrs: vec![],
+            path: "/".to_owned(),
+            origins: vec![],
+            max_frame: DEFAULT_MAX_FRAME,
+            accept_queue: 100,
+            handshake: None,
+            allow_anonymous: false,
+            establish_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            #[cfg(feature = "noise")]
+            noise: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+impl WebSocketGatewayConfig {
+    fn check_request(&self, request: &Request) -> Result<(), StatusCode> {
+        if request.uri().path() != self.path {
+            return Err(StatusCode::NOT_FOUND);
+        }
+
+        if self.origins.is_empty() {
+            return Ok(());
+        }
+
+        match request.headers().get("Origin") {
+            Some(origin) => {
+                if self
+                    .origins
+                    .iter()
+                    .any(|allowed| allowed.as_bytes() == origin.as_bytes())
+                {
+                    Ok(())
+                } else {
+                    Err(StatusCode::FORBIDDEN)
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: Some(self.max_frame),
+            max_frame_size: Some(self.max_frame),
+            ..Default::default()
+        }
+    }
+}
+
+/// Handshake messages travel in binary messages, like sync messages.
+#[async_trait]
+impl<S> HandshakeChannel for WebSocketStream<S>
+where
+    S: futures::AsyncRead + futures::AsyncWrite + Unpin + Send,
+{
+    async fn send_frame(&mut self, buff: Vec<u8>) -> Result<(), HandshakeError> {
+        self.send(Message::Binary(buff))
+            .await
+            .map_err(|err| HandshakeError::Transport(err.to_string()))
+    }
+
+    async fn recv_frame(&mut self) -> Result<Option<Vec<u8>>, HandshakeError> {
+        while let Some(message) = self.next().await {
+            match message.map_err(|err| HandshakeError::Transport(err.to_string()))? {
+                Message::Binary(buff) => return Ok(Some(buff)),
+                Message::Text(_) => {
+                    return Err(HandshakeError::Transport(
+                        WebSocketError::TextMessage.to_string(),
+                    ))
+                }
+                Message::Close(_) => return Ok(None),
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Split websocket `stream` into [`SyncMessage`] input and output, binary messages are limited
+/// and observed by `meter` like frames.
+pub fn websocket_framed<S>(
+    stream: WebSocketStream<S>,
+    meter: Arc<FrameMeter>,
+) -> (WebSocketInput, WebSocketOutput)
+where
+    S: futures::AsyncRead + futures::AsyncWrite + Unpin + Send + 'static,
+{
+    let (sink, stream) = stream.split();
+
+    let input_meter = meter.clone();
+
+    let input = stream
+        .map_err(WebSocketError::from)
+        .try_filter_map(move |message| {
+            future::ready(match message {
+                Message::Binary(buff) => input_meter.recv(&buff).map(Some).map_err(Into::into),
+                Message::Text(_) => Err(WebSocketError::TextMessage),
+                // pongs are queued by tungstenite, close ends the stream on next poll.
+                Message::Ping(_) | Message::Pong(_) | Message::Close(_) | Message::Frame(_) => {
+                    Ok(None)
+                }
+            })
+        })
+        .boxed();
+
+    let output = sink.with(move |message: SyncMessage| {
+        future::ready(
+            meter
+                .send(&message)
+                .map(Message::Binary)
+                .map_err(WebSocketError::from),
+        )
+    });
+
+    (input, Box::pin(output))
+}
+
+pub struct WebSocketGateway {
+    receiver: mpsc::Receiver<DatagramConnection<WebSocketContext>>,
+    local_addrs: Vec<SocketAddr>,
+}
+
+impl WebSocketGateway {
+    /// Listen on every configured address.
+    pub async fn bind(config: WebSocketGatewayConfig) -> anyhow::Result<Self> {
+        let (sender, receiver) = mpsc::channel(config.accept_queue);
+
+        let config = Arc::new(config);
+
+        let mut local_addrs = vec![];
+
+        for addr in &config.addrs {
+            let listener = TcpListener::bind(addr).await?;
+
+            local_addrs.push(listener.local_addr()?);
+
+            run_background(accept_loop(listener, sender.clone(), config.clone()))?;
+        }
+
+        Ok(Self {
+            receiver,
+            local_addrs,
+        })
+    }
+
+    /// Returns bound addresses, in config order.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    sender: mpsc::Sender<DatagramConnection<WebSocketContext>>,
+    config: Arc<WebSocketGatewayConfig>,
+) {
+    let mut incoming = listener.incoming();
+
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("WebSocket accept failed, {}", err);
+                continue;
+            }
+        };
+
+        if sender.is_closed() {
+            log::debug!("WebSocket gateway dropped, stop accept loop");
+            break;
+        }
+
+        // slow upgrade requests must not block the listener.
+        if let Err(err) = run_background(upgrade(stream, sender.clone(), config.clone())) {
+            log::error!("WebSocket spawn upgrade failed, {}", err);
+        }
+    }
+}
+
+async fn upgrade(
+    stream: TcpStream,
+    mut sender: mpsc::Sender<DatagramConnection<WebSocketContext>>,
+    config: Arc<WebSocketGatewayConfig>,
+) {
+    _ = stream.set_nodelay(true);
+
+    let established = deadline(config.establish_timeout, secure(stream, &config)).await;
+
+    let conn = match established
+        .map_err(anyhow::Error::from)
+        .and_then(|conn| conn)
+    {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("WebSocket upgrade failed, {}", err);
+            return;
+        }
+    };
+
+    log::debug!("WebSocket connection({}) accepted", conn.id);
+
+    _ = sender.send(conn).await;
+}
+
+/// Run the configured tls or noise layer, then upgrade.
+async fn secure(
+    stream: TcpStream,
+    config: &WebSocketGatewayConfig,
+) -> anyhow::Result<DatagramConnection<WebSocketContext>> {
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &config.tls {
+        let (stream, account) = tls.accept(stream).await?;
+
+        let binding = exporter_binding(stream.get_ref().1)?;
+
+        return serve(stream, config, account, &binding).await;
+    }
+
+    #[cfg(feature = "noise")]
+    if let Some(keypair) = &config.noise {
+        let stream = noise_accept(stream, keypair).await?;
+
+        let binding = stream.handshake_hash().to_vec();
+
+        return serve(stream, config, None, &binding).await;
+    }
+
+    serve(stream, config, None, &[]).await
+}
+
+/// Upgrade `stream`, then handshake unless `account` is already known.
+///
+/// `binding` identifies the secure channel under the websocket, if any.
+async fn serve<S>(
+    stream: S,
+    config: &WebSocketGatewayConfig,
+    account: Option<MNSAccount>,
+    binding: &[u8],
+) -> anyhow::Result<DatagramConnection<WebSocketContext>>
+where
+    S: futures::AsyncRead + futures::AsyncWrite + Unpin + Send + 'static,
+{
+    // the rejection type is fixed by tungstenite.
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        config
+            .check_request(request)
+            .map(|_| response)
+            .map_err(|status| {
+                let mut response = ErrorResponse::new(None);
+                *response.status_mut() = status;
+                response
+            })
+    };
+
+    let mut stream = async_tungstenite::accept_hdr_async_with_config(
+        stream,
+        callback,
+        Some(config.websocket_config()),
+    )
+    .await?;
+
+    let context = match (account, &config.handshake) {
+        (Some(account), _) => account,
+        (None, Some(handshake)) => server_handshake(&mut stream, handshake, binding).await?,
+        (None, None) if config.allow_anonymous => MNSAccount::anonymous(),
+        (None, None) => anyhow::bail!("anonymous peers are not allowed"),
+    };
+
+    let meter = Arc::new(FrameMeter::new(config.max_frame));
+
+    let (input, output) = websocket_framed(stream, meter.clone());
+
+    Ok(DatagramConnection {
+        id: crate::next_connection_id(),
+        context,
+        input,
+        output,
+        frames: Some(meter),
+    })
+}
+
+impl DatagramGateway for WebSocketGateway {
+    type Context = WebSocketContext;
+
+    type Accepable<'cx> = ChannelAccepable<'cx, WebSocketContext>;
+
+    fn accept<'a, 'cx>(&'a mut self) -> Self::Accepable<'cx>
+    where
+        'a: 'cx,
+    {
+        ChannelAccepable::new(&mut self.receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use async_std::net::TcpStream;
+    use async_tungstenite::{
+        tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+        WebSocketStream,
+    };
+    use futures::{SinkExt, StreamExt};
+
+    use crate::{
+        framing::{decode_message, encode_message},
+        test_support::message,
+        DatagramGateway,
+    };
+
+    use super::{WebSocketGateway, WebSocketGatewayConfig};
+
+    async fn connect(
+        addr: SocketAddr,
+        path: &str,
+        origin: &'static str,
+    ) -> anyhow::Result<WebSocketStream<TcpStream>> {
+        let mut request = format!("ws://{}{}", addr, path).into_client_request()?;
+
+        request
+            .headers_mut()
+            .insert("Origin", HeaderValue::from_static(origin));
+
+        let stream = TcpStream::connect(addr).await?;
+
+        Ok(async_tungstenite::client_async(request, stream).await?.0)
+    }
+
+    #[async_std::test]
+    async fn test_websocket_gateway() {
+        let mut gateway = WebSocketGateway::bind(WebSocketGatewayConfig {
+            addrs: vec!["127.0.0.1:0".parse().unwrap()],
+            path: "/dimsp".to_owned(),
+            origins: vec!["https://app.agoramail.io".to_owned()],
+            allow_anonymous: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let addr = gateway.local_addrs()[0];
+
+        assert!(connect(addr, "/", "https://app.agoramail.io")
+            .await
+            .is_err());
+        assert!(connect(addr, "/dimsp", "https://evil.io").await.is_err());
+
+        let mut client = connect(addr, "/dimsp", "https://app.agoramail.io")
+            .await
+            .unwrap();
+
+        let mut conn = gateway.accept().await.unwrap();
+
+        client.send(Message::Ping(vec![1])).await.unwrap();
+
+        client
+            .send(Message::Binary(encode_message(&message(1)).unwrap()))
+            .await
+            .unwrap();
+
+        let echo = conn.try_next().await.unwrap().unwrap();
+
+        assert_eq!(echo.id(), 1);
+
+        conn.send(echo).await.unwrap();
+
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Message::Pong(vec![1])
+        );
+
+        match client.next().await.unwrap().unwrap() {
+            Message::Binary(buff) => {
+                assert_eq!(decode_message(&buff).unwrap().id(), 1)
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+
+        client.close(None).await.unwrap();
+
+        assert!(conn.try_next().await.unwrap().is_none());
+    }
+}
@@ -1,13 +1,21 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use dimsp_spnetwork::SpNetwork;
-use dimsp_types::{sync_message::Type, MNSAccount, SyncMessage};
+use dimsp_types::{Envelope, EnvelopeQuery, MNSAccount, Mime, SyncMessage};
 use futures::{task::SpawnError, SinkExt, TryStreamExt};
+use libipld::Cid;
 
 use thiserror::Error;
 
 use crate::threadpool::run_background;
 
 use dimsp_gateway::*;
-use dimsp_storage::Storage;
+use dimsp_storage::{
+    codec, envelope::EnvelopeIndex, kv::MimeKV, timeline::Timeline, txn::Transaction,
+};
 
 #[derive(Debug, Error)]
 pub enum DismpError {
@@ -15,71 +23,126 @@ pub enum DismpError {
     SpwanError(#[from] SpawnError),
     #[error("StartTwice: call start method twice")]
     StartTwice,
-    #[error("SyncMessageTypeError: DimspHub can'nt handle sync message type with id {0}")]
-    SyncMessageType(i32),
+    #[error("SyncMessageType: DimspHub can'nt handle sync message {0}")]
+    SyncMessageType(&'static str),
+    #[error("PushId: push({0}) is already in progress")]
+    PushId(u64),
+    /// Pushed content doesn't hash to the pulled cid.
+    #[error("PushContent: content of mime({0}) doesn't match its cid")]
+    PushContent(Cid),
+    #[error("NotFound: {0} not found")]
+    NotFound(Cid),
+    /// Pushed content answers a push that isn't in progress.
+    #[error("UnknownPush: push({0}) is not in progress")]
+    UnknownPush(u64),
+    #[error("PushLimit: connection already has {0} pushes in progress")]
+    PushLimit(usize),
+    #[error("PendingBytes: pushes in progress would stage more than {0} bytes")]
+    PendingBytes(u64),
+    #[error("Quota: mns({0}) storage quota of {1} bytes exceeded")]
+    Quota(u64, u64),
+    #[error("UnknownRecipient: mns({0}) not found")]
+    UnknownRecipient(u64),
+    /// Gateway accepted the connection without authenticating it.
+    #[error("Anonymous: {0} requires an authenticated connection")]
+    Anonymous(&'static str),
+}
 
-    /// Read sync message content is none
-    #[error("SyncMessageContent: SyncMessage content({0}) is none")]
-    SyncMessageContent(String),
+/// Per connection limits of [`DimspHub`].
+#[derive(Debug, Clone)]
+pub struct DimspHubConfig {
+    /// Max pushes waiting for pulled content.
+    pub max_pending_pushes: usize,
+    /// Max bytes staged by the pushes in progress.
+    pub max_pending_bytes: u64,
+}
+
+impl Default for DimspHubConfig {
+    fn default() -> Self {
+        Self {
+            max_pending_pushes: 16,
+            max_pending_bytes: 64 * 1024 * 1024,
+        }
+    }
 }
 
 /// Dimsp service provider node implementation
 #[derive(Debug, Clone)]
-pub struct DimspHub<G, N, S> {
+pub struct DimspHub<G, N, K, T> {
     gateway: G,
-    #[allow(unused)]
     network: N,
-    storage: S,
+    kv: K,
+    timeline: T,
+    config: DimspHubConfig,
 }
 
-impl<G, N, S> Drop for DimspHub<G, N, S> {
+impl<G, N, K, T> Drop for DimspHub<G, N, K, T> {
     fn drop(&mut self) {
         log::debug!("drop hub");
     }
 }
 
-impl<G, N, S> Default for DimspHub<G, N, S>
+impl<G, N, K, T> Default for DimspHub<G, N, K, T>
 where
     G: DatagramGateway + Default,
     N: SpNetwork + Default,
-    S: Storage + Default,
+    K: MimeKV + Default,
+    T: Timeline + EnvelopeIndex + Default,
 {
     fn default() -> Self {
-        Self::new(Default::default(), Default::default(), Default::default())
+        Self::new(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        )
     }
 }
 
-impl<G, N, S> From<(G, N, S)> for DimspHub<G, N, S>
+impl<G, N, K, T> From<(G, N, K, T)> for DimspHub<G, N, K, T>
 where
     G: DatagramGateway,
     N: SpNetwork,
-    S: Storage,
+    K: MimeKV,
+    T: Timeline + EnvelopeIndex,
 {
-    fn from(value: (G, N, S)) -> Self {
-        Self::new(value.0, value.1, value.2)
+    fn from(value: (G, N, K, T)) -> Self {
+        Self::new(value.0, value.1, value.2, value.3)
     }
 }
 
-impl<G, N, S> DimspHub<G, N, S>
+impl<G, N, K, T> DimspHub<G, N, K, T>
 where
     G: DatagramGateway,
     N: SpNetwork,
-    S: Storage,
+    K: MimeKV,
+    T: Timeline + EnvelopeIndex,
 {
-    /// Create new DimspHub instance from ([`gateway`](DatagramGateway),[`network`](SpNetwork),[`stoorage`](Storage))
-    pub fn new(gateway: G, network: N, storage: S) -> Self {
+    /// Create new DimspHub instance from ([`gateway`](DatagramGateway),[`network`](SpNetwork),[`kv`](MimeKV),[`timeline`](Timeline))
+    ///
+    /// `timeline` also keeps the [`envelope index`](EnvelopeIndex) searched by clients.
+    pub fn new(gateway: G, network: N, kv: K, timeline: T) -> Self {
         Self {
             gateway,
             network,
-            storage,
+            kv,
+            timeline,
+            config: Default::default(),
         }
     }
+
+    /// Replace default connection limits with `config`.
+    pub fn with_config(mut self, config: DimspHubConfig) -> Self {
+        self.config = config;
+        self
+    }
 }
-impl<G, N, S> DimspHub<G, N, S>
+impl<G, N, K, T> DimspHub<G, N, K, T>
 where
     G: DatagramGateway + Send + Sync + 'static,
     N: SpNetwork + Clone + Send + Sync + 'static,
-    S: Storage + Clone + Send + Sync + 'static,
+    K: MimeKV + Clone + Send + Sync + 'static,
+    T: Timeline + EnvelopeIndex + Clone + Send + Sync + 'static,
 {
     /// Start [`DimspHub`] main event loop in background thread.
     pub fn start(self) -> anyhow::Result<()> {
@@ -98,22 +161,68 @@ where
     }
 }
 
-struct DimspHubSession<N, S> {
-    #[allow(unused)]
+/// Push waiting for the client to send missing mime objects.
+struct PendingPush {
+    /// Recipient account of the message.
+    to: MNSAccount,
+    root: Cid,
+    /// Cid of the last [`SyncMessage::PullMultipart`] sent to the client.
+    waiting: Cid,
+    /// Missing cids not pulled yet.
+    queue: Vec<Cid>,
+    /// Cids already pulled or queued.
+    requested: HashSet<Cid>,
+    /// Encoded bytes of every object of the message found so far, stored or staged.
+    size: u64,
+    /// Encoded bytes staged in `txn`.
+    staged: u64,
+    txn: Transaction,
+}
+
+impl PendingPush {
+    fn new(to: MNSAccount, root: Cid) -> Self {
+        Self {
+            to,
+            root,
+            waiting: root,
+            queue: vec![],
+            requested: HashSet::from([root]),
+            size: 0,
+            staged: 0,
+            txn: Transaction::default(),
+        }
+    }
+}
+
+struct DimspHubSession<N, K, T> {
     network: N,
-    storage: S,
+    kv: K,
+    timeline: T,
+    /// Pushes in progress by request id.
+    pending: HashMap<u64, PendingPush>,
+    config: DimspHubConfig,
+    /// Bytes of delivered messages by recipient, summed from the envelope index on first use.
+    used: HashMap<u64, u64>,
+    /// Multipart children of the objects pulled by the client.
+    readable: HashSet<Cid>,
 }
 
-impl<G, N, S> DimspHub<G, N, S>
+impl<G, N, K, T> DimspHub<G, N, K, T>
 where
     G: DatagramGateway + Send + Sync + 'static,
     N: SpNetwork + Clone + Send + Sync + 'static,
-    S: Storage + Clone + Send + Sync + 'static,
+    K: MimeKV + Clone + Send + Sync + 'static,
+    T: Timeline + EnvelopeIndex + Clone + Send + Sync + 'static,
 {
-    fn new_session(&self) -> DimspHubSession<N, S> {
+    fn new_session(&self) -> DimspHubSession<N, K, T> {
         DimspHubSession {
             network: self.network.clone(),
-            storage: self.storage.clone(),
+            kv: self.kv.clone(),
+            timeline: self.timeline.clone(),
+            pending: Default::default(),
+            config: self.config.clone(),
+            used: Default::default(),
+            readable: Default::default(),
         }
     }
 
@@ -123,7 +232,7 @@ where
             log::debug!("loop {}", conn.id);
             let session = self.new_session();
 
-            _ = run_background(async move {
+            run_background(async move {
                 let conn_id = conn.id;
                 let uns_id = conn.context.uns.id;
                 match session.handle_incoming_connection::<G>(conn).await {
@@ -146,18 +255,17 @@ where
     }
 }
 
-impl<N, S> DimspHubSession<N, S>
+impl<N, K, T> DimspHubSession<N, K, T>
 where
-    N: SpNetwork + Clone + Send + 'static,
-    S: Storage + Clone + Send + 'static,
+    N: SpNetwork + Clone + Send + Sync + 'static,
+    K: MimeKV + Clone + Send + Sync + 'static,
+    T: Timeline + EnvelopeIndex + Clone + Send + Sync + 'static,
 {
     /// Handle incoming user connection.
     async fn handle_incoming_connection<G: DatagramGateway + Send + Sync + 'static>(
         mut self,
         mut conn: DatagramConnection<G::Context>,
     ) -> anyhow::Result<()> {
-        use protobuf::Enum;
-
         log::debug!(
             "handle user({}) connection({})",
             conn.context.uns.id,
@@ -165,219 +273,342 @@ where
         );
 
         while let Some(message) = conn.input.try_next().await? {
-            // extract message type first.
-            let message_type = Type::from_i32(message.type_.value())
-                .ok_or(DismpError::SyncMessageType(message.type_.value()))?;
+            let id = message.id();
 
-            let message_id = message.id;
+            // failed requests are answered, protocol and storage errors close the connection.
+            let response = match self.handle(&conn.context, message).await {
+                Ok(response) => response,
+                Err(err) => match err.downcast_ref::<DismpError>() {
+                    Some(DismpError::SyncMessageType(_)) | None => return Err(err),
+                    Some(_) => {
+                        log::debug!("request({}) failed, {}", id, err);
 
-            let mut response = match message_type {
-                Type::OpenWriteStream => {
-                    self.open_write_stream(conn.context.clone(), message)
-                        .await?
-                }
-                Type::CloseWriteStream => {
-                    self.close_write_stream(conn.context.clone(), message)
-                        .await?
-                }
-                Type::OpenInbox => self.open_inbox(conn.context.clone(), message).await?,
-                Type::OpenNextInboxStream => {
-                    self.open_next_inbox_stream(conn.context.clone(), message)
-                        .await?
-                }
-                Type::CloseInboxStream => {
-                    self.close_inbox_stream(conn.context.clone(), message)
-                        .await?
-                }
-                Type::ReadFragment => self.read_fragment(conn.context.clone(), message).await?,
-                Type::WriteFragment => self.write_fragment(conn.context.clone(), message).await?,
-                _ => {
-                    return Err(DismpError::SyncMessageType(message.type_.value()).into());
-                }
+                        Some(SyncMessage::Error(id, err.to_string()))
+                    }
+                },
             };
 
-            response.id = message_id;
+            if let Some(response) = response {
+                log::debug!("send response {}", response.id());
 
-            log::debug!("send response {}", message_id);
-
-            conn.output.send(response).await?;
+                conn.output.send(response).await?;
+            }
         }
 
         Ok(())
     }
 
-    async fn open_write_stream(
+    async fn handle(
         &mut self,
-        mns: MNSAccount,
+        mns: &MNSAccount,
         message: SyncMessage,
-    ) -> anyhow::Result<SyncMessage> {
-        if !message.has_open_write_stream() {
-            return Err(DismpError::SyncMessageContent("OpenWriteStream".to_owned()).into());
+    ) -> anyhow::Result<Option<SyncMessage>> {
+        let response = match message {
+            // heartbeats of clients talking to a gateway without heartbeat layer.
+            SyncMessage::Ping(id) => Some(SyncMessage::Pong(id)),
+            SyncMessage::Pong(_) => None,
+            message if mns.is_anonymous() => {
+                return Err(DismpError::Anonymous(message.name()).into());
+            }
+            SyncMessage::Push(id, to, cid) => self.push(mns, id, to, cid).await?,
+            SyncMessage::PullMultipartContent(id, mime) => self.push_content(mns, id, mime).await?,
+            SyncMessage::PullMultipart(id, cid) => Some(self.pull(mns, id, cid).await?),
+            SyncMessage::Search(id, query) => Some(self.search(mns, id, query).await?),
+            SyncMessage::SetFlags(id, cid, flags) => {
+                Some(self.set_flags(mns, id, cid, flags).await?)
+            }
+            message => {
+                return Err(DismpError::SyncMessageType(message.name()).into());
+            }
+        };
+
+        Ok(response)
+    }
+
+    /// Start delivering message `root` from `mns` to the timeline of account `to`, pulling missing
+    /// objects from the client.
+    ///
+    /// The push is acknowledged by echoing it once the message is delivered.
+    async fn push(
+        &mut self,
+        mns: &MNSAccount,
+        id: u64,
+        to: u64,
+        root: Cid,
+    ) -> anyhow::Result<Option<SyncMessage>> {
+        if self.pending.contains_key(&id) {
+            return Err(DismpError::PushId(id).into());
         }
 
-        let ack = self
-            .storage
-            .open_write_stream(mns, message.open_write_stream().clone())
-            .await?;
+        if self.pending.len() >= self.config.max_pending_pushes {
+            return Err(DismpError::PushLimit(self.pending.len()).into());
+        }
 
-        let mut response = SyncMessage::new();
+        // already delivered, pushes are idempotent.
+        if self.timeline.envelope(to, root).await?.is_some() {
+            return Ok(Some(SyncMessage::Push(id, to, root)));
+        }
 
-        response.id = message.id;
+        let recipient = self
+            .network
+            .mns_by_id(to)
+            .await?
+            .ok_or(DismpError::UnknownRecipient(to))?;
 
-        response.type_ = Type::OpenWriteStreamAck.into();
+        let mut push = PendingPush::new(recipient, root);
 
-        response.set_open_write_stream_ack(ack);
+        match self.kv.get_raw(root).await? {
+            Some(data) => {
+                push.size += data.len() as u64;
 
-        Ok(response)
+                self.queue_missing(&mut push, &codec::decode(&root, &data)?)
+                    .await?;
+            }
+            None => push.queue.push(root),
+        }
+
+        self.next_pull(mns, id, push).await
     }
 
-    async fn close_write_stream(
+    /// Stage mime object pulled by push `id`.
+    async fn push_content(
         &mut self,
-        _mns: MNSAccount,
-        message: SyncMessage,
-    ) -> anyhow::Result<SyncMessage> {
-        if !message.has_close_write_stream() {
-            return Err(DismpError::SyncMessageContent("CloseWriteStream".to_owned()).into());
+        mns: &MNSAccount,
+        id: u64,
+        mime: Mime,
+    ) -> anyhow::Result<Option<SyncMessage>> {
+        let mut push = self
+            .pending
+            .remove(&id)
+            .ok_or(DismpError::UnknownPush(id))?;
+
+        let cid = push.waiting;
+
+        let data = codec::encode(&cid, &mime)?;
+
+        codec::verify(&cid, &data).map_err(|_| DismpError::PushContent(cid))?;
+
+        let pending_bytes = self.pending.values().map(|push| push.staged).sum::<u64>();
+
+        if pending_bytes + push.staged + data.len() as u64 > self.config.max_pending_bytes {
+            return Err(DismpError::PendingBytes(self.config.max_pending_bytes).into());
         }
 
-        let ack = self
-            .storage
-            .close_write_stream(message.close_write_stream().clone())
+        self.check_quota(&push.to, push.staged + data.len() as u64)
             .await?;
 
-        let mut response = SyncMessage::new();
+        push.staged += data.len() as u64;
+        push.size += data.len() as u64;
 
-        response.id = message.id;
+        push.txn.put_raw(cid, data);
 
-        response.type_ = Type::CloseWriteStreamAck.into();
+        self.queue_missing(&mut push, &mime).await?;
 
-        response.set_close_write_stream_ack(ack);
-
-        Ok(response)
+        self.next_pull(mns, id, push).await
     }
 
-    async fn open_inbox(
-        &mut self,
-        mns: MNSAccount,
-        message: SyncMessage,
-    ) -> anyhow::Result<SyncMessage> {
-        let ack = self.storage.open_inbox(mns).await?;
+    /// Fail if storing `bytes` more would exceed the quota of `mns`, a zero quota is unlimited.
+    async fn check_quota(&mut self, mns: &MNSAccount, bytes: u64) -> anyhow::Result<()> {
+        if mns.quota == 0 {
+            return Ok(());
+        }
 
-        let mut response = SyncMessage::new();
+        let used = match self.used.get(&mns.uns.id) {
+            Some(used) => *used,
+            None => {
+                let used = self
+                    .timeline
+                    .search(mns.uns.id, Default::default())
+                    .await?
+                    .iter()
+                    .map(|envelope| envelope.size)
+                    .sum();
+
+                *self.used.entry(mns.uns.id).or_insert(used)
+            }
+        };
 
-        response.id = message.id;
+        if used.saturating_add(bytes) > mns.quota {
+            return Err(DismpError::Quota(mns.uns.id, mns.quota).into());
+        }
 
-        response.type_ = Type::OpenInboxAck.into();
+        Ok(())
+    }
 
-        response.set_inbox(ack);
+    /// Queue descendants of `mime` that are neither stored nor requested, stored ones are walked
+    /// and counted into the message size.
+    async fn queue_missing(&self, push: &mut PendingPush, mime: &Mime) -> anyhow::Result<()> {
+        let mut children = mime
+            .multipart
+            .iter()
+            .filter(|cid| push.requested.insert(**cid))
+            .copied()
+            .collect::<Vec<_>>();
+
+        while let Some(cid) = children.pop() {
+            match self.kv.get_raw(cid).await? {
+                Some(data) => {
+                    push.size += data.len() as u64;
+
+                    children.extend(
+                        codec::decode(&cid, &data)?
+                            .multipart
+                            .into_iter()
+                            .filter(|cid| push.requested.insert(*cid)),
+                    );
+                }
+                None => push.queue.push(cid),
+            }
+        }
 
-        Ok(response)
+        Ok(())
     }
 
-    async fn open_next_inbox_stream(
+    /// Pull next missing object of push `id`, or deliver it if none is left.
+    async fn next_pull(
         &mut self,
-        mns: MNSAccount,
-        message: SyncMessage,
-    ) -> anyhow::Result<SyncMessage> {
-        let ack = self.storage.open_next_inbox_stream(mns).await?;
+        mns: &MNSAccount,
+        id: u64,
+        mut push: PendingPush,
+    ) -> anyhow::Result<Option<SyncMessage>> {
+        if let Some(cid) = push.queue.pop() {
+            push.waiting = cid;
 
-        let mut response = SyncMessage::new();
+            self.pending.insert(id, push);
 
-        response.id = message.id;
+            return Ok(Some(SyncMessage::PullMultipart(id, cid)));
+        }
 
-        response.type_ = Type::OpenNextInboxStreamAck.into();
+        let (to, root) = (push.to.uns.id, push.root);
 
-        response.set_open_next_inbox_stream_ack(ack);
+        self.deliver(mns, push).await?;
 
-        Ok(response)
+        Ok(Some(SyncMessage::Push(id, to, root)))
     }
 
-    async fn close_inbox_stream(
-        &mut self,
-        _mns: MNSAccount,
-        message: SyncMessage,
-    ) -> anyhow::Result<SyncMessage> {
-        if !message.has_close_inbox_stream() {
-            return Err(DismpError::SyncMessageContent("CloseInboxStream".to_owned()).into());
-        }
+    /// Write mime objects and timeline entry of `push` in one transaction, and index its envelope.
+    ///
+    /// The envelope is indexed first and removed again if the transaction fails, so a message
+    /// is either delivered and searchable or not delivered at all.
+    async fn deliver(&mut self, mns: &MNSAccount, push: PendingPush) -> anyhow::Result<()> {
+        let to = push.to.uns.id;
 
-        let ack = self
-            .storage
-            .close_inbox_stream(message.close_inbox_stream().clone())
-            .await?;
+        let envelope = Envelope {
+            cid: push.root,
+            sender: mns.uns.id,
+            received: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            size: push.size,
+            thread_id: None,
+            flags: 0,
+        };
 
-        let mut response = SyncMessage::new();
+        self.timeline.index(to, envelope).await?;
 
-        response.id = message.id;
+        let mut txn = push.txn;
 
-        response.type_ = Type::CloseInboxStreamAck.into();
+        txn.append(push.to, push.root);
 
-        response.set_close_inbox_stream_ack(ack);
+        if let Err(err) = txn.commit(&self.kv, &self.timeline).await {
+            if let Err(err) = self.timeline.remove(to, push.root).await {
+                log::error!("Remove envelope({}) failed, {}", push.root, err);
+            }
 
-        Ok(response)
-    }
+            return Err(err);
+        }
 
-    async fn read_fragment(
-        &mut self,
-        _mns: MNSAccount,
-        message: SyncMessage,
-    ) -> anyhow::Result<SyncMessage> {
-        if !message.has_read_fragment() {
-            return Err(DismpError::SyncMessageContent("ReadFragment".to_owned()).into());
+        if let Some(used) = self.used.get_mut(&to) {
+            *used += push.size;
         }
 
-        let ack = self
-            .storage
-            .read_fragment(message.read_fragment().clone())
-            .await?;
+        log::debug!(
+            "UNS({}) message({}) delivered to UNS({})",
+            mns.uns.id,
+            push.root,
+            to
+        );
 
-        let mut response = SyncMessage::new();
+        Ok(())
+    }
 
-        response.id = message.id;
+    /// Pull a message of the `mns` inbox, or a multipart child of an object pulled before.
+    async fn pull(&mut self, mns: &MNSAccount, id: u64, cid: Cid) -> anyhow::Result<SyncMessage> {
+        // unreachable objects are reported missing, so their existence doesn't leak.
+        if !self.readable.contains(&cid) && self.timeline.envelope(mns.uns.id, cid).await?.is_none()
+        {
+            return Err(DismpError::NotFound(cid).into());
+        }
 
-        response.type_ = Type::ReadFragmentAck.into();
+        let mime = self.kv.get(cid).await?.ok_or(DismpError::NotFound(cid))?;
 
-        response.set_read_fragment_ack(ack);
+        self.readable.extend(mime.multipart.iter().copied());
 
-        Ok(response)
+        Ok(SyncMessage::PullMultipartContent(id, mime))
     }
 
-    async fn write_fragment(
+    async fn search(
         &mut self,
-        _mns: MNSAccount,
-        message: SyncMessage,
+        mns: &MNSAccount,
+        id: u64,
+        query: EnvelopeQuery,
     ) -> anyhow::Result<SyncMessage> {
-        if !message.has_write_fragment() {
-            return Err(DismpError::SyncMessageContent("WriteFrament".to_owned()).into());
-        }
+        let envelopes = self.timeline.search(mns.uns.id, query).await?;
 
-        let ack = self
-            .storage
-            .write_fragment(message.write_fragment().clone())
-            .await?;
-
-        let mut response = SyncMessage::new();
-
-        response.id = message.id;
-
-        response.type_ = Type::WriteFragmentAck.into();
+        Ok(SyncMessage::SearchResult(id, envelopes))
+    }
 
-        response.set_write_fragment_ack(ack);
+    /// Replace message flags, acknowledged by echoing the request.
+    async fn set_flags(
+        &mut self,
+        mns: &MNSAccount,
+        id: u64,
+        cid: Cid,
+        flags: u32,
+    ) -> anyhow::Result<SyncMessage> {
+        if !self.timeline.set_flags(mns.uns.id, cid, flags).await? {
+            return Err(DismpError::NotFound(cid).into());
+        }
 
-        Ok(response)
+        Ok(SyncMessage::SetFlags(id, cid, flags))
     }
 }
 
 #[cfg(all(test, feature = "mock"))]
 mod tests {
-    use std::time::Duration;
+    use std::collections::HashMap;
 
     use dimsp_gateway::mock::MockGateway;
     use dimsp_spnetwork::mock::MockSpNetwork;
-    use dimsp_storage::mock::MockStorage;
-    use dimsp_types::MNSAccount;
+    use dimsp_storage::{
+        codec::MimeOptions, leveldb_kv::LeveldbMimeKV, leveldb_timeline::LeveldbTimeline,
+        timeline::Timeline,
+    };
+    use dimsp_types::{EnvelopeQuery, MNSAccount, Mime, SyncMessage, ENVELOPE_SEEN};
+    use libipld::Cid;
+
+    use super::{DimspHub, DimspHubConfig};
+
+    fn mime(content: &[u8], multipart: Vec<Cid>) -> (Cid, Mime) {
+        let mime = Mime {
+            id: Cid::default(),
+            length: content.len() as u64,
+            content: content.to_vec(),
+            multipart,
+        };
+
+        (MimeOptions::default().encode(&mime).unwrap().0, mime)
+    }
 
-    use super::DimspHub;
+    fn encoded_len(mime: &Mime) -> u64 {
+        MimeOptions::default().encode(mime).unwrap().1.len() as u64
+    }
+
+    fn account(id: u64) -> MNSAccount {
+        let mut account = MNSAccount::default();
+
+        account.uns.id = id;
+
+        account
+    }
 
     #[async_std::test]
     async fn test_send_message() {
@@ -385,23 +616,163 @@ mod tests {
 
         let (gateway, mut client) = MockGateway::new();
 
-        let storage = MockStorage::default();
+        let timeline = LeveldbTimeline::memory().unwrap();
+
+        let mut network = MockSpNetwork::default();
 
-        let network = MockSpNetwork::default();
+        network.add_mns(account(200));
 
-        let hub = DimspHub::new(gateway, network, storage);
+        let hub = DimspHub::new(
+            gateway,
+            network,
+            LeveldbMimeKV::memory().unwrap(),
+            timeline.clone(),
+        );
 
         hub.start().unwrap();
 
-        // Create mock account
-        let mut account = MNSAccount::default();
+        let mut sender = client.connect_with(account(100)).await.unwrap();
+
+        sender.ping().await.unwrap();
+
+        let (part_cid, part) = mime(b"world", vec![]);
+        let (root_cid, root) = mime(b"Hello ", vec![part_cid]);
+
+        let size = encoded_len(&root) + encoded_len(&part);
+
+        let objects = HashMap::from([(root_cid, root), (part_cid, part)]);
+
+        sender.send_message(200, root_cid, &objects).await.unwrap();
+
+        // delivered messages are not pulled again.
+        sender
+            .send_message(200, root_cid, &HashMap::new())
+            .await
+            .unwrap();
+
+        assert!(sender.send_message(300, root_cid, &objects).await.is_err());
+
+        // unauthenticated connections only get heartbeats answered.
+        let mut anonymous = client.connect_with(MNSAccount::anonymous()).await.unwrap();
+
+        anonymous.ping().await.unwrap();
 
-        account.uns.id = 100;
-        account.quota = 1024 * 1024 * 4;
-        account.lease = Duration::from_secs(10);
+        assert!(anonymous
+            .send_message(200, root_cid, &objects)
+            .await
+            .is_err());
+
+        assert_eq!(timeline.length(account(200)).await.unwrap(), 1);
+        assert_eq!(timeline.length(account(100)).await.unwrap(), 0);
+
+        // the sender can't read the recipient inbox.
+        assert!(sender.pull(root_cid).await.is_err());
+
+        let mut recipient = client.connect_with(account(200)).await.unwrap();
+
+        // multipart children are readable through their message only.
+        assert!(recipient.pull(part_cid).await.is_err());
+
+        assert_eq!(recipient.pull(root_cid).await.unwrap().content, b"Hello ");
+        assert_eq!(recipient.pull(part_cid).await.unwrap().content, b"world");
+
+        let unread = EnvelopeQuery {
+            flags_clear: ENVELOPE_SEEN,
+            ..Default::default()
+        };
+
+        let envelopes = recipient.search(unread.clone()).await.unwrap();
+
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].cid, root_cid);
+        assert_eq!(envelopes[0].sender, 100);
+        assert_eq!(envelopes[0].size, size);
+
+        let from_sender = EnvelopeQuery {
+            sender: Some(100),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            recipient.search(from_sender.clone()).await.unwrap().len(),
+            1
+        );
+        assert!(sender.search(from_sender).await.unwrap().is_empty());
+
+        recipient.set_flags(root_cid, ENVELOPE_SEEN).await.unwrap();
+
+        assert!(recipient.search(unread).await.unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_push_limits() {
+        _ = pretty_env_logger::try_init();
+
+        let (gateway, mut client) = MockGateway::new();
+
+        let mut account = account(100);
+
+        account.quota = 64;
+
+        let mut network = MockSpNetwork::default();
+
+        network.add_mns(account.clone());
+
+        let hub = DimspHub::new(
+            gateway,
+            network,
+            LeveldbMimeKV::memory().unwrap(),
+            LeveldbTimeline::memory().unwrap(),
+        )
+        .with_config(DimspHubConfig {
+            max_pending_pushes: 1,
+            ..Default::default()
+        });
+
+        hub.start().unwrap();
 
         let mut session = client.connect_with(account).await.unwrap();
 
-        session.send_message(20, "Hello world", 2).await.unwrap();
+        // content of an unknown push is answered with an error, the session stays open.
+        let (small_cid, small) = mime(b"small", vec![]);
+
+        let err = session
+            .request(SyncMessage::PullMultipartContent(1000, small.clone()))
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().starts_with("UnknownPush"));
+
+        session.ping().await.unwrap();
+
+        let (large_cid, large) = mime(&[0u8; 100], vec![]);
+
+        let err = session
+            .send_message(100, large_cid, &HashMap::from([(large_cid, large)]))
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().starts_with("Quota"));
+
+        let reply = session
+            .request(SyncMessage::Push(1001, 100, small_cid))
+            .await
+            .unwrap();
+
+        assert!(matches!(reply, SyncMessage::PullMultipart(1001, _)));
+
+        let err = session
+            .request(SyncMessage::Push(1002, 100, large_cid))
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().starts_with("PushLimit"));
+
+        let reply = session
+            .request(SyncMessage::PullMultipartContent(1001, small))
+            .await
+            .unwrap();
+
+        assert!(matches!(reply, SyncMessage::Push(1001, 100, cid) if cid == small_cid));
     }
 }
@@ -52,4 +52,16 @@ impl SpNetwork for MockSpNetwork {
 
         Ok(inner.subscribed_by.get(&mns_id).map(|c| c.clone()))
     }
+
+    async fn mns_by_pubkey(&self, pub_key: &PublicKey) -> anyhow::Result<Option<MNSAccount>> {
+        let inner = self.inner.lock().unwrap();
+
+        Ok(inner.mns_by_pubkey.get(pub_key).cloned())
+    }
+
+    async fn mns_by_id(&self, mns_id: u64) -> anyhow::Result<Option<MNSAccount>> {
+        let inner = self.inner.lock().unwrap();
+
+        Ok(inner.mns_by_id.get(&mns_id).cloned())
+    }
 }
@@ -1,5 +1,5 @@
 use async_trait::async_trait;
-use dimsp_types::{PublicKey, SPRSAccount};
+use dimsp_types::{MNSAccount, PublicKey, SPRSAccount};
 use thiserror::Error;
 
 /// Use this trait to fetch **sp network** information.
@@ -9,6 +9,16 @@ pub trait SpNetwork {
     ///
     /// Returns [`SPRSAccount`] list.
     async fn subscription(&self, mns_id: u64) -> anyhow::Result<Option<Vec<SPRSAccount>>>;
+
+    /// Get [`MNSAccount`] bound to `pub_key`
+    ///
+    /// Returns [`None`] if no account is bound to the key.
+    async fn mns_by_pubkey(&self, pub_key: &PublicKey) -> anyhow::Result<Option<MNSAccount>>;
+
+    /// Get [`MNSAccount`] of `mns_id`
+    ///
+    /// Returns [`None`] if the account doesn't exist.
+    async fn mns_by_id(&self, mns_id: u64) -> anyhow::Result<Option<MNSAccount>>;
 }
 
 #[derive(Debug, Error)]
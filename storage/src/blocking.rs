@@ -0,0 +1,57 @@
+use anyhow::Result;
+use futures::{channel::oneshot, executor::ThreadPool, task::SpawnExt, Future};
+use once_cell::sync::OnceCell;
+
+fn thread_pool() -> Result<&'static ThreadPool> {
+    static THREAD_POOL: OnceCell<ThreadPool> = OnceCell::new();
+
+    Ok(THREAD_POOL
+        .get_or_try_init(|| ThreadPool::builder().name_prefix("dimsp-storage-").create())?)
+}
+
+/// Run blocking storage function `f` on the dedicated storage thread pool.
+///
+/// The caller's executor is never blocked, it only waits on a oneshot channel for the result.
+pub async fn unblock<F, R>(f: F) -> Result<R>
+where
+    F: FnOnce() -> Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+
+    thread_pool()?.spawn(async move {
+        _ = sender.send(f());
+    })?;
+
+    receiver.await?
+}
+
+/// Run detached storage task `fut` on the storage thread pool.
+pub(crate) fn spawn<F>(fut: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    Ok(thread_pool()?.spawn(fut)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::unblock;
+
+    #[async_std::test]
+    async fn test_unblock() {
+        let caller = thread::current().id();
+
+        let worker = unblock(|| Ok(thread::current().id())).await.unwrap();
+
+        assert_ne!(caller, worker);
+
+        let err = unblock::<_, ()>(|| Err(anyhow::format_err!("disk error")))
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.to_string(), "disk error");
+    }
+}
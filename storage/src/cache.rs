@@ -0,0 +1,306 @@
+//! In-memory read cache in front of a [`MimeKV`] backend.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dimsp_types::Mime;
+use libipld::Cid;
+use lru::LruCache;
+use serde::Serialize;
+
+use crate::kv::MimeKV;
+
+/// Cache hit/miss counters and current usage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+struct MimeLru {
+    mimes: LruCache<Cid, Mime>,
+    bytes: u64,
+    capacity: u64,
+    /// Bumped after every backend change, a read started before it must not fill the cache.
+    epoch: u64,
+}
+
+impl MimeLru {
+    fn insert(&mut self, epoch: u64, cid: Cid, mime: Mime) {
+        let size = mime_size(&mime);
+
+        if epoch != self.epoch || size > self.capacity {
+            return;
+        }
+
+        if let Some(old) = self.mimes.put(cid, mime) {
+            self.bytes -= mime_size(&old);
+        }
+
+        self.bytes += size;
+
+        while self.bytes > self.capacity {
+            match self.mimes.pop_lru() {
+                Some((_, evicted)) => self.bytes -= mime_size(&evicted),
+                None => break,
+            }
+        }
+    }
+
+    fn invalidate(&mut self, cid: &Cid) {
+        self.epoch += 1;
+
+        if let Some(old) = self.mimes.pop(cid) {
+            self.bytes -= mime_size(&old);
+        }
+    }
+}
+
+/// Approximate heap size of decoded `mime`.
+fn mime_size(mime: &Mime) -> u64 {
+    (std::mem::size_of::<Mime>()
+        + mime.content.len()
+        + mime.multipart.len() * std::mem::size_of::<Cid>()) as u64
+}
+
+/// [`MimeKV`] wrapper caching decoded mime objects in a LRU bounded by total bytes.
+#[derive(Clone)]
+pub struct CachedMimeKV<K> {
+    inner: K,
+    lru: Arc<Mutex<MimeLru>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl<K> CachedMimeKV<K> {
+    /// Create cache holding at most `capacity` bytes of decoded mime objects.
+    pub fn new(inner: K, capacity: u64) -> Self {
+        Self {
+            inner,
+            lru: Arc::new(Mutex::new(MimeLru {
+                mimes: LruCache::unbounded(),
+                bytes: 0,
+                capacity,
+                epoch: 0,
+            })),
+            hits: Default::default(),
+            misses: Default::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.lru.lock().unwrap();
+
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: lru.mimes.len() as u64,
+            bytes: lru.bytes,
+        }
+    }
+
+    /// Returns cached mime and current epoch, counting the lookup.
+    fn lookup(&self, cid: &Cid) -> (Option<Mime>, u64) {
+        let mut lru = self.lru.lock().unwrap();
+
+        let mime = lru.mimes.get(cid).cloned();
+
+        if mime.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        (mime, lru.epoch)
+    }
+
+    fn invalidate(&self, cid: &Cid) {
+        self.lru.lock().unwrap().invalidate(cid);
+    }
+}
+
+#[async_trait]
+impl<K> MimeKV for CachedMimeKV<K>
+where
+    K: MimeKV + Send + Sync,
+{
+    async fn put(&self, mime: Mime) -> Result<Cid> {
+        self.inner.put(mime).await
+    }
+
+    async fn contains_cid(&self, cid: Cid) -> Result<bool> {
+        if self.lru.lock().unwrap().mimes.contains(&cid) {
+            return Ok(true);
+        }
+
+        self.inner.contains_cid(cid).await
+    }
+
+    async fn get(&self, cid: Cid) -> Result<Option<Mime>> {
+        let (cached, epoch) = self.lookup(&cid);
+
+        if cached.is_some() {
+            return Ok(cached);
+        }
+
+        let mime = self.inner.get(cid).await?;
+
+        if let Some(mime) = &mime {
+            self.lru.lock().unwrap().insert(epoch, cid, mime.clone());
+        }
+
+        Ok(mime)
+    }
+
+    async fn delete(&self, cid: Cid) -> Result<Option<Mime>> {
+        let removed = self.inner.delete(cid).await?;
+
+        self.invalidate(&cid);
+
+        Ok(removed)
+    }
+
+    async fn cids(&self) -> Result<Vec<Cid>> {
+        self.inner.cids().await
+    }
+
+    async fn get_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        self.inner.get_raw(cid).await
+    }
+
+    async fn put_raw(&self, cid: Cid, data: Vec<u8>) -> Result<()> {
+        self.inner.put_raw(cid, data).await?;
+
+        // drop whatever was decoded before.
+        self.invalidate(&cid);
+
+        Ok(())
+    }
+
+    async fn put_raw_many(&self, objects: Vec<(Cid, Vec<u8>)>) -> Result<()> {
+        let cids = objects.iter().map(|(cid, _)| *cid).collect::<Vec<_>>();
+
+        let result = self.inner.put_raw_many(objects).await;
+
+        // a failed batch may still be partly written.
+        for cid in &cids {
+            self.invalidate(cid);
+        }
+
+        result
+    }
+
+    async fn put_unchecked(&self, key: Cid, data: Vec<u8>) -> Result<()> {
+        self.inner.put_unchecked(key, data).await?;
+
+        self.invalidate(&key);
+
+        Ok(())
+    }
+
+    async fn delete_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        let removed = self.inner.delete_raw(cid).await?;
+
+        self.invalidate(&cid);
+
+        Ok(removed)
+    }
+
+    async fn put_many(&self, mimes: Vec<Mime>) -> Result<Vec<Cid>> {
+        self.inner.put_many(mimes).await
+    }
+
+    async fn get_many(&self, cids: Vec<Cid>) -> Result<Vec<Option<Mime>>> {
+        let mut mimes = vec![];
+        let mut missed = vec![];
+        let mut epoch = 0;
+
+        for cid in &cids {
+            let (cached, current) = self.lookup(cid);
+
+            if cached.is_none() {
+                missed.push(*cid);
+            }
+
+            epoch = current;
+            mimes.push(cached);
+        }
+
+        if missed.is_empty() {
+            return Ok(mimes);
+        }
+
+        let mut loaded = self.inner.get_many(missed).await?.into_iter();
+
+        let mut lru = self.lru.lock().unwrap();
+
+        for (cid, mime) in cids.iter().zip(mimes.iter_mut()) {
+            if mime.is_some() {
+                continue;
+            }
+
+            *mime = loaded.next().flatten();
+
+            if let Some(mime) = mime {
+                lru.insert(epoch, *cid, mime.clone());
+            }
+        }
+
+        Ok(mimes)
+    }
+
+    async fn contains_many(&self, cids: Vec<Cid>) -> Result<Vec<bool>> {
+        self.inner.contains_many(cids).await
+    }
+}
+
+#[cfg(all(test, feature = "leveldb_kv"))]
+mod tests {
+    use crate::{conformance::mime, kv::MimeKV, leveldb_kv::LeveldbMimeKV};
+
+    use super::{mime_size, CachedMimeKV};
+
+    #[async_std::test]
+    async fn test_cache() {
+        // room for two mime objects.
+        let kv = CachedMimeKV::new(LeveldbMimeKV::memory().unwrap(), mime_size(&mime(0)) * 2);
+
+        let a = kv.put(mime(1)).await.unwrap();
+        let b = kv.put(mime(2)).await.unwrap();
+        let c = kv.put(mime(3)).await.unwrap();
+
+        kv.get(a).await.unwrap();
+        kv.get(a).await.unwrap();
+
+        let stats = kv.stats();
+
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+        kv.get_many(vec![a, b]).await.unwrap();
+        kv.get(a).await.unwrap();
+
+        // c evicts least recently used b, a stays cached.
+        kv.get(c).await.unwrap();
+
+        assert_eq!(kv.stats().entries, 2);
+
+        kv.get(a).await.unwrap();
+        kv.get(b).await.unwrap();
+
+        let stats = kv.stats();
+
+        assert_eq!((stats.hits, stats.misses), (4, 4));
+
+        // delete drops the cached copy.
+        kv.delete(a).await.unwrap();
+
+        assert!(kv.get(a).await.unwrap().is_none());
+        assert!(!kv.contains_cid(a).await.unwrap());
+    }
+}
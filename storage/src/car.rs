@@ -0,0 +1,641 @@
+//! CAR archive export and import of one account's mailbox.
+//!
+//! The archive root is a mailbox manifest block holding the timeline entries and client cursors,
+//! followed by every mime object reachable from the entries, multipart children included.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::Cursor,
+    pin::Pin,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use dimsp_types::{Envelope, MNSAccount};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libipld::{
+    cbor::DagCborCodec,
+    multihash::{Code, MultihashDigest},
+    prelude::Codec,
+    Cid, DagCbor,
+};
+
+use crate::{
+    codec, envelope::EnvelopeIndex, error::StorageError, kv::MimeKV, timeline::Timeline,
+    txn::Transaction,
+};
+
+/// CARv2 pragma, varint length prefixed dag-cbor `{"version": 2}`.
+const CARV2_PRAGMA: [u8; 11] = [
+    0x0a, 0xa1, 0x67, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x02,
+];
+
+/// CARv2 header length: characteristics(16) + data offset(8) + data size(8) + index offset(8).
+const CARV2_HEADER_LEN: usize = 40;
+
+/// Largest accepted archive section, bounds the memory of one block during import.
+const MAX_SECTION_LEN: u64 = 64 * 1024 * 1024;
+
+/// Exported archive format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CarVersion {
+    V1,
+    /// CARv2 wrapper around CARv1 payload, without index.
+    V2,
+}
+
+#[derive(Debug, Clone, DagCbor)]
+struct CarHeader {
+    roots: Vec<Cid>,
+    version: u64,
+}
+
+/// Archive root block.
+#[derive(Debug, Clone, DagCbor)]
+struct Mailbox {
+    /// Exported account id.
+    mns_id: u64,
+    /// Source timeline offset of the first entry.
+    start: u64,
+    entries: Vec<Cid>,
+    cursors: Vec<MailboxCursor>,
+    /// Indexed envelopes of the entries, entries without one are reindexed on import.
+    envelopes: Vec<Envelope>,
+}
+
+#[derive(Debug, Clone, DagCbor)]
+struct MailboxCursor {
+    client_id: Cid,
+    /// Source timeline offset.
+    offset: u64,
+}
+
+/// Number of timeline entries and mime objects moved by [`export`] or [`import`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CarSummary {
+    pub entries: u64,
+    pub mimes: u64,
+}
+
+/// Write `mns` timeline, cursors, envelopes and all reachable mime objects into `writer` as CAR archive.
+///
+/// Mime objects are read twice, once to size the archive and once to stream them to `writer`,
+/// so only their cids are held in memory.
+pub async fn export<K, T, W>(
+    kv: &K,
+    timeline: &T,
+    mns: &MNSAccount,
+    version: CarVersion,
+    mut writer: W,
+) -> Result<CarSummary>
+where
+    K: MimeKV + Sync,
+    T: Timeline + EnvelopeIndex + Sync,
+    W: AsyncWrite + Unpin + Send,
+{
+    let mns_id = mns.uns.id;
+
+    let entries = timeline.entries(mns_id).await?;
+
+    let mut envelopes = vec![];
+
+    for (_, cid) in &entries {
+        if let Some(envelope) = timeline.envelope(mns_id, *cid).await? {
+            envelopes.push(envelope);
+        }
+    }
+
+    let mailbox = Mailbox {
+        mns_id,
+        start: entries.first().map(|(offset, _)| *offset).unwrap_or(0),
+        entries: entries.into_iter().map(|(_, cid)| cid).collect(),
+        cursors: timeline
+            .cursors(mns_id)
+            .await?
+            .into_iter()
+            .map(|(client_id, offset)| MailboxCursor { client_id, offset })
+            .collect(),
+        envelopes,
+    };
+
+    let root_data = DagCborCodec.encode(&mailbox)?;
+    let root = Cid::new_v1(DagCborCodec.into(), Code::Keccak256.digest(&root_data));
+
+    let header = DagCborCodec.encode(&CarHeader {
+        roots: vec![root],
+        version: 1,
+    })?;
+
+    let mut blocks = vec![];
+    let mut visited = HashSet::new();
+    let mut pending = mailbox.entries.iter().copied().collect::<VecDeque<_>>();
+
+    let mut payload_len = frame_len(header.len()) + section_len(&root, root_data.len());
+
+    while let Some(cid) = pending.pop_front() {
+        if !visited.insert(cid) {
+            continue;
+        }
+
+        let data = kv
+            .get_raw(cid)
+            .await?
+            .ok_or(StorageError::MimeNotFound(cid))?;
+
+        pending.extend(codec::decode(&cid, &data)?.multipart);
+
+        payload_len += section_len(&cid, data.len());
+
+        blocks.push(cid);
+    }
+
+    if version == CarVersion::V2 {
+        let mut header = [0u8; CARV2_HEADER_LEN];
+
+        let data_offset = (CARV2_PRAGMA.len() + CARV2_HEADER_LEN) as u64;
+
+        header[16..24].copy_from_slice(&data_offset.to_le_bytes());
+        header[24..32].copy_from_slice(&payload_len.to_le_bytes());
+
+        writer.write_all(&CARV2_PRAGMA).await?;
+        writer.write_all(&header).await?;
+    }
+
+    let mut frame = vec![];
+
+    write_varint(&mut frame, header.len() as u64);
+    frame.extend_from_slice(&header);
+
+    writer.write_all(&frame).await?;
+
+    write_section(&mut writer, &root, &root_data).await?;
+
+    for cid in &blocks {
+        let data = kv
+            .get_raw(*cid)
+            .await?
+            .ok_or(StorageError::MimeNotFound(*cid))?;
+
+        write_section(&mut writer, cid, &data).await?;
+    }
+
+    writer.flush().await?;
+
+    Ok(CarSummary {
+        entries: mailbox.entries.len() as u64,
+        mimes: blocks.len() as u64,
+    })
+}
+
+/// Read CARv1/CARv2 archive created by [`export`] and append it to `mns` mailbox.
+///
+/// Mime objects and timeline entries are staged in one [`Transaction`] committed after the last
+/// block, so a broken archive stores nothing. The whole archive is held in memory until then.
+///
+/// Imported entries are indexed with their archived envelope, or with a new one sized from the
+/// archived objects and an unknown (0) sender if the source had none.
+///
+/// Client cursors are moved to the matching imported entries, so the target account should not
+/// receive deliveries meanwhile.
+pub async fn import<K, T, R>(
+    kv: &K,
+    timeline: &T,
+    mns: &MNSAccount,
+    reader: R,
+) -> Result<CarSummary>
+where
+    K: MimeKV + Sync,
+    T: Timeline + EnvelopeIndex + Sync,
+    R: AsyncRead + Unpin + Send,
+{
+    let mut reader = carv1_payload(reader).await?;
+
+    let header: CarHeader = DagCborCodec.decode(
+        &read_frame(&mut reader)
+            .await?
+            .ok_or_else(|| car_format("missing header"))?,
+    )?;
+
+    if header.version != 1 {
+        return Err(car_format(format!("unsupported version({})", header.version)).into());
+    }
+
+    let root = match header.roots.as_slice() {
+        [root] => *root,
+        roots => return Err(car_format(format!("expect one root, got {}", roots.len())).into()),
+    };
+
+    // export writes the root block first, so the mailbox is known before any object is stored.
+    let (cid, root_data) = read_section(&mut reader)
+        .await?
+        .ok_or_else(|| car_format("missing root block"))?;
+
+    if cid != root {
+        return Err(car_format("root block must be the first block").into());
+    }
+
+    codec::verify(&root, &root_data)?;
+
+    let mailbox: Mailbox = DagCborCodec.decode(&root_data)?;
+
+    log::debug!(
+        "Import mailbox of mns({}) into mns({})",
+        mailbox.mns_id,
+        mns.uns.id
+    );
+
+    let mut txn = Transaction::default();
+
+    // size and multipart children of every archived object, to size entries without envelope.
+    let mut blocks = HashMap::new();
+
+    while let Some((cid, data)) = read_section(&mut reader).await? {
+        let children = codec::decode(&cid, &data)?.multipart;
+
+        blocks.insert(cid, (data.len() as u64, children));
+
+        txn.put_raw(cid, data);
+    }
+
+    let mimes = blocks.len() as u64;
+
+    let unarchived = mailbox
+        .entries
+        .iter()
+        .filter(|cid| !blocks.contains_key(cid))
+        .copied()
+        .collect::<Vec<_>>();
+
+    if let Some(missing) = kv
+        .contains_many(unarchived.clone())
+        .await?
+        .iter()
+        .position(|found| !found)
+    {
+        return Err(StorageError::MimeNotFound(unarchived[missing]).into());
+    }
+
+    let envelopes = import_envelopes(&mailbox, &blocks)?;
+
+    for cid in &mailbox.entries {
+        txn.append(mns.clone(), *cid);
+    }
+
+    for envelope in &envelopes {
+        timeline.index(mns.uns.id, envelope.clone()).await?;
+    }
+
+    // like hub delivery, envelopes go in first and are removed again if the commit fails.
+    if let Err(err) = txn.commit(kv, timeline).await {
+        for envelope in &envelopes {
+            if let Err(err) = timeline.remove(mns.uns.id, envelope.cid).await {
+                log::error!("Remove envelope({}) failed, {}", envelope.cid, err);
+            }
+        }
+
+        return Err(err);
+    }
+
+    let entries = timeline.entries(mns.uns.id).await?;
+
+    let end = entries.last().map(|(offset, _)| offset + 1).unwrap_or(0);
+
+    let imported = &entries[entries.len().saturating_sub(mailbox.entries.len())..];
+
+    for cursor in mailbox.cursors {
+        let consumed = cursor.offset.saturating_sub(mailbox.start) as usize;
+
+        let offset = imported
+            .get(consumed)
+            .map(|(offset, _)| *offset)
+            .unwrap_or(end);
+
+        let mut client = mns.clone();
+
+        client.client_id = cursor.client_id;
+
+        timeline.set_cursor(client, offset).await?;
+    }
+
+    Ok(CarSummary {
+        entries: mailbox.entries.len() as u64,
+        mimes,
+    })
+}
+
+/// Returns one envelope per distinct mailbox entry, archived ones first choice.
+fn import_envelopes(
+    mailbox: &Mailbox,
+    blocks: &HashMap<Cid, (u64, Vec<Cid>)>,
+) -> Result<Vec<Envelope>> {
+    let mut archived = mailbox
+        .envelopes
+        .iter()
+        .map(|envelope| (envelope.cid, envelope.clone()))
+        .collect::<HashMap<_, _>>();
+
+    let received = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let mut envelopes = vec![];
+    let mut seen = HashSet::new();
+
+    for cid in &mailbox.entries {
+        if !seen.insert(*cid) {
+            continue;
+        }
+
+        let envelope = archived.remove(cid).unwrap_or_else(|| Envelope {
+            cid: *cid,
+            received,
+            size: reachable_size(*cid, blocks),
+            ..Default::default()
+        });
+
+        envelopes.push(envelope);
+    }
+
+    Ok(envelopes)
+}
+
+/// Total bytes of the archived objects reachable from `root`.
+fn reachable_size(root: Cid, blocks: &HashMap<Cid, (u64, Vec<Cid>)>) -> u64 {
+    let mut size = 0;
+    let mut visited = HashSet::new();
+    let mut pending = vec![root];
+
+    while let Some(cid) = pending.pop() {
+        if !visited.insert(cid) {
+            continue;
+        }
+
+        if let Some((len, children)) = blocks.get(&cid) {
+            size += len;
+            pending.extend(children);
+        }
+    }
+
+    size
+}
+
+fn car_format<S: Into<String>>(message: S) -> StorageError {
+    StorageError::CarFormat(message.into())
+}
+
+fn varint_len(mut value: u64) -> u64 {
+    let mut len = 1;
+
+    while value >= 0x80 {
+        len += 1;
+        value >>= 7;
+    }
+
+    len
+}
+
+fn frame_len(len: usize) -> u64 {
+    varint_len(len as u64) + len as u64
+}
+
+fn section_len(cid: &Cid, data_len: usize) -> u64 {
+    frame_len(cid.encoded_len() + data_len)
+}
+
+fn write_varint(buff: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buff.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    buff.push(value as u8);
+}
+
+async fn write_section<W>(writer: &mut W, cid: &Cid, data: &[u8]) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let cid_bytes = cid.to_bytes();
+
+    let mut buff = vec![];
+
+    write_varint(&mut buff, (cid_bytes.len() + data.len()) as u64);
+    buff.extend_from_slice(&cid_bytes);
+
+    writer.write_all(&buff).await?;
+    writer.write_all(data).await?;
+
+    Ok(())
+}
+
+/// Read one varint, returns [`None`] on end of stream before its first byte.
+async fn read_varint<R>(reader: &mut R) -> Result<Option<u64>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut value = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8; 1];
+
+        if reader.read(&mut byte).await? == 0 {
+            if shift == 0 {
+                return Ok(None);
+            }
+
+            return Err(car_format("unexpected end of varint").into());
+        }
+
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+
+    Err(car_format("varint overflow").into())
+}
+
+/// Read one varint length prefixed frame, returns [`None`] on end of stream.
+async fn read_frame<R>(reader: &mut R) -> Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(len) = read_varint(reader).await? else {
+        return Ok(None);
+    };
+
+    if len > MAX_SECTION_LEN {
+        return Err(car_format(format!("section of {} bytes is too large", len)).into());
+    }
+
+    let mut frame = vec![0u8; len as usize];
+
+    reader
+        .read_exact(&mut frame)
+        .await
+        .map_err(|_| car_format("unexpected end of section"))?;
+
+    Ok(Some(frame))
+}
+
+async fn read_section<R>(reader: &mut R) -> Result<Option<(Cid, Vec<u8>)>>
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(mut section) = read_frame(reader).await? else {
+        return Ok(None);
+    };
+
+    let mut cursor = Cursor::new(section.as_slice());
+
+    let cid = Cid::read_bytes(&mut cursor)?;
+
+    let data = section.split_off(cursor.position() as usize);
+
+    Ok(Some((cid, data)))
+}
+
+/// Returns the CARv1 payload stream, unwraps CARv2 archive if needed.
+async fn carv1_payload<'a, R>(mut reader: R) -> Result<Pin<Box<dyn AsyncRead + Send + 'a>>>
+where
+    R: AsyncRead + Unpin + Send + 'a,
+{
+    let mut pragma = vec![];
+
+    (&mut reader)
+        .take(CARV2_PRAGMA.len() as u64)
+        .read_to_end(&mut pragma)
+        .await?;
+
+    if pragma != CARV2_PRAGMA {
+        return Ok(Box::pin(futures::io::Cursor::new(pragma).chain(reader)));
+    }
+
+    let mut header = [0u8; CARV2_HEADER_LEN];
+
+    reader
+        .read_exact(&mut header)
+        .await
+        .map_err(|_| car_format("missing CARv2 header"))?;
+
+    let data_offset = u64::from_le_bytes(header[16..24].try_into()?);
+    let data_size = u64::from_le_bytes(header[24..32].try_into()?);
+
+    let skip = data_offset
+        .checked_sub((CARV2_PRAGMA.len() + CARV2_HEADER_LEN) as u64)
+        .ok_or_else(|| car_format("CARv2 data section overlaps header"))?;
+
+    data_offset
+        .checked_add(data_size)
+        .ok_or_else(|| car_format("CARv2 data section out of range"))?;
+
+    futures::io::copy((&mut reader).take(skip), &mut futures::io::sink()).await?;
+
+    Ok(Box::pin(reader.take(data_size)))
+}
+
+#[cfg(all(test, feature = "leveldb_kv", feature = "leveldb_timeline"))]
+mod tests {
+    use dimsp_types::{Envelope, MNSAccount, Mime};
+
+    use crate::{
+        conformance::mime, envelope::EnvelopeIndex, kv::MimeKV, leveldb_kv::LeveldbMimeKV,
+        leveldb_timeline::LeveldbTimeline, timeline::Timeline,
+    };
+
+    use super::{export, import, CarSummary, CarVersion};
+
+    #[async_std::test]
+    async fn test_car() {
+        let kv = LeveldbMimeKV::memory().unwrap();
+        let timeline = LeveldbTimeline::memory().unwrap();
+
+        let mut mns = MNSAccount::default();
+        mns.uns.id = 1;
+
+        let child = kv.put(mime(1)).await.unwrap();
+        let parent = kv
+            .put(Mime {
+                multipart: vec![child],
+                ..mime(2)
+            })
+            .await
+            .unwrap();
+        let other = kv.put(mime(3)).await.unwrap();
+
+        timeline.append(mns.clone(), parent).await.unwrap();
+        timeline.append(mns.clone(), other).await.unwrap();
+        timeline.advance(mns.clone(), 1).await.unwrap();
+
+        let indexed = Envelope {
+            cid: parent,
+            sender: 7,
+            received: 100,
+            size: 42,
+            ..Default::default()
+        };
+
+        timeline.index(1, indexed.clone()).await.unwrap();
+
+        let other_size = kv.get_raw(other).await.unwrap().unwrap().len() as u64;
+
+        for version in [CarVersion::V1, CarVersion::V2] {
+            let mut archive = vec![];
+
+            let summary = export(&kv, &timeline, &mns, version, &mut archive)
+                .await
+                .unwrap();
+
+            assert_eq!(
+                summary,
+                CarSummary {
+                    entries: 2,
+                    mimes: 3
+                }
+            );
+
+            let target_kv = LeveldbMimeKV::memory().unwrap();
+            let target_timeline = LeveldbTimeline::memory().unwrap();
+
+            let summary = import(&target_kv, &target_timeline, &mns, archive.as_slice())
+                .await
+                .unwrap();
+
+            assert_eq!(summary.mimes, 3);
+
+            assert_eq!(
+                target_timeline.get(mns.clone(), 10).await.unwrap(),
+                vec![other]
+            );
+
+            let loaded = target_kv.get(child).await.unwrap().unwrap();
+
+            assert_eq!(loaded.content, vec![1]);
+
+            assert_eq!(
+                target_timeline.envelope(1, parent).await.unwrap(),
+                Some(indexed.clone())
+            );
+
+            // entries without envelope are reindexed from the archived objects.
+            let reindexed = target_timeline.envelope(1, other).await.unwrap().unwrap();
+
+            assert_eq!((reindexed.sender, reindexed.size), (0, other_size));
+
+            // a truncated archive leaves neither entries nor orphan objects behind.
+            let target_kv = LeveldbMimeKV::memory().unwrap();
+            let target_timeline = LeveldbTimeline::memory().unwrap();
+
+            import(
+                &target_kv,
+                &target_timeline,
+                &mns,
+                &archive[..archive.len() - 1],
+            )
+            .await
+            .unwrap_err();
+
+            assert!(target_timeline.entries(1).await.unwrap().is_empty());
+            assert!(target_kv.cids().await.unwrap().is_empty());
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Cid generation and verification of stored mime objects.
+
+use anyhow::Result;
+use dimsp_types::Mime;
+use libipld::{
+    cbor::DagCborCodec,
+    json::DagJsonCodec,
+    multihash::{Code, MultihashDigest},
+    prelude::Codec,
+    Cid, Ipld,
+};
+
+use crate::error::StorageError;
+
+/// Multihash algorithm used to address mime objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MimeHash {
+    #[default]
+    Keccak256,
+    Blake2b256,
+    Sha2_256,
+}
+
+impl From<MimeHash> for Code {
+    fn from(value: MimeHash) -> Self {
+        match value {
+            MimeHash::Keccak256 => Code::Keccak256,
+            MimeHash::Blake2b256 => Code::Blake2b256,
+            MimeHash::Sha2_256 => Code::Sha2_256,
+        }
+    }
+}
+
+/// Ipld codec used to serialize mime objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MimeCodec {
+    #[default]
+    DagCbor,
+    DagJson,
+}
+
+impl From<MimeCodec> for u64 {
+    fn from(value: MimeCodec) -> Self {
+        match value {
+            MimeCodec::DagCbor => DagCborCodec.into(),
+            MimeCodec::DagJson => DagJsonCodec.into(),
+        }
+    }
+}
+
+impl TryFrom<u64> for MimeCodec {
+    type Error = StorageError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        if value == u64::from(DagCborCodec) {
+            Ok(Self::DagCbor)
+        } else if value == u64::from(DagJsonCodec) {
+            Ok(Self::DagJson)
+        } else {
+            Err(StorageError::UnsupportedCodec(value))
+        }
+    }
+}
+
+/// Mime object encoding options of [`MimeKV`](crate::kv::MimeKV) backends.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MimeOptions {
+    /// Multihash used to generate cids of new objects.
+    pub hash: MimeHash,
+    /// Codec used to serialize new objects.
+    pub codec: MimeCodec,
+    /// Recompute and check the cid of every object read from backend.
+    pub verify_on_read: bool,
+}
+
+impl MimeOptions {
+    /// Encode mime object and generate its cid.
+    pub fn encode(&self, mime: &Mime) -> Result<(Cid, Vec<u8>)> {
+        let data = match self.codec {
+            MimeCodec::DagCbor => DagCborCodec.encode(mime)?,
+            MimeCodec::DagJson => {
+                let ipld: Ipld = DagCborCodec.decode(&DagCborCodec.encode(mime)?)?;
+
+                DagJsonCodec.encode(&ipld)?
+            }
+        };
+
+        let cid = Cid::new_v1(self.codec.into(), Code::from(self.hash).digest(&data));
+
+        Ok((cid, data))
+    }
+
+    /// Decode stored `data` of `cid`, checks integrity first if `verify_on_read` is set.
+    pub fn decode(&self, cid: &Cid, data: &[u8]) -> Result<Mime> {
+        if self.verify_on_read {
+            verify(cid, data)?;
+        }
+
+        decode(cid, data)
+    }
+}
+
+/// Encode `mime` with the codec recorded in `cid`.
+pub fn encode(cid: &Cid, mime: &Mime) -> Result<Vec<u8>> {
+    let options = MimeOptions {
+        codec: MimeCodec::try_from(cid.codec())?,
+        ..Default::default()
+    };
+
+    Ok(options.encode(mime)?.1)
+}
+
+/// Decode stored `data` with the codec recorded in `cid`.
+pub fn decode(cid: &Cid, data: &[u8]) -> Result<Mime> {
+    match MimeCodec::try_from(cid.codec())? {
+        MimeCodec::DagCbor => Ok(DagCborCodec.decode(data)?),
+        MimeCodec::DagJson => {
+            let ipld: Ipld = DagJsonCodec.decode(data)?;
+
+            Ok(DagCborCodec.decode(&DagCborCodec.encode(&ipld)?)?)
+        }
+    }
+}
+
+/// Recompute the cid of stored `data` with the multihash recorded in `cid`.
+pub fn verify(cid: &Cid, data: &[u8]) -> Result<()> {
+    let code = cid.hash().code();
+
+    let hasher = Code::try_from(code).map_err(|_| StorageError::UnsupportedMultihash(code))?;
+
+    let actual = Cid::new_v1(cid.codec(), hasher.digest(data));
+
+    if actual.hash() != cid.hash() {
+        return Err(StorageError::Integrity(*cid, actual).into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,520 @@
+//! Conformance suite for [`MimeKV`] and [`Timeline`] implementations.
+//!
+//! Backends prove conformance by invoking [`mime_kv_conformance!`](crate::mime_kv_conformance) and
+//! [`timeline_conformance!`](crate::timeline_conformance) in a test module, with a factory that
+//! opens the backend in a given directory:
+//!
+//! ```ignore
+//! mod leveldb_kv {
+//!     dimsp_storage::mime_kv_conformance!(|dir| LeveldbMimeKV::local(dir).unwrap(), durable);
+//! }
+//! ```
+//!
+//! The `durable` flag adds the reopen checks: writes acknowledged before the backend is dropped,
+//! or before the process aborts, must be readable after opening the same directory again.
+//! In-memory backends leave it out.
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+use dimsp_types::{MNSAccount, Mime};
+use futures::future::join_all;
+use libipld::{
+    cbor::DagCborCodec,
+    multihash::{Code, MultihashDigest},
+    Cid,
+};
+use rand::{rngs::OsRng, RngCore};
+
+use crate::{kv::MimeKV, timeline::Timeline};
+
+pub use futures::executor::block_on;
+
+/// Env var pointing the re-executed crash test at the directory to write.
+const CRASH_DIR: &str = "DIMSP_CONFORMANCE_CRASH_DIR";
+
+/// Empty directory of one conformance test, removed on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Create an empty directory for one conformance test.
+pub fn temp_dir() -> TempDir {
+    let dir = env::temp_dir().join(format!("dimsp-conformance-{:016x}", OsRng.next_u64()));
+
+    std::fs::create_dir_all(&dir).unwrap();
+
+    TempDir(dir)
+}
+
+/// Run `write` against the crash directory and abort, if this process is the re-executed `test`.
+///
+/// Otherwise re-execute `test` on a fresh directory, wait for the abort and return the directory.
+fn crashed(test: &str, write: impl FnOnce(&Path)) -> TempDir {
+    if let Some(dir) = env::var_os(CRASH_DIR) {
+        write(Path::new(&dir));
+
+        process::abort();
+    }
+
+    // `module_path!` starts with the crate name, test names don't.
+    let name = test.split_once("::").map(|(_, name)| name).unwrap_or(test);
+
+    let dir = temp_dir();
+
+    let status = Command::new(env::current_exe().unwrap())
+        .args([name, "--exact", "--test-threads=1", "--nocapture"])
+        .env(CRASH_DIR, dir.path())
+        .status()
+        .unwrap();
+
+    assert!(!status.success(), "crash test {} exited cleanly", name);
+
+    dir
+}
+
+/// Single part mime object fixture, distinct for every `content`.
+pub fn mime(content: u8) -> Mime {
+    Mime {
+        id: Cid::new_v1(DagCborCodec.into(), Code::Blake2b256.digest(&[content])),
+        length: 1,
+        content: vec![content],
+        multipart: vec![],
+    }
+}
+
+fn cid(seed: u8) -> Cid {
+    Cid::new_v1(DagCborCodec.into(), Code::Keccak256.digest(&[seed]))
+}
+
+fn account(id: u64, client: u8) -> MNSAccount {
+    let mut mns = MNSAccount::default();
+
+    mns.uns.id = id;
+    mns.client_id = cid(client);
+
+    mns
+}
+
+/// put, get, contains, cids and delete round trip.
+pub async fn kv_round_trip<K: MimeKV + Sync>(kv: &K) {
+    let cid = kv.put(mime(1)).await.unwrap();
+
+    assert!(kv.contains_cid(cid).await.unwrap());
+    assert_eq!(kv.get(cid).await.unwrap().unwrap().content, vec![1]);
+    assert_eq!(kv.cids().await.unwrap(), vec![cid]);
+
+    // content addressed puts are idempotent.
+    assert_eq!(kv.put(mime(1)).await.unwrap(), cid);
+    assert_eq!(kv.cids().await.unwrap().len(), 1);
+
+    assert_eq!(kv.delete(cid).await.unwrap().unwrap().content, vec![1]);
+
+    assert!(!kv.contains_cid(cid).await.unwrap());
+    assert!(kv.get(cid).await.unwrap().is_none());
+    assert!(kv.delete(cid).await.unwrap().is_none());
+    assert!(kv.cids().await.unwrap().is_empty());
+}
+
+/// Raw bytes are stored and returned as is.
+pub async fn kv_raw_round_trip<K: MimeKV + Sync>(kv: &K) {
+    let cid = kv.put(mime(1)).await.unwrap();
+
+    let data = kv.get_raw(cid).await.unwrap().unwrap();
+
+    assert_eq!(kv.delete_raw(cid).await.unwrap(), Some(data.clone()));
+    assert!(kv.get_raw(cid).await.unwrap().is_none());
+
+    kv.put_raw(cid, data.clone()).await.unwrap();
+
+    assert_eq!(kv.get_raw(cid).await.unwrap(), Some(data));
+    assert_eq!(kv.get(cid).await.unwrap().unwrap().content, vec![1]);
+}
+
+/// Batch calls keep one result per input, in input order.
+pub async fn kv_batch<K: MimeKV + Sync>(kv: &K) {
+    let cids = kv.put_many(vec![mime(1), mime(2)]).await.unwrap();
+
+    assert_eq!(cids.len(), 2);
+
+    let missing = cid(0);
+
+    let mimes = kv.get_many(vec![cids[1], missing, cids[0]]).await.unwrap();
+
+    assert_eq!(mimes[0].as_ref().unwrap().content, vec![2]);
+    assert!(mimes[1].is_none());
+    assert_eq!(mimes[2].as_ref().unwrap().content, vec![1]);
+
+    assert_eq!(
+        kv.contains_many(vec![missing, cids[0], cids[1]])
+            .await
+            .unwrap(),
+        vec![false, true, true]
+    );
+}
+
+/// Concurrent puts through one shared handle are all stored.
+pub async fn kv_concurrency<K: MimeKV + Sync>(kv: &K) {
+    let cids = join_all((0..32u8).map(|i| kv.put(mime(i % 16))))
+        .await
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()
+        .unwrap();
+
+    let mut stored = kv.cids().await.unwrap();
+
+    stored.sort();
+
+    let mut expected = cids;
+
+    expected.sort();
+    expected.dedup();
+
+    assert_eq!(stored, expected);
+}
+
+/// Acknowledged puts and deletes survive reopen.
+pub fn kv_durable<K, F>(open: F, dir: &Path)
+where
+    K: MimeKV + Sync,
+    F: Fn(&Path) -> K,
+{
+    let (kept, deleted) = block_on(async {
+        let kv = open(dir);
+
+        let kept = kv.put(mime(1)).await.unwrap();
+        let deleted = kv.put(mime(2)).await.unwrap();
+
+        kv.delete(deleted).await.unwrap();
+
+        (kept, deleted)
+    });
+
+    block_on(async {
+        let kv = open(dir);
+
+        assert_eq!(kv.get(kept).await.unwrap().unwrap().content, vec![1]);
+        assert!(!kv.contains_cid(deleted).await.unwrap());
+    });
+}
+
+/// Acknowledged puts and deletes survive a process abort.
+///
+/// `test` is the full path of the calling test, it is re-executed to write and abort.
+pub fn kv_crash<K, F>(open: F, test: &str)
+where
+    K: MimeKV + Sync,
+    F: Fn(&Path) -> K,
+{
+    let dir = crashed(test, |dir| {
+        block_on(async {
+            let kv = open(dir);
+
+            kv.put(mime(1)).await.unwrap();
+
+            let deleted = kv.put(mime(2)).await.unwrap();
+
+            kv.delete(deleted).await.unwrap();
+        })
+    });
+
+    block_on(async {
+        let kv = open(dir.path());
+
+        let cids = kv.cids().await.unwrap();
+
+        assert_eq!(cids.len(), 1);
+        assert_eq!(kv.get(cids[0]).await.unwrap().unwrap().content, vec![1]);
+    });
+}
+
+/// Entries are returned in append order, limited by `first_n`.
+pub async fn timeline_append_get<T: Timeline + Sync>(timeline: &T) {
+    let mns = account(1, 1);
+
+    assert!(timeline.get(mns.clone(), 10).await.unwrap().is_empty());
+    assert_eq!(timeline.length(mns.clone()).await.unwrap(), 0);
+
+    for seed in 1..=3 {
+        timeline.append(mns.clone(), cid(seed)).await.unwrap();
+    }
+
+    assert_eq!(
+        timeline.get(mns.clone(), 2).await.unwrap(),
+        vec![cid(1), cid(2)]
+    );
+    assert_eq!(timeline.get(mns.clone(), 10).await.unwrap().len(), 3);
+    assert_eq!(timeline.length(mns.clone()).await.unwrap(), 3);
+
+    timeline
+        .append_many(vec![(mns.clone(), cid(4)), (account(2, 1), cid(5))])
+        .await
+        .unwrap();
+
+    assert_eq!(timeline.length(mns).await.unwrap(), 4);
+    assert_eq!(timeline.get(account(2, 1), 10).await.unwrap(), vec![cid(5)]);
+
+    let mut accounts = timeline.accounts().await.unwrap();
+
+    accounts.sort();
+
+    assert_eq!(accounts, vec![1, 2]);
+}
+
+/// `advance` and `set_cursor` clamp the cursor to the retained entries.
+pub async fn timeline_cursor_clamp<T: Timeline + Sync>(timeline: &T) {
+    let mns = account(1, 1);
+
+    for seed in 1..=3 {
+        timeline.append(mns.clone(), cid(seed)).await.unwrap();
+    }
+
+    assert_eq!(timeline.advance(mns.clone(), 1).await.unwrap(), 2);
+    assert_eq!(
+        timeline.get(mns.clone(), 10).await.unwrap(),
+        vec![cid(2), cid(3)]
+    );
+
+    // past the end stops at the end.
+    assert_eq!(timeline.advance(mns.clone(), 100).await.unwrap(), 0);
+    assert!(timeline.get(mns.clone(), 10).await.unwrap().is_empty());
+
+    // new entries show up after a cursor parked at the end.
+    timeline.append(mns.clone(), cid(4)).await.unwrap();
+
+    assert_eq!(timeline.get(mns.clone(), 10).await.unwrap(), vec![cid(4)]);
+
+    timeline.set_cursor(mns.clone(), 100).await.unwrap();
+
+    assert_eq!(timeline.length(mns.clone()).await.unwrap(), 0);
+
+    timeline.set_cursor(mns.clone(), 1).await.unwrap();
+
+    assert_eq!(timeline.length(mns.clone()).await.unwrap(), 3);
+
+    let entries = timeline.entries(1).await.unwrap();
+
+    assert_eq!(
+        entries.iter().map(|(_, cid)| *cid).collect::<Vec<_>>(),
+        (1..=4).map(cid).collect::<Vec<_>>()
+    );
+}
+
+/// Every client of one account has its own cursor.
+pub async fn timeline_multi_client<T: Timeline + Sync>(timeline: &T) {
+    let phone = account(1, 1);
+    let laptop = account(1, 2);
+
+    for seed in 1..=3 {
+        timeline.append(phone.clone(), cid(seed)).await.unwrap();
+    }
+
+    timeline.advance(phone.clone(), 2).await.unwrap();
+
+    assert_eq!(timeline.get(phone.clone(), 10).await.unwrap(), vec![cid(3)]);
+    assert_eq!(timeline.get(laptop.clone(), 10).await.unwrap().len(), 3);
+
+    timeline.advance(laptop.clone(), 1).await.unwrap();
+
+    let mut cursors = timeline.cursors(1).await.unwrap();
+
+    cursors.sort();
+
+    let mut expected = vec![(phone.client_id, 2), (laptop.client_id, 1)];
+
+    expected.sort();
+
+    assert_eq!(cursors, expected);
+}
+
+/// Concurrent appends to one account through one shared handle are never lost.
+pub async fn timeline_concurrency<T: Timeline + Sync>(timeline: &T) {
+    let mns = account(1, 1);
+
+    join_all((0..32u8).map(|seed| timeline.append(mns.clone(), cid(seed))))
+        .await
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()
+        .unwrap();
+
+    let mut cids = timeline.get(mns.clone(), 100).await.unwrap();
+
+    cids.sort();
+
+    let mut expected = (0..32u8).map(cid).collect::<Vec<_>>();
+
+    expected.sort();
+
+    assert_eq!(cids, expected);
+}
+
+/// Acknowledged appends and cursor moves survive reopen.
+pub fn timeline_durable<T, F>(open: F, dir: &Path)
+where
+    T: Timeline + Sync,
+    F: Fn(&Path) -> T,
+{
+    let mns = account(1, 1);
+
+    block_on(async {
+        let timeline = open(dir);
+
+        for seed in 1..=3 {
+            timeline.append(mns.clone(), cid(seed)).await.unwrap();
+        }
+
+        timeline.advance(mns.clone(), 1).await.unwrap();
+    });
+
+    block_on(async {
+        let timeline = open(dir);
+
+        assert_eq!(
+            timeline.get(mns.clone(), 10).await.unwrap(),
+            vec![cid(2), cid(3)]
+        );
+    });
+}
+
+/// Acknowledged appends and cursor moves survive a process abort.
+///
+/// `test` is the full path of the calling test, it is re-executed to write and abort.
+pub fn timeline_crash<T, F>(open: F, test: &str)
+where
+    T: Timeline + Sync,
+    F: Fn(&Path) -> T,
+{
+    let mns = account(1, 1);
+
+    let dir = crashed(test, |dir| {
+        block_on(async {
+            let timeline = open(dir);
+
+            for seed in 1..=3 {
+                timeline.append(mns.clone(), cid(seed)).await.unwrap();
+            }
+
+            timeline.advance(mns.clone(), 1).await.unwrap();
+        })
+    });
+
+    block_on(async {
+        let timeline = open(dir.path());
+
+        assert_eq!(
+            timeline.get(mns.clone(), 10).await.unwrap(),
+            vec![cid(2), cid(3)]
+        );
+    });
+}
+
+/// Generate [`MimeKV`] conformance tests for backend factory `$open: Fn(&Path) -> K`.
+///
+/// Pass `durable` for backends persisting to the given directory.
+#[macro_export]
+macro_rules! mime_kv_conformance {
+    ($open:expr, durable) => {
+        $crate::mime_kv_conformance!($open);
+
+        #[test]
+        fn mime_kv_durable() {
+            $crate::conformance::kv_durable($open, $crate::conformance::temp_dir().path());
+        }
+
+        #[test]
+        fn mime_kv_crash() {
+            $crate::conformance::kv_crash($open, concat!(module_path!(), "::mime_kv_crash"));
+        }
+    };
+    ($open:expr) => {
+        #[test]
+        fn mime_kv_round_trip() {
+            let dir = $crate::conformance::temp_dir();
+            let kv = ($open)(dir.path());
+            $crate::conformance::block_on($crate::conformance::kv_round_trip(&kv));
+        }
+
+        #[test]
+        fn mime_kv_raw_round_trip() {
+            let dir = $crate::conformance::temp_dir();
+            let kv = ($open)(dir.path());
+            $crate::conformance::block_on($crate::conformance::kv_raw_round_trip(&kv));
+        }
+
+        #[test]
+        fn mime_kv_batch() {
+            let dir = $crate::conformance::temp_dir();
+            let kv = ($open)(dir.path());
+            $crate::conformance::block_on($crate::conformance::kv_batch(&kv));
+        }
+
+        #[test]
+        fn mime_kv_concurrency() {
+            let dir = $crate::conformance::temp_dir();
+            let kv = ($open)(dir.path());
+            $crate::conformance::block_on($crate::conformance::kv_concurrency(&kv));
+        }
+    };
+}
+
+/// Generate [`Timeline`] conformance tests for backend factory `$open: Fn(&Path) -> T`.
+///
+/// Pass `durable` for backends persisting to the given directory.
+#[macro_export]
+macro_rules! timeline_conformance {
+    ($open:expr, durable) => {
+        $crate::timeline_conformance!($open);
+
+        #[test]
+        fn timeline_durable() {
+            $crate::conformance::timeline_durable($open, $crate::conformance::temp_dir().path());
+        }
+
+        #[test]
+        fn timeline_crash() {
+            $crate::conformance::timeline_crash($open, concat!(module_path!(), "::timeline_crash"));
+        }
+    };
+    ($open:expr) => {
+        #[test]
+        fn timeline_append_get() {
+            let dir = $crate::conformance::temp_dir();
+            let timeline = ($open)(dir.path());
+            $crate::conformance::block_on($crate::conformance::timeline_append_get(&timeline));
+        }
+
+        #[test]
+        fn timeline_cursor_clamp() {
+            let dir = $crate::conformance::temp_dir();
+            let timeline = ($open)(dir.path());
+            $crate::conformance::block_on($crate::conformance::timeline_cursor_clamp(&timeline));
+        }
+
+        #[test]
+        fn timeline_multi_client() {
+            let dir = $crate::conformance::temp_dir();
+            let timeline = ($open)(dir.path());
+            $crate::conformance::block_on($crate::conformance::timeline_multi_client(&timeline));
+        }
+
+        #[test]
+        fn timeline_concurrency() {
+            let dir = $crate::conformance::temp_dir();
+            let timeline = ($open)(dir.path());
+            $crate::conformance::block_on($crate::conformance::timeline_concurrency(&timeline));
+        }
+    };
+}
@@ -0,0 +1,816 @@
+//! Encryption at rest wrappers of [`MimeKV`] and [`Timeline`] backends.
+//!
+//! Values are sealed with AES-256-GCM-SIV under per-account data keys, and data keys are stored in
+//! a keyring file wrapped by the node master key. Every sealed value records the data key version,
+//! so rotated keys keep old data readable.
+//!
+//! The [`Keyring`] also seals [`LeveldbTimeline`](crate::leveldb_timeline::LeveldbTimeline)
+//! account records, entries and envelope index, through
+//! [`with_cipher`](crate::leveldb_timeline::LeveldbTimeline::with_cipher). [`EncryptedTimeline`]
+//! over LevelDB should be paired with it, the wrapper alone leaves cursors and envelope senders
+//! readable.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use aes_gcm_siv::{
+    aead::{Aead, Payload},
+    Aes256GcmSiv, Key, KeyInit, Nonce,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use dimsp_types::{Envelope, EnvelopeQuery, MNSAccount, Mime};
+use hex::{FromHex, ToHex};
+use libipld::{multihash::Multihash, Cid};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec::{self, MimeOptions},
+    envelope::EnvelopeIndex,
+    error::StorageError,
+    kv::MimeKV,
+    timeline::Timeline,
+};
+
+/// Private use multicodec of sealed timeline cids, the identity multihash carries the ciphertext.
+pub const SEALED_CID_CODEC: u64 = 0x300001;
+
+/// Format byte of sealed values.
+const SEALED_FORMAT: u8 = 1;
+
+const NONCE_LEN: usize = 12;
+
+const TAG_LEN: usize = 16;
+
+fn encryption_error<S: Into<String>>(message: S) -> anyhow::Error {
+    StorageError::Encryption(message.into()).into()
+}
+
+fn read_keyfile(path: &Path) -> Result<[u8; 32]> {
+    let text = fs::read_to_string(path)?;
+
+    <[u8; 32]>::from_hex(text.trim().trim_start_matches("0x"))
+        .map_err(|err| encryption_error(format!("keyfile {}, {}", path.display(), err)))
+}
+
+fn cipher(key: &[u8; 32]) -> Aes256GcmSiv {
+    Aes256GcmSiv::new(Key::<Aes256GcmSiv>::from_slice(key))
+}
+
+fn encrypt(key: &[u8; 32], nonce: &[u8; NONCE_LEN], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    cipher(key)
+        .encrypt(Nonce::from_slice(nonce), Payload { msg, aad })
+        .map_err(|_| encryption_error("encrypt failed"))
+}
+
+fn decrypt(key: &[u8; 32], nonce: &[u8], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if nonce.len() != NONCE_LEN {
+        return Err(encryption_error("invalid nonce"));
+    }
+
+    cipher(key)
+        .decrypt(Nonce::from_slice(nonce), Payload { msg, aad })
+        .map_err(|_| encryption_error("decrypt failed, wrong key or tampered value"))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WrappedKey {
+    version: u32,
+    /// Hex encoded nonce and data key sealed by master key.
+    key: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KeyringFile {
+    scopes: BTreeMap<String, Vec<WrappedKey>>,
+}
+
+struct KeyringState {
+    master: [u8; 32],
+    file: KeyringFile,
+    unwrapped: HashMap<(String, u32), [u8; 32]>,
+}
+
+impl KeyringState {
+    fn wrap(&self, scope: &str, key: &[u8; 32]) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let mut buff = nonce.to_vec();
+
+        buff.append(&mut encrypt(&self.master, &nonce, key, scope.as_bytes())?);
+
+        Ok(buff.encode_hex())
+    }
+
+    fn unwrap(&mut self, scope: &str, version: u32) -> Result<[u8; 32]> {
+        if let Some(key) = self.unwrapped.get(&(scope.to_owned(), version)) {
+            return Ok(*key);
+        }
+
+        let wrapped = self
+            .file
+            .scopes
+            .get(scope)
+            .and_then(|keys| keys.iter().find(|k| k.version == version))
+            .ok_or_else(|| encryption_error(format!("data key {}/{} not found", scope, version)))?;
+
+        let buff = Vec::<u8>::from_hex(&wrapped.key)?;
+
+        let (nonce, sealed) = buff.split_at(NONCE_LEN.min(buff.len()));
+
+        let key: [u8; 32] = decrypt(&self.master, nonce, sealed, scope.as_bytes())?
+            .try_into()
+            .map_err(|_| encryption_error("invalid data key length"))?;
+
+        self.unwrapped.insert((scope.to_owned(), version), key);
+
+        Ok(key)
+    }
+
+    /// Generate data key version following the latest one of `scope`.
+    fn generate(&mut self, scope: &str) -> Result<u32> {
+        let version = self
+            .file
+            .scopes
+            .get(scope)
+            .and_then(|keys| keys.last())
+            .map(|k| k.version + 1)
+            .unwrap_or(1);
+
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+
+        let wrapped = WrappedKey {
+            version,
+            key: self.wrap(scope, &key)?,
+        };
+
+        self.file
+            .scopes
+            .entry(scope.to_owned())
+            .or_default()
+            .push(wrapped);
+
+        self.unwrapped.insert((scope.to_owned(), version), key);
+
+        Ok(version)
+    }
+}
+
+/// Data keys wrapped by node master key, persisted in a keyring file.
+pub struct Keyring {
+    path: PathBuf,
+    state: Mutex<KeyringState>,
+}
+
+impl Keyring {
+    /// Open keyring file `path` with master key read from hex `master_keyfile`, the keyring is created if missing.
+    pub fn open<M: AsRef<Path>, P: Into<PathBuf>>(master_keyfile: M, path: P) -> Result<Self> {
+        let path = path.into();
+
+        let file = if path.exists() {
+            serde_json::from_slice(&fs::read(&path)?)?
+        } else {
+            KeyringFile::default()
+        };
+
+        Ok(Self {
+            path,
+            state: Mutex::new(KeyringState {
+                master: read_keyfile(master_keyfile.as_ref())?,
+                file,
+                unwrapped: Default::default(),
+            }),
+        })
+    }
+
+    fn save(&self, file: &KeyringFile) -> Result<()> {
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        fs::write(&tmp, serde_json::to_vec_pretty(file)?)?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+
+    /// Returns latest data key of `scope`, generates the first version if needed.
+    fn current(&self, scope: &str) -> Result<(u32, [u8; 32])> {
+        let mut state = self.state.lock().unwrap();
+
+        let latest = state
+            .file
+            .scopes
+            .get(scope)
+            .and_then(|keys| keys.last())
+            .map(|k| k.version);
+
+        let version = match latest {
+            Some(version) => version,
+            None => {
+                let version = state.generate(scope)?;
+                self.save(&state.file)?;
+                version
+            }
+        };
+
+        Ok((version, state.unwrap(scope, version)?))
+    }
+
+    fn key(&self, scope: &str, version: u32) -> Result<[u8; 32]> {
+        self.state.lock().unwrap().unwrap(scope, version)
+    }
+
+    /// Start a new data key version of `scope`, new values are sealed with it.
+    pub fn rotate(&self, scope: &str) -> Result<u32> {
+        let mut state = self.state.lock().unwrap();
+
+        let version = state.generate(scope)?;
+
+        self.save(&state.file)?;
+
+        Ok(version)
+    }
+
+    /// Rewrap every data key with master key read from `master_keyfile`, stored values are untouched.
+    pub fn rotate_master<M: AsRef<Path>>(&self, master_keyfile: M) -> Result<()> {
+        let master = read_keyfile(master_keyfile.as_ref())?;
+
+        let mut state = self.state.lock().unwrap();
+
+        let mut keys = vec![];
+
+        for (scope, wrapped) in &state.file.scopes.clone() {
+            for key in wrapped {
+                keys.push((
+                    scope.clone(),
+                    key.version,
+                    state.unwrap(scope, key.version)?,
+                ));
+            }
+        }
+
+        state.master = master;
+
+        let mut file = KeyringFile::default();
+
+        for (scope, version, key) in keys {
+            let key = state.wrap(&scope, &key)?;
+
+            file.scopes
+                .entry(scope)
+                .or_default()
+                .push(WrappedKey { version, key });
+        }
+
+        self.save(&file)?;
+
+        state.file = file;
+
+        Ok(())
+    }
+
+    /// Seal `msg` with latest key of `scope`, layout: format(1) | key version(4) | nonce(12) | ciphertext.
+    fn seal(&self, scope: &str, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let (version, key) = self.current(scope)?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let mut buff = vec![SEALED_FORMAT];
+
+        buff.extend_from_slice(&version.to_be_bytes());
+        buff.extend_from_slice(&nonce);
+        buff.append(&mut encrypt(&key, &nonce, msg, aad)?);
+
+        Ok(buff)
+    }
+
+    fn open_sealed(&self, scope: &str, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < 5 + NONCE_LEN || sealed[0] != SEALED_FORMAT {
+            return Err(encryption_error("invalid sealed value"));
+        }
+
+        let version = u32::from_be_bytes(sealed[1..5].try_into()?);
+
+        let key = self.key(scope, version)?;
+
+        decrypt(
+            &key,
+            &sealed[5..5 + NONCE_LEN],
+            &sealed[5 + NONCE_LEN..],
+            aad,
+        )
+    }
+
+    /// Deterministically seal `cid` into an identity multihash cid, layout: key version(4) | ciphertext.
+    ///
+    /// Nonce is fixed, GCM-SIV only reveals that two sealed cids of one scope are equal.
+    fn seal_cid(&self, scope: &str, cid: &Cid) -> Result<Cid> {
+        let (version, key) = self.current(scope)?;
+
+        seal_cid_with(version, &key, scope, cid)
+    }
+
+    /// Seal `cid` with the first data key of `scope`, the result never changes with rotation.
+    ///
+    /// Used as storage key of sealed objects, so they stay reachable by their plaintext cid.
+    fn index_cid(&self, scope: &str, cid: &Cid) -> Result<Cid> {
+        self.current(scope)?;
+
+        seal_cid_with(1, &self.key(scope, 1)?, scope, cid)
+    }
+
+    /// Keyed token of `value` under the first data key of `scope`, the synthetic IV of its sealing.
+    fn token(&self, scope: &str, value: &[u8]) -> Result<Vec<u8>> {
+        self.current(scope)?;
+
+        let aad = format!("{}/token", scope);
+
+        let sealed = encrypt(
+            &self.key(scope, 1)?,
+            &[0u8; NONCE_LEN],
+            value,
+            aad.as_bytes(),
+        )?;
+
+        Ok(sealed[sealed.len() - TAG_LEN..].to_vec())
+    }
+
+    fn open_cid(&self, scope: &str, sealed: &Cid) -> Result<Cid> {
+        let buff = sealed.hash().digest();
+
+        if sealed.codec() != SEALED_CID_CODEC || buff.len() < 4 {
+            return Err(encryption_error(format!("cid({}) is not sealed", sealed)));
+        }
+
+        let version = u32::from_be_bytes(buff[..4].try_into()?);
+
+        let key = self.key(scope, version)?;
+
+        let plain = decrypt(&key, &[0u8; NONCE_LEN], &buff[4..], scope.as_bytes())?;
+
+        Ok(Cid::try_from(plain)?)
+    }
+}
+
+fn seal_cid_with(version: u32, key: &[u8; 32], scope: &str, cid: &Cid) -> Result<Cid> {
+    let mut buff = version.to_be_bytes().to_vec();
+
+    buff.append(&mut encrypt(
+        key,
+        &[0u8; NONCE_LEN],
+        &cid.to_bytes(),
+        scope.as_bytes(),
+    )?);
+
+    Ok(Cid::new_v1(SEALED_CID_CODEC, Multihash::wrap(0, &buff)?))
+}
+
+fn account_scope(mns_id: u64) -> String {
+    format!("mns/{}", mns_id)
+}
+
+#[cfg(feature = "leveldb_timeline")]
+impl crate::leveldb_timeline::RecordCipher for Keyring {
+    fn seal(&self, mns_id: u64, record: &[u8]) -> Result<Vec<u8>> {
+        Keyring::seal(self, &account_scope(mns_id), record, &mns_id.to_be_bytes())
+    }
+
+    fn open(&self, mns_id: u64, sealed: &[u8]) -> Result<Vec<u8>> {
+        self.open_sealed(&account_scope(mns_id), sealed, &mns_id.to_be_bytes())
+    }
+
+    fn token(&self, mns_id: u64, value: &[u8]) -> Result<Vec<u8>> {
+        Keyring::token(self, &account_scope(mns_id), value)
+    }
+}
+
+/// [`MimeKV`] wrapper storing mime objects of one account sealed in `inner`.
+///
+/// Cids are computed over the plaintext encoding, so verify-on-read keeps working. Objects are
+/// stored under a sealed cid of the account, so accounts sharing `inner` never share a data key.
+#[derive(Clone)]
+pub struct EncryptedMimeKV<K> {
+    inner: K,
+    keyring: Arc<Keyring>,
+    scope: String,
+    options: MimeOptions,
+}
+
+impl<K> EncryptedMimeKV<K> {
+    pub fn new(inner: K, keyring: Arc<Keyring>, mns_id: u64, options: MimeOptions) -> Self {
+        Self {
+            inner,
+            keyring,
+            scope: account_scope(mns_id),
+            options,
+        }
+    }
+
+    fn index(&self, cid: &Cid) -> Result<Cid> {
+        self.keyring.index_cid(&self.scope, cid)
+    }
+
+    fn open(&self, cid: &Cid, sealed: Option<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        sealed
+            .map(|sealed| {
+                self.keyring
+                    .open_sealed(&self.scope, &sealed, &cid.to_bytes())
+            })
+            .transpose()
+    }
+
+    fn decode(&self, cid: &Cid, sealed: Option<Vec<u8>>) -> Result<Option<Mime>> {
+        self.open(cid, sealed)?
+            .map(|data| self.options.decode(cid, &data))
+            .transpose()
+    }
+}
+
+#[async_trait]
+impl<K> MimeKV for EncryptedMimeKV<K>
+where
+    K: MimeKV + Send + Sync,
+{
+    async fn put(&self, mime: Mime) -> Result<Cid> {
+        let (cid, data) = self.options.encode(&mime)?;
+
+        self.put_unchecked(cid, data).await?;
+
+        Ok(cid)
+    }
+
+    async fn contains_cid(&self, cid: Cid) -> Result<bool> {
+        self.inner.contains_cid(self.index(&cid)?).await
+    }
+
+    async fn get(&self, cid: Cid) -> Result<Option<Mime>> {
+        let sealed = self.inner.get_raw(self.index(&cid)?).await?;
+
+        self.decode(&cid, sealed)
+    }
+
+    async fn delete(&self, cid: Cid) -> Result<Option<Mime>> {
+        let sealed = self.inner.delete_raw(self.index(&cid)?).await?;
+
+        self.decode(&cid, sealed)
+    }
+
+    async fn cids(&self) -> Result<Vec<Cid>> {
+        // objects of other accounts don't open with this scope.
+        Ok(self
+            .inner
+            .cids()
+            .await?
+            .iter()
+            .filter(|cid| cid.codec() == SEALED_CID_CODEC)
+            .filter_map(|cid| self.keyring.open_cid(&self.scope, cid).ok())
+            .collect())
+    }
+
+    async fn get_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        let sealed = self.inner.get_raw(self.index(&cid)?).await?;
+
+        self.open(&cid, sealed)
+    }
+
+    async fn put_raw(&self, cid: Cid, data: Vec<u8>) -> Result<()> {
+        codec::verify(&cid, &data)?;
+
+        self.put_unchecked(cid, data).await
+    }
+
+    async fn put_unchecked(&self, cid: Cid, data: Vec<u8>) -> Result<()> {
+        let sealed = self.keyring.seal(&self.scope, &data, &cid.to_bytes())?;
+
+        self.inner.put_unchecked(self.index(&cid)?, sealed).await
+    }
+
+    async fn delete_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        let sealed = self.inner.delete_raw(self.index(&cid)?).await?;
+
+        self.open(&cid, sealed)
+    }
+
+    async fn contains_many(&self, cids: Vec<Cid>) -> Result<Vec<bool>> {
+        let cids = cids
+            .iter()
+            .map(|cid| self.index(cid))
+            .collect::<Result<Vec<_>>>()?;
+
+        self.inner.contains_many(cids).await
+    }
+}
+
+/// [`Timeline`] and [`EnvelopeIndex`] wrapper sealing entry, envelope and thread cids under
+/// per-account data keys.
+///
+/// Envelope cids are sealed with the first data key, like kv storage keys, so lookups by cid
+/// keep working after rotation.
+#[derive(Clone)]
+pub struct EncryptedTimeline<T> {
+    inner: T,
+    keyring: Arc<Keyring>,
+}
+
+impl<T> EncryptedTimeline<T> {
+    pub fn new(inner: T, keyring: Arc<Keyring>) -> Self {
+        Self { inner, keyring }
+    }
+}
+
+#[async_trait]
+impl<T> Timeline for EncryptedTimeline<T>
+where
+    T: Timeline + Send + Sync,
+{
+    async fn append(&self, mns: MNSAccount, cid: Cid) -> Result<()> {
+        let sealed = self.keyring.seal_cid(&account_scope(mns.uns.id), &cid)?;
+
+        self.inner.append(mns, sealed).await
+    }
+
+    async fn append_many(&self, entries: Vec<(MNSAccount, Cid)>) -> Result<()> {
+        let sealed = entries
+            .into_iter()
+            .map(|(mns, cid)| {
+                let sealed = self.keyring.seal_cid(&account_scope(mns.uns.id), &cid)?;
+
+                Ok((mns, sealed))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.inner.append_many(sealed).await
+    }
+
+    async fn get(&self, mns: MNSAccount, first_n: u64) -> Result<Vec<Cid>> {
+        let scope = account_scope(mns.uns.id);
+
+        self.inner
+            .get(mns, first_n)
+            .await?
+            .iter()
+            .map(|cid| self.keyring.open_cid(&scope, cid))
+            .collect()
+    }
+
+    async fn advance(&self, mns: MNSAccount, steps: u64) -> Result<u64> {
+        self.inner.advance(mns, steps).await
+    }
+
+    async fn length(&self, mns: MNSAccount) -> Result<u64> {
+        self.inner.length(mns).await
+    }
+
+    async fn accounts(&self) -> Result<Vec<u64>> {
+        self.inner.accounts().await
+    }
+
+    async fn entries(&self, mns_id: u64) -> Result<Vec<(u64, Cid)>> {
+        let scope = account_scope(mns_id);
+
+        self.inner
+            .entries(mns_id)
+            .await?
+            .iter()
+            .map(|(offset, cid)| Ok((*offset, self.keyring.open_cid(&scope, cid)?)))
+            .collect()
+    }
+
+    async fn cursors(&self, mns_id: u64) -> Result<Vec<(Cid, u64)>> {
+        self.inner.cursors(mns_id).await
+    }
+
+    async fn set_cursor(&self, mns: MNSAccount, offset: u64) -> Result<()> {
+        self.inner.set_cursor(mns, offset).await
+    }
+}
+
+impl<T> EncryptedTimeline<T> {
+    fn seal_envelope(&self, mns_id: u64, mut envelope: Envelope) -> Result<Envelope> {
+        let scope = account_scope(mns_id);
+
+        envelope.cid = self.keyring.index_cid(&scope, &envelope.cid)?;
+
+        envelope.thread_id = envelope
+            .thread_id
+            .map(|thread_id| self.keyring.index_cid(&scope, &thread_id))
+            .transpose()?;
+
+        Ok(envelope)
+    }
+
+    fn open_envelope(&self, mns_id: u64, mut envelope: Envelope) -> Result<Envelope> {
+        let scope = account_scope(mns_id);
+
+        envelope.cid = self.keyring.open_cid(&scope, &envelope.cid)?;
+
+        envelope.thread_id = envelope
+            .thread_id
+            .map(|thread_id| self.keyring.open_cid(&scope, &thread_id))
+            .transpose()?;
+
+        Ok(envelope)
+    }
+}
+
+#[async_trait]
+impl<T> EnvelopeIndex for EncryptedTimeline<T>
+where
+    T: EnvelopeIndex + Send + Sync,
+{
+    async fn index(&self, mns_id: u64, envelope: Envelope) -> Result<()> {
+        let envelope = self.seal_envelope(mns_id, envelope)?;
+
+        self.inner.index(mns_id, envelope).await
+    }
+
+    async fn envelope(&self, mns_id: u64, cid: Cid) -> Result<Option<Envelope>> {
+        let sealed = self.keyring.index_cid(&account_scope(mns_id), &cid)?;
+
+        self.inner
+            .envelope(mns_id, sealed)
+            .await?
+            .map(|envelope| self.open_envelope(mns_id, envelope))
+            .transpose()
+    }
+
+    async fn set_flags(&self, mns_id: u64, cid: Cid, flags: u32) -> Result<bool> {
+        let sealed = self.keyring.index_cid(&account_scope(mns_id), &cid)?;
+
+        self.inner.set_flags(mns_id, sealed, flags).await
+    }
+
+    async fn remove(&self, mns_id: u64, cid: Cid) -> Result<Option<Envelope>> {
+        let sealed = self.keyring.index_cid(&account_scope(mns_id), &cid)?;
+
+        self.inner
+            .remove(mns_id, sealed)
+            .await?
+            .map(|envelope| self.open_envelope(mns_id, envelope))
+            .transpose()
+    }
+
+    async fn search(&self, mns_id: u64, mut query: EnvelopeQuery) -> Result<Vec<Envelope>> {
+        query.thread_id = query
+            .thread_id
+            .map(|thread_id| self.keyring.index_cid(&account_scope(mns_id), &thread_id))
+            .transpose()?;
+
+        self.inner
+            .search(mns_id, query)
+            .await?
+            .into_iter()
+            .map(|envelope| self.open_envelope(mns_id, envelope))
+            .collect()
+    }
+}
+
+#[cfg(all(test, feature = "leveldb_kv", feature = "leveldb_timeline"))]
+mod tests {
+    use std::{env, fs, sync::Arc};
+
+    use dimsp_types::{Envelope, EnvelopeQuery, MNSAccount, Mime};
+    use hex::ToHex;
+    use libipld::{
+        cbor::DagCborCodec,
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+    use rand::{rngs::OsRng, RngCore};
+
+    use rusty_leveldb::LdbIterator;
+
+    use crate::{
+        envelope::EnvelopeIndex, kv::MimeKV, leveldb_kv::LeveldbMimeKV,
+        leveldb_timeline::LeveldbTimeline, timeline::Timeline,
+    };
+
+    use super::{account_scope, EncryptedMimeKV, EncryptedTimeline, Keyring};
+
+    fn random_hex() -> String {
+        let mut buff = [0u8; 32];
+        OsRng.fill_bytes(&mut buff);
+        buff.encode_hex()
+    }
+
+    #[async_std::test]
+    async fn test_encryption() {
+        let root = env::temp_dir().join(random_hex());
+        fs::create_dir_all(&root).unwrap();
+
+        fs::write(root.join("master1"), random_hex()).unwrap();
+        fs::write(root.join("master2"), random_hex()).unwrap();
+
+        let keyring = Arc::new(Keyring::open(root.join("master1"), root.join("keyring")).unwrap());
+
+        let plain_kv = LeveldbMimeKV::memory().unwrap();
+        let inner_kv = LeveldbMimeKV::memory().unwrap();
+        let kv = EncryptedMimeKV::new(inner_kv.clone(), keyring.clone(), 0, Default::default());
+        let other_kv =
+            EncryptedMimeKV::new(inner_kv.clone(), keyring.clone(), 1, Default::default());
+
+        let inner_timeline = LeveldbTimeline::memory().unwrap();
+        let timeline = EncryptedTimeline::new(
+            inner_timeline.clone().with_cipher(keyring.clone()),
+            keyring.clone(),
+        );
+
+        let mime = Mime {
+            id: Cid::new_v1(DagCborCodec.into(), Code::Blake2b256.digest(&b""[..])),
+            length: 5,
+            content: b"hello".to_vec(),
+            multipart: vec![],
+        };
+
+        // cid is computed over plaintext.
+        let cid = kv.put(mime.clone()).await.unwrap();
+        assert_eq!(cid, plain_kv.put(mime.clone()).await.unwrap());
+
+        assert!(!inner_kv.contains_cid(cid).await.unwrap());
+        assert_eq!(kv.cids().await.unwrap(), vec![cid]);
+
+        // data keys are scoped per account.
+        assert!(!other_kv.contains_cid(cid).await.unwrap());
+        assert!(other_kv.cids().await.unwrap().is_empty());
+
+        // tampered objects fail verification.
+        assert!(kv.put_raw(cid, b"tampered".to_vec()).await.is_err());
+
+        let mns = MNSAccount::default();
+
+        timeline.append(mns.clone(), cid).await.unwrap();
+
+        // account records are sealed too.
+        assert!(inner_timeline.length(mns.clone()).await.is_err());
+
+        let envelope = Envelope {
+            cid,
+            sender: 7,
+            received: 100,
+            size: 5,
+            thread_id: Some(cid),
+            flags: 0,
+        };
+
+        timeline.index(0, envelope.clone()).await.unwrap();
+
+        let from_7 = EnvelopeQuery {
+            sender: Some(7),
+            thread_id: Some(cid),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            timeline.search(0, from_7.clone()).await.unwrap(),
+            vec![envelope.clone()]
+        );
+
+        // neither entries nor envelope keys and records hold the cid or the sender in clear.
+        {
+            let mut db = inner_timeline.db.lock().unwrap();
+            let mut iter = db.new_iter().unwrap();
+
+            let (mut key, mut value) = (vec![], vec![]);
+
+            let plain = [cid.to_bytes(), cid.to_string().into_bytes()];
+
+            while iter.advance() {
+                iter.current(&mut key, &mut value);
+
+                for buff in [&key, &value] {
+                    assert!(!plain.iter().any(|p| buff.windows(p.len()).any(|w| w == p)));
+                }
+
+                assert!(!String::from_utf8_lossy(&key).contains(&format!("{:020}", 7)));
+            }
+        }
+
+        // old data stays readable after data key and master key rotation.
+        keyring.rotate(&account_scope(0)).unwrap();
+        keyring.rotate_master(root.join("master2")).unwrap();
+
+        let keyring = Arc::new(Keyring::open(root.join("master2"), root.join("keyring")).unwrap());
+
+        let kv = EncryptedMimeKV::new(inner_kv, keyring.clone(), 0, Default::default());
+        let timeline = EncryptedTimeline::new(inner_timeline.with_cipher(keyring.clone()), keyring);
+
+        assert_eq!(kv.get(cid).await.unwrap().unwrap().content, mime.content);
+        assert_eq!(timeline.get(mns, 1).await.unwrap(), vec![cid]);
+
+        assert_eq!(
+            timeline.search(0, from_7).await.unwrap(),
+            vec![envelope.clone()]
+        );
+        assert!(timeline.set_flags(0, cid, 1).await.unwrap());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
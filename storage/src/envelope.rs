@@ -0,0 +1,25 @@
+//! Envelope metadata index of account timelines.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dimsp_types::{Envelope, EnvelopeQuery};
+use libipld::Cid;
+
+/// Secondary index over envelopes of delivered messages, searchable per account.
+#[async_trait]
+pub trait EnvelopeIndex {
+    /// Index `envelope` for account `mns_id`, replacing the envelope of the same cid.
+    async fn index(&self, mns_id: u64, envelope: Envelope) -> Result<()>;
+
+    /// Returns indexed envelope of `cid`.
+    async fn envelope(&self, mns_id: u64, cid: Cid) -> Result<Option<Envelope>>;
+
+    /// Replace flags of `cid` envelope, returns false if it isn't indexed.
+    async fn set_flags(&self, mns_id: u64, cid: Cid, flags: u32) -> Result<bool>;
+
+    /// Remove `cid` envelope, returns removed envelope.
+    async fn remove(&self, mns_id: u64, cid: Cid) -> Result<Option<Envelope>>;
+
+    /// Returns envelopes of account `mns_id` matching `query`, newest first.
+    async fn search(&self, mns_id: u64, query: EnvelopeQuery) -> Result<Vec<Envelope>>;
+}
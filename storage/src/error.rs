@@ -0,0 +1,35 @@
+use libipld::Cid;
+use thiserror::Error;
+
+/// Typed storage errors, returned wrapped in [`anyhow::Error`] so callers can `downcast_ref` them.
+#[derive(Debug, Error)]
+pub enum StorageError {
+    /// Stored content doesn't hash to the requested cid.
+    #[error("Integrity: stored mime({0}) content hashes to {1}")]
+    Integrity(Cid, Cid),
+    #[error("MimeNotFound: mime({0}) not found")]
+    MimeNotFound(Cid),
+    #[error("CarFormat: invalid car archive, {0}")]
+    CarFormat(String),
+    #[error("SchemaTooNew: {0} schema version({1}) is newer than supported version({2})")]
+    SchemaTooNew(String, u32, u32),
+    #[error("SnapshotIntegrity: {0}")]
+    SnapshotIntegrity(String),
+    #[error("SnapshotTarget: snapshot directory {0} is not empty")]
+    SnapshotTarget(String),
+    #[error("UnsupportedMultihash: multihash code({0:#x}) is not supported")]
+    UnsupportedMultihash(u64),
+    #[error("UnsupportedCodec: ipld codec({0:#x}) is not supported")]
+    UnsupportedCodec(u64),
+    /// Fewer replicas than the write quorum stored the mime object.
+    #[error("Quorum: mime({0}) stored by {1} replicas, write quorum is {2}")]
+    Quorum(Cid, usize, usize),
+    /// Only the first `{0}` entries of a batch append were applied.
+    #[error("PartialAppend: first {0} entries appended, then {1}")]
+    PartialAppend(usize, String),
+    #[error("Encryption: {0}")]
+    Encryption(String),
+    /// Optional backend method without an implementation.
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+}
@@ -0,0 +1,164 @@
+//! [`MimeKV`] keeping one file per mime object, meant as cold tier of
+//! [`TieredMimeKV`](crate::tiered::TieredMimeKV) on large and slow disks.
+//!
+//! Objects are stored as `{root}/{cid}`. Writes go to a `.tmp` file that is synced and renamed
+//! over the final name, so a crash never leaves a partial object behind.
+
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dimsp_types::Mime;
+use libipld::Cid;
+
+use crate::{
+    blocking::unblock,
+    codec::{self, MimeOptions},
+    kv::MimeKV,
+};
+
+const TMP_SUFFIX: &str = ".tmp";
+
+#[derive(Clone)]
+pub struct FsMimeKV {
+    root: Arc<PathBuf>,
+    options: MimeOptions,
+}
+
+impl FsMimeKV {
+    /// Open kv directory `root`, the directory is created if missing.
+    pub fn local<P: Into<PathBuf>>(root: P) -> Result<Self> {
+        Self::local_with_options(root, Default::default())
+    }
+
+    /// Open kv directory `root` with mime encoding `options`.
+    pub fn local_with_options<P: Into<PathBuf>>(root: P, options: MimeOptions) -> Result<Self> {
+        let root = root.into();
+
+        fs::create_dir_all(&root)?;
+
+        Ok(Self {
+            root: Arc::new(root),
+            options,
+        })
+    }
+
+    fn path(&self, cid: &Cid) -> PathBuf {
+        self.root.join(cid.to_string())
+    }
+
+    async fn write(&self, cid: Cid, data: Vec<u8>) -> Result<()> {
+        let path = self.path(&cid);
+
+        unblock(move || {
+            let mut tmp = path.clone().into_os_string();
+            tmp.push(TMP_SUFFIX);
+
+            let mut file = File::create(&tmp)?;
+
+            file.write_all(&data)?;
+            file.sync_all()?;
+
+            fs::rename(&tmp, &path)?;
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+fn read(path: PathBuf) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[async_trait]
+impl MimeKV for FsMimeKV {
+    async fn put(&self, mime: Mime) -> Result<Cid> {
+        let (cid, data) = self.options.encode(&mime)?;
+
+        self.write(cid, data).await?;
+
+        Ok(cid)
+    }
+
+    async fn contains_cid(&self, cid: Cid) -> Result<bool> {
+        let path = self.path(&cid);
+
+        unblock(move || Ok(path.exists())).await
+    }
+
+    async fn get(&self, cid: Cid) -> Result<Option<Mime>> {
+        self.get_raw(cid)
+            .await?
+            .map(|data| self.options.decode(&cid, &data))
+            .transpose()
+    }
+
+    async fn delete(&self, cid: Cid) -> Result<Option<Mime>> {
+        self.delete_raw(cid)
+            .await?
+            .map(|data| codec::decode(&cid, &data))
+            .transpose()
+    }
+
+    async fn cids(&self) -> Result<Vec<Cid>> {
+        let root = self.root.clone();
+
+        unblock(move || {
+            let mut cids = vec![];
+
+            for entry in fs::read_dir(root.as_path())? {
+                let name = entry?.file_name();
+
+                // leftovers of interrupted writes.
+                match name.to_str().map(Cid::try_from) {
+                    Some(Ok(cid)) => cids.push(cid),
+                    _ => log::debug!("FsMimeKV skip {:?}", name),
+                }
+            }
+
+            Ok(cids)
+        })
+        .await
+    }
+
+    async fn get_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        let path = self.path(&cid);
+
+        unblock(move || read(path)).await
+    }
+
+    async fn put_raw(&self, cid: Cid, data: Vec<u8>) -> Result<()> {
+        codec::verify(&cid, &data)?;
+
+        self.write(cid, data).await
+    }
+
+    async fn put_unchecked(&self, key: Cid, data: Vec<u8>) -> Result<()> {
+        self.write(key, data).await
+    }
+
+    async fn delete_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        let path = self.path(&cid);
+
+        unblock(move || {
+            let data = read(path.clone())?;
+
+            if data.is_some() {
+                fs::remove_file(path)?;
+            }
+
+            Ok(data)
+        })
+        .await
+    }
+}
@@ -4,18 +4,126 @@ use async_trait::async_trait;
 use dimsp_types::Mime;
 use libipld::Cid;
 
+use crate::{codec, error::StorageError};
+
 /// Ipld kv database.
+///
+/// Methods take `&self`, so one backend can be shared between sessions without extra wrapping.
 #[async_trait]
 pub trait MimeKV {
     /// Put mime object into database and generate cid.
-    async fn put(&mut self, mime: Mime) -> Result<Cid>;
+    async fn put(&self, mime: Mime) -> Result<Cid>;
 
     /// Returns true if the database contains a mime object for the specified cid.
-    async fn contains_cid(&mut self, cid: Cid) -> Result<bool>;
+    async fn contains_cid(&self, cid: Cid) -> Result<bool>;
 
     /// Try get mime object for specified cid. returns [`None`] if object doesn't exist
-    async fn get(&mut self, cid: Cid) -> Result<Option<Mime>>;
+    async fn get(&self, cid: Cid) -> Result<Option<Mime>>;
 
     /// Delete mime object for the specified cid. returns removed object.
-    async fn delete(&mut self, cid: Cid) -> Result<Option<Mime>>;
+    async fn delete(&self, cid: Cid) -> Result<Option<Mime>>;
+
+    /// Returns cids of all stored mime objects.
+    ///
+    /// The default fails with [`Unsupported`](StorageError::Unsupported), backends able to list their keys override it.
+    async fn cids(&self) -> Result<Vec<Cid>> {
+        Err(StorageError::Unsupported("MimeKV::cids".to_owned()).into())
+    }
+
+    /// Try get the encoded bytes of mime object for specified cid, without decoding or verification.
+    ///
+    /// The default re-encodes [`get`](MimeKV::get) with the codec recorded in `cid`.
+    async fn get_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        self.get(cid)
+            .await?
+            .map(|mime| codec::encode(&cid, &mime))
+            .transpose()
+    }
+
+    /// Put already encoded mime object under `cid`, keeping the cid chosen by its producer.
+    ///
+    /// Returns [`StorageError::Integrity`](crate::error::StorageError::Integrity) if `data` doesn't hash to `cid`.
+    ///
+    /// The default decodes `data` and [`put`](MimeKV::put)s it, failing with
+    /// [`Unsupported`](StorageError::Unsupported) if the backend addresses it by another cid.
+    async fn put_raw(&self, cid: Cid, data: Vec<u8>) -> Result<()> {
+        codec::verify(&cid, &data)?;
+
+        let stored = self.put(codec::decode(&cid, &data)?).await?;
+
+        if stored != cid {
+            return Err(StorageError::Unsupported(format!(
+                "MimeKV::put_raw of mime({}), stored as mime({})",
+                cid, stored
+            ))
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Put bytes under `key` without checking them, for wrappers storing transformed objects,
+    /// like the sealed objects of `EncryptedMimeKV`.
+    ///
+    /// The default fails with [`Unsupported`](StorageError::Unsupported).
+    async fn put_unchecked(&self, key: Cid, _data: Vec<u8>) -> Result<()> {
+        Err(StorageError::Unsupported(format!("MimeKV::put_unchecked of mime({})", key)).into())
+    }
+
+    /// Delete mime object for the specified cid. returns removed bytes without decoding them.
+    ///
+    /// The default reads the bytes with [`get_raw`](MimeKV::get_raw) before deleting.
+    async fn delete_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        let data = self.get_raw(cid).await?;
+
+        if data.is_some() {
+            self.delete(cid).await?;
+        }
+
+        Ok(data)
+    }
+
+    /// Put mime objects into database, returns generated cids in input order.
+    async fn put_many(&self, mimes: Vec<Mime>) -> Result<Vec<Cid>> {
+        let mut cids = vec![];
+
+        for mime in mimes {
+            cids.push(self.put(mime).await?);
+        }
+
+        Ok(cids)
+    }
+
+    /// Put already encoded mime objects, each checked like [`put_raw`](MimeKV::put_raw).
+    ///
+    /// The default puts them one by one, backends able to write a batch atomically override it.
+    async fn put_raw_many(&self, objects: Vec<(Cid, Vec<u8>)>) -> Result<()> {
+        for (cid, data) in objects {
+            self.put_raw(cid, data).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Try get mime objects for cids, the result has one entry per input cid.
+    async fn get_many(&self, cids: Vec<Cid>) -> Result<Vec<Option<Mime>>> {
+        let mut mimes = vec![];
+
+        for cid in cids {
+            mimes.push(self.get(cid).await?);
+        }
+
+        Ok(mimes)
+    }
+
+    /// Check existence of every cid, the result has one entry per input cid.
+    async fn contains_many(&self, cids: Vec<Cid>) -> Result<Vec<bool>> {
+        let mut flags = vec![];
+
+        for cid in cids {
+            flags.push(self.contains_cid(cid).await?);
+        }
+
+        Ok(flags)
+    }
 }
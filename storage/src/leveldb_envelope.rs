@@ -0,0 +1,633 @@
+//! [`EnvelopeIndex`] stored in the [`LeveldbTimeline`] database.
+//!
+//! Every account keeps its envelopes under `envelope/{mns_id}/`: the envelope record keyed by cid,
+//! plus `time`, `sender`, `thread`, `size` and `flag` columns whose keys end with
+//! `{u64::MAX - received}/{cid}`, so each column scan returns envelopes newest first and stops
+//! once the query limit is reached.
+//!
+//! The `size` column is bucketed by bit length of the size, the `flag` column keeps every
+//! `ENVELOPE_*` bit as set or clear.
+//!
+//! With a [`RecordCipher`] the records are sealed and the cid, sender and thread values in keys are
+//! replaced by keyed tokens. Receive time, size bucket and flag bits stay readable, columns are
+//! range scanned over them.
+
+use std::cmp::Reverse;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dimsp_types::{Envelope, EnvelopeQuery, ENVELOPE_ANSWERED, ENVELOPE_FLAGGED, ENVELOPE_SEEN};
+use libipld::Cid;
+use rusty_leveldb::{LdbIterator, WriteBatch, DB};
+
+use crate::{
+    blocking::unblock,
+    envelope::EnvelopeIndex,
+    leveldb_timeline::{lock_account, LeveldbTimeline, RecordCipher},
+};
+
+/// Key prefix of every envelope index key.
+const ENVELOPE_PREFIX: &str = "envelope/";
+
+/// Flag bits with a `flag` column.
+const INDEXED_FLAGS: [u32; 3] = [ENVELOPE_SEEN, ENVELOPE_ANSWERED, ENVELOPE_FLAGGED];
+
+fn account_prefix(mns_id: u64) -> String {
+    format!("{}{:020}/", ENVELOPE_PREFIX, mns_id)
+}
+
+fn size_bucket(size: u64) -> u32 {
+    u64::BITS - size.leading_zeros()
+}
+
+fn size_column(mns_id: u64, bucket: u32) -> String {
+    format!("{}size/{:02}/", account_prefix(mns_id), bucket)
+}
+
+fn flag_column(mns_id: u64, flag: u32, set: bool) -> String {
+    format!(
+        "{}flag/{:010}/{}/",
+        account_prefix(mns_id),
+        flag,
+        if set { "set" } else { "clear" }
+    )
+}
+
+/// Index keys and records of one account, sealed when the timeline has a cipher.
+#[derive(Clone, Copy)]
+struct AccountKeys<'a> {
+    mns_id: u64,
+    cipher: Option<&'a dyn RecordCipher>,
+}
+
+impl<'a> AccountKeys<'a> {
+    fn new(mns_id: u64, cipher: Option<&'a dyn RecordCipher>) -> Self {
+        Self { mns_id, cipher }
+    }
+
+    /// Returns `plain`, or the hex keyed token of `value` with cipher.
+    fn value(&self, plain: String, value: &[u8]) -> Result<String> {
+        let Some(cipher) = self.cipher else {
+            return Ok(plain);
+        };
+
+        Ok(cipher
+            .token(self.mns_id, value)?
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect())
+    }
+
+    fn cid(&self, cid: &Cid) -> Result<String> {
+        self.value(cid.to_string(), &cid.to_bytes())
+    }
+
+    fn record(&self, cid_key: &str) -> String {
+        format!("{}cid/{}", account_prefix(self.mns_id), cid_key)
+    }
+
+    fn sender_column(&self, sender: u64) -> Result<String> {
+        Ok(format!(
+            "{}sender/{}/",
+            account_prefix(self.mns_id),
+            self.value(format!("{:020}", sender), &sender.to_be_bytes())?
+        ))
+    }
+
+    fn thread_column(&self, thread_id: &Cid) -> Result<String> {
+        Ok(format!(
+            "{}thread/{}/",
+            account_prefix(self.mns_id),
+            self.cid(thread_id)?
+        ))
+    }
+
+    fn column_keys(&self, envelope: &Envelope) -> Result<Vec<String>> {
+        let suffix = format!(
+            "{:020}/{}",
+            u64::MAX - envelope.received,
+            self.cid(&envelope.cid)?
+        );
+
+        let mut keys = vec![
+            format!("{}time/{}", account_prefix(self.mns_id), suffix),
+            format!("{}{}", self.sender_column(envelope.sender)?, suffix),
+            format!(
+                "{}{}",
+                size_column(self.mns_id, size_bucket(envelope.size)),
+                suffix
+            ),
+        ];
+
+        if let Some(thread_id) = &envelope.thread_id {
+            keys.push(format!("{}{}", self.thread_column(thread_id)?, suffix));
+        }
+
+        for flag in INDEXED_FLAGS {
+            let set = envelope.flags & flag != 0;
+
+            keys.push(format!("{}{}", flag_column(self.mns_id, flag, set), suffix));
+        }
+
+        Ok(keys)
+    }
+
+    /// Returns envelope record stored under `cid_key`, the cid or its token.
+    fn get(&self, db: &mut DB, cid_key: &str) -> Result<Option<Envelope>> {
+        let Some(buff) = db.get(self.record(cid_key).as_bytes()) else {
+            return Ok(None);
+        };
+
+        let buff = match self.cipher {
+            Some(cipher) => cipher.open(self.mns_id, &buff)?,
+            None => buff,
+        };
+
+        Ok(Some(serde_json::from_slice(&buff)?))
+    }
+
+    fn get_envelope(&self, db: &mut DB, cid: &Cid) -> Result<Option<Envelope>> {
+        self.get(db, &self.cid(cid)?)
+    }
+
+    fn batch_remove(&self, batch: &mut WriteBatch, envelope: &Envelope) -> Result<()> {
+        batch.delete(self.record(&self.cid(&envelope.cid)?).as_bytes());
+
+        for key in self.column_keys(envelope)? {
+            batch.delete(key.as_bytes());
+        }
+
+        Ok(())
+    }
+
+    fn batch_put(&self, batch: &mut WriteBatch, envelope: &Envelope) -> Result<()> {
+        let record = serde_json::to_vec(envelope)?;
+
+        let record = match self.cipher {
+            Some(cipher) => cipher.seal(self.mns_id, &record)?,
+            None => record,
+        };
+
+        batch.put(self.record(&self.cid(&envelope.cid)?).as_bytes(), &record);
+
+        for key in self.column_keys(envelope)? {
+            batch.put(key.as_bytes(), &[]);
+        }
+
+        Ok(())
+    }
+}
+
+/// Rebuild the columns of every envelope record, replacing the oldest-first layout.
+///
+/// Runs before any cipher is attached, records of that layout are never sealed.
+pub(crate) fn reindex_envelopes(db: &mut DB) -> Result<()> {
+    let mut batch = WriteBatch::new();
+    let mut records = vec![];
+
+    {
+        let mut iter = db.new_iter()?;
+
+        let mut key = vec![];
+        let mut value = vec![];
+
+        iter.seek(ENVELOPE_PREFIX.as_bytes());
+
+        while iter.valid() {
+            iter.current(&mut key, &mut value);
+
+            if !key.starts_with(ENVELOPE_PREFIX.as_bytes()) {
+                break;
+            }
+
+            let text = String::from_utf8_lossy(&key);
+
+            let mut segments = text[ENVELOPE_PREFIX.len()..].split('/');
+
+            match (segments.next(), segments.next()) {
+                (Some(mns_id), Some("cid")) => {
+                    records.push((mns_id.parse::<u64>()?, serde_json::from_slice(&value)?))
+                }
+                _ => batch.delete(&key),
+            }
+
+            if !iter.advance() {
+                break;
+            }
+        }
+    }
+
+    for (mns_id, envelope) in &records {
+        AccountKeys::new(*mns_id, None).batch_put(&mut batch, envelope)?;
+    }
+
+    db.write(batch, true)?;
+
+    Ok(())
+}
+
+/// Scan `column` newest first within the receive time range of `query`, until `limit` hits.
+fn scan(
+    db: &mut DB,
+    keys: AccountKeys,
+    column: &str,
+    query: &EnvelopeQuery,
+    limit: usize,
+) -> Result<Vec<Envelope>> {
+    let start = match query.received_before {
+        Some(0) => return Ok(vec![]),
+        Some(before) => format!("{}{:020}", column, u64::MAX - (before - 1)),
+        None => column.to_owned(),
+    };
+
+    let mut iter = db.new_iter()?;
+
+    let mut key = vec![];
+    let mut value = vec![];
+    let mut hits = vec![];
+
+    iter.seek(start.as_bytes());
+
+    while iter.valid() && hits.len() < limit {
+        iter.current(&mut key, &mut value);
+
+        if !key.starts_with(column.as_bytes()) {
+            break;
+        }
+
+        let suffix = String::from_utf8_lossy(&key[column.len()..]).into_owned();
+
+        let (reversed, cid_key) = suffix.split_once('/').unwrap_or_default();
+
+        let received = u64::MAX - reversed.parse::<u64>()?;
+
+        if query.received_after.is_some_and(|after| received < after) {
+            break;
+        }
+
+        match keys.get(db, cid_key)? {
+            Some(envelope) if query.matches(&envelope) => hits.push(envelope),
+            _ => {}
+        }
+
+        if !iter.advance() {
+            break;
+        }
+    }
+
+    Ok(hits)
+}
+
+/// Scan the most selective column of `query`.
+fn search(db: &mut DB, keys: AccountKeys, query: &EnvelopeQuery) -> Result<Vec<Envelope>> {
+    let mns_id = keys.mns_id;
+
+    let limit = match query.limit {
+        0 => usize::MAX,
+        limit => limit as usize,
+    };
+
+    let flag = INDEXED_FLAGS.iter().find_map(|flag| {
+        if query.flags_set & flag != 0 {
+            Some((*flag, true))
+        } else if query.flags_clear & flag != 0 {
+            Some((*flag, false))
+        } else {
+            None
+        }
+    });
+
+    let column = match (query.sender, &query.thread_id, flag) {
+        (Some(sender), _, _) => keys.sender_column(sender)?,
+        (None, Some(thread_id), _) => keys.thread_column(thread_id)?,
+        (None, None, Some((flag, set))) => flag_column(mns_id, flag, set),
+        (None, None, None) if query.min_size.is_some() || query.max_size.is_some() => {
+            return search_sizes(db, keys, query, limit);
+        }
+        (None, None, None) => format!("{}time/", account_prefix(mns_id)),
+    };
+
+    scan(db, keys, &column, query, limit)
+}
+
+/// Scan every size bucket of the query range, each stops at `limit` so the newest hits are kept.
+fn search_sizes(
+    db: &mut DB,
+    keys: AccountKeys,
+    query: &EnvelopeQuery,
+    limit: usize,
+) -> Result<Vec<Envelope>> {
+    let first = size_bucket(query.min_size.unwrap_or(0));
+    let last = size_bucket(query.max_size.unwrap_or(u64::MAX));
+
+    let mut hits = vec![];
+
+    for bucket in first..=last {
+        hits.append(&mut scan(
+            db,
+            keys,
+            &size_column(keys.mns_id, bucket),
+            query,
+            limit,
+        )?);
+    }
+
+    hits.sort_by_key(|envelope| Reverse(envelope.received));
+    hits.truncate(limit);
+
+    Ok(hits)
+}
+
+#[async_trait]
+impl EnvelopeIndex for LeveldbTimeline {
+    async fn index(&self, mns_id: u64, envelope: Envelope) -> Result<()> {
+        let db = self.db.clone();
+        let accounts = self.accounts.clone();
+        let cipher = self.cipher.clone();
+
+        unblock(move || {
+            let _account = lock_account(&accounts, mns_id);
+
+            let keys = AccountKeys::new(mns_id, cipher.as_deref());
+
+            let mut db = db.lock().unwrap();
+
+            let mut batch = WriteBatch::new();
+
+            if let Some(old) = keys.get_envelope(&mut db, &envelope.cid)? {
+                keys.batch_remove(&mut batch, &old)?;
+            }
+
+            keys.batch_put(&mut batch, &envelope)?;
+
+            db.write(batch, false)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn envelope(&self, mns_id: u64, cid: Cid) -> Result<Option<Envelope>> {
+        let db = self.db.clone();
+        let cipher = self.cipher.clone();
+
+        unblock(move || {
+            let mut db = db.lock().unwrap();
+
+            AccountKeys::new(mns_id, cipher.as_deref()).get_envelope(&mut db, &cid)
+        })
+        .await
+    }
+
+    async fn set_flags(&self, mns_id: u64, cid: Cid, flags: u32) -> Result<bool> {
+        let db = self.db.clone();
+        let accounts = self.accounts.clone();
+        let cipher = self.cipher.clone();
+
+        unblock(move || {
+            let _account = lock_account(&accounts, mns_id);
+
+            let keys = AccountKeys::new(mns_id, cipher.as_deref());
+
+            let mut db = db.lock().unwrap();
+
+            let mut envelope = match keys.get_envelope(&mut db, &cid)? {
+                Some(envelope) => envelope,
+                None => return Ok(false),
+            };
+
+            let mut batch = WriteBatch::new();
+
+            keys.batch_remove(&mut batch, &envelope)?;
+
+            envelope.flags = flags;
+
+            keys.batch_put(&mut batch, &envelope)?;
+
+            db.write(batch, false)?;
+
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn remove(&self, mns_id: u64, cid: Cid) -> Result<Option<Envelope>> {
+        let db = self.db.clone();
+        let accounts = self.accounts.clone();
+        let cipher = self.cipher.clone();
+
+        unblock(move || {
+            let _account = lock_account(&accounts, mns_id);
+
+            let keys = AccountKeys::new(mns_id, cipher.as_deref());
+
+            let mut db = db.lock().unwrap();
+
+            let envelope = keys.get_envelope(&mut db, &cid)?;
+
+            if let Some(envelope) = &envelope {
+                let mut batch = WriteBatch::new();
+
+                keys.batch_remove(&mut batch, envelope)?;
+
+                db.write(batch, false)?;
+            }
+
+            Ok(envelope)
+        })
+        .await
+    }
+
+    async fn search(&self, mns_id: u64, query: EnvelopeQuery) -> Result<Vec<Envelope>> {
+        let db = self.db.clone();
+        let cipher = self.cipher.clone();
+
+        unblock(move || {
+            let mut db = db.lock().unwrap();
+
+            search(&mut db, AccountKeys::new(mns_id, cipher.as_deref()), &query)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dimsp_types::{Envelope, EnvelopeQuery, ENVELOPE_SEEN};
+    use libipld::{
+        cbor::DagCborCodec,
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use crate::{envelope::EnvelopeIndex, leveldb_timeline::LeveldbTimeline, timeline::Timeline};
+
+    use super::reindex_envelopes;
+
+    fn cid(seed: u8) -> Cid {
+        Cid::new_v1(DagCborCodec.into(), Code::Keccak256.digest(&[seed]))
+    }
+
+    fn envelope(seed: u8, sender: u64, received: u64) -> Envelope {
+        Envelope {
+            cid: cid(seed),
+            sender,
+            received,
+            size: 10,
+            ..Default::default()
+        }
+    }
+
+    async fn search(timeline: &LeveldbTimeline, query: EnvelopeQuery) -> Vec<Cid> {
+        timeline
+            .search(1, query)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|envelope| envelope.cid)
+            .collect()
+    }
+
+    #[async_std::test]
+    async fn test_envelope_index() {
+        let timeline = LeveldbTimeline::memory().unwrap();
+
+        timeline
+            .index(
+                1,
+                Envelope {
+                    flags: ENVELOPE_SEEN,
+                    ..envelope(1, 7, 100)
+                },
+            )
+            .await
+            .unwrap();
+
+        timeline.index(1, envelope(2, 8, 200)).await.unwrap();
+
+        timeline
+            .index(
+                1,
+                Envelope {
+                    thread_id: Some(cid(1)),
+                    ..envelope(3, 7, 300)
+                },
+            )
+            .await
+            .unwrap();
+
+        // other accounts are not visible.
+        timeline.index(2, envelope(4, 7, 300)).await.unwrap();
+
+        assert_eq!(
+            search(&timeline, Default::default()).await,
+            vec![cid(3), cid(2), cid(1)]
+        );
+
+        let from_7 = EnvelopeQuery {
+            sender: Some(7),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            search(&timeline, from_7.clone()).await,
+            vec![cid(3), cid(1)]
+        );
+
+        let unread = EnvelopeQuery {
+            flags_clear: ENVELOPE_SEEN,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            search(&timeline, unread.clone()).await,
+            vec![cid(3), cid(2)]
+        );
+
+        let window = EnvelopeQuery {
+            received_after: Some(150),
+            received_before: Some(300),
+            ..Default::default()
+        };
+
+        assert_eq!(search(&timeline, window).await, vec![cid(2)]);
+
+        let thread = EnvelopeQuery {
+            thread_id: Some(cid(1)),
+            limit: 1,
+            ..Default::default()
+        };
+
+        assert_eq!(search(&timeline, thread).await, vec![cid(3)]);
+
+        let latest = EnvelopeQuery {
+            limit: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(search(&timeline, latest).await, vec![cid(3), cid(2)]);
+
+        let large = EnvelopeQuery {
+            min_size: Some(1000),
+            ..Default::default()
+        };
+
+        assert!(search(&timeline, large).await.is_empty());
+
+        timeline
+            .index(
+                1,
+                Envelope {
+                    size: 4000,
+                    ..envelope(5, 8, 50)
+                },
+            )
+            .await
+            .unwrap();
+
+        let sized = EnvelopeQuery {
+            min_size: Some(10),
+            max_size: Some(5000),
+            limit: 3,
+            ..Default::default()
+        };
+
+        assert_eq!(search(&timeline, sized).await, vec![cid(3), cid(2), cid(1)]);
+
+        timeline.remove(1, cid(5)).await.unwrap();
+
+        assert!(timeline.set_flags(1, cid(3), ENVELOPE_SEEN).await.unwrap());
+
+        assert_eq!(search(&timeline, unread).await, vec![cid(2)]);
+
+        let seen = EnvelopeQuery {
+            flags_set: ENVELOPE_SEEN,
+            ..Default::default()
+        };
+
+        assert_eq!(search(&timeline, seen).await, vec![cid(3), cid(1)]);
+
+        // reindexing drops stale columns and keeps the records.
+        reindex_envelopes(&mut timeline.db.lock().unwrap()).unwrap();
+
+        assert_eq!(
+            search(&timeline, Default::default()).await,
+            vec![cid(3), cid(2), cid(1)]
+        );
+
+        // reindexing moves the envelope between sender columns.
+        timeline.index(1, envelope(1, 9, 100)).await.unwrap();
+
+        assert_eq!(search(&timeline, from_7).await, vec![cid(3)]);
+
+        timeline.remove(1, cid(2)).await.unwrap();
+
+        assert_eq!(
+            search(&timeline, Default::default()).await,
+            vec![cid(3), cid(1)]
+        );
+
+        // envelope keys don't show up as timeline accounts.
+        assert!(timeline.accounts().await.unwrap().is_empty());
+    }
+}
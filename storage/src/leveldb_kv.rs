@@ -8,96 +8,300 @@ use anyhow::Result;
 use async_trait::async_trait;
 use dimsp_types::{keccack256, Mime};
 
-use libipld::{
-    cbor::DagCborCodec,
-    multihash::{Code, MultihashDigest},
-    prelude::Codec,
-    Cid,
+use libipld::Cid;
+
+use rusty_leveldb::{LdbIterator, WriteBatch};
+
+use crate::{
+    blocking::unblock,
+    codec::{self, MimeOptions},
+    kv::MimeKV,
+    leveldb_schema::{adopt_unversioned, open_schema, Migration},
+    leveldb_snapshot::snapshot_db,
+    snapshot::{Snapshot, SnapshotManifest},
 };
 
-use crate::kv::MimeKV;
+/// Kv layout migrations, ordered by version.
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    name: "adopt unversioned layout",
+    migrate: adopt_unversioned,
+}];
 
+#[derive(Clone)]
 pub struct LeveldbMimeKV {
-    db: Arc<Mutex<rusty_leveldb::DB>>,
+    /// Not sharded, unlike the timeline account locks: every kv call is one get or one batch
+    /// write, there is no per-account read-modify-write to serialize, and rusty-leveldb takes
+    /// `&mut` for reads too, so finer locks over one database wouldn't run anything in parallel.
+    pub(crate) db: Arc<Mutex<rusty_leveldb::DB>>,
+    options: MimeOptions,
 }
 
 impl LeveldbMimeKV {
     /// Create kv in memory
     pub fn memory() -> Result<Self> {
-        let db = rusty_leveldb::DB::open("::memory::", rusty_leveldb::in_memory())?;
+        Self::memory_with_options(Default::default())
+    }
+
+    /// Create kv database in local storage
+    pub fn local<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        Self::local_with_options(path, Default::default())
+    }
+
+    /// Create kv in memory with mime encoding `options`
+    pub fn memory_with_options(options: MimeOptions) -> Result<Self> {
+        let mut db = rusty_leveldb::DB::open("::memory::", rusty_leveldb::in_memory())?;
+
+        open_schema(&mut db, "kv", MIGRATIONS)?;
+
         Ok(Self {
             db: Arc::new(Mutex::new(db)),
+            options,
         })
     }
 
-    /// Create kv database in local storage
-    pub fn local<P: Into<PathBuf>>(path: P) -> Result<Self> {
-        let db = rusty_leveldb::DB::open(path.into(), Default::default())?;
+    /// Create kv database in local storage with mime encoding `options`
+    pub fn local_with_options<P: Into<PathBuf>>(path: P, options: MimeOptions) -> Result<Self> {
+        let mut db = rusty_leveldb::DB::open(path.into(), Default::default())?;
+
+        open_schema(&mut db, "kv", MIGRATIONS)?;
 
         Ok(Self {
             db: Arc::new(Mutex::new(db)),
+            options,
         })
     }
 }
 
+fn decode_mime(options: &MimeOptions, cid: &Cid, data: Option<Vec<u8>>) -> Result<Option<Mime>> {
+    match data {
+        Some(mime) => Ok(Some(options.decode(cid, &mime)?)),
+        None => Ok(None),
+    }
+}
+
+/// Append mime object puts into `batch`, returns generated cid.
+fn batch_put(options: &MimeOptions, batch: &mut WriteBatch, mime: &Mime) -> Result<Cid> {
+    let (cid, data) = options.encode(mime)?;
+
+    batch_put_raw(batch, &cid, &data);
+
+    Ok(cid)
+}
+
+/// Append existence marker and content puts of encoded object into `batch`.
+fn batch_put_raw(batch: &mut WriteBatch, cid: &Cid, data: &[u8]) {
+    let cid_bytes = cid.to_bytes();
+
+    let key = keccack256(&cid_bytes);
+
+    batch.put(&key, &[0u8; 1]);
+
+    batch.put(&cid_bytes, data);
+}
+
 #[async_trait]
 impl MimeKV for LeveldbMimeKV {
-    async fn contains_cid(&mut self, cid: Cid) -> Result<bool> {
-        let mut db = self.db.lock().unwrap();
+    async fn contains_cid(&self, cid: Cid) -> Result<bool> {
+        let db = self.db.clone();
+
+        unblock(move || {
+            let mut db = db.lock().unwrap();
 
-        let key = keccack256(&cid.to_bytes());
+            let key = keccack256(&cid.to_bytes());
 
-        Ok(db.get(&key).is_some())
+            Ok(db.get(&key).is_some())
+        })
+        .await
     }
 
-    async fn delete(&mut self, cid: libipld::Cid) -> Result<Option<Mime>> {
-        let mut db = self.db.lock().unwrap();
+    async fn delete(&self, cid: libipld::Cid) -> Result<Option<Mime>> {
+        let mime = self.delete_raw(cid).await?;
 
-        let key = keccack256(&cid.to_bytes());
+        mime.map(|mime| codec::decode(&cid, &mime)).transpose()
+    }
 
-        db.delete(&key)?;
+    async fn get(&self, cid: libipld::Cid) -> Result<Option<Mime>> {
+        let db = self.db.clone();
+        let options = self.options;
 
-        let key = cid.to_bytes();
+        unblock(move || {
+            let mut db = db.lock().unwrap();
 
-        let mime = db.get(&key);
+            decode_mime(&options, &cid, db.get(&cid.to_bytes()))
+        })
+        .await
+    }
 
-        db.delete(&key)?;
+    async fn put(&self, mime: dimsp_types::Mime) -> Result<Cid> {
+        let db = self.db.clone();
+        let options = self.options;
 
-        if let Some(mime) = mime {
-            return Ok(DagCborCodec.decode(&mime)?);
-        } else {
-            return Ok(None);
-        }
+        unblock(move || {
+            let mut batch = WriteBatch::new();
+
+            let cid = batch_put(&options, &mut batch, &mime)?;
+
+            db.lock().unwrap().write(batch, false)?;
+
+            Ok(cid)
+        })
+        .await
+    }
+
+    async fn cids(&self) -> Result<Vec<Cid>> {
+        let db = self.db.clone();
+
+        unblock(move || {
+            let mut db = db.lock().unwrap();
+
+            let mut iter = db.new_iter()?;
+
+            let mut key = vec![];
+            let mut value = vec![];
+            let mut candidates = vec![];
+
+            while iter.advance() {
+                iter.current(&mut key, &mut value);
+
+                match Cid::try_from(key.as_slice()) {
+                    Ok(cid) if cid.to_bytes() == key => candidates.push(cid),
+                    _ => {}
+                }
+            }
+
+            // existence keys are random looking hashes, keep only cids with existence marker.
+            Ok(candidates
+                .into_iter()
+                .filter(|cid| db.get(&keccack256(&cid.to_bytes())).is_some())
+                .collect())
+        })
+        .await
+    }
+
+    async fn get_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        let db = self.db.clone();
+
+        unblock(move || Ok(db.lock().unwrap().get(&cid.to_bytes()))).await
+    }
+
+    async fn put_raw(&self, cid: Cid, data: Vec<u8>) -> Result<()> {
+        codec::verify(&cid, &data)?;
+
+        self.put_unchecked(cid, data).await
+    }
+
+    async fn put_unchecked(&self, cid: Cid, data: Vec<u8>) -> Result<()> {
+        let db = self.db.clone();
+
+        unblock(move || {
+            let mut batch = WriteBatch::new();
+
+            batch_put_raw(&mut batch, &cid, &data);
+
+            db.lock().unwrap().write(batch, false)?;
+
+            Ok(())
+        })
+        .await
     }
 
-    async fn get(&mut self, cid: libipld::Cid) -> Result<Option<Mime>> {
-        let mut db = self.db.lock().unwrap();
+    async fn delete_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        let db = self.db.clone();
 
-        let mime = db.get(&cid.to_bytes());
+        unblock(move || {
+            let mut db = db.lock().unwrap();
 
-        if let Some(mime) = mime {
-            return Ok(DagCborCodec.decode(&mime)?);
-        } else {
-            return Ok(None);
+            let key = keccack256(&cid.to_bytes());
+
+            db.delete(&key)?;
+
+            let key = cid.to_bytes();
+
+            let mime = db.get(&key);
+
+            db.delete(&key)?;
+
+            Ok(mime)
+        })
+        .await
+    }
+
+    async fn put_many(&self, mimes: Vec<Mime>) -> Result<Vec<Cid>> {
+        let db = self.db.clone();
+        let options = self.options;
+
+        unblock(move || {
+            let mut batch = WriteBatch::new();
+
+            let cids = mimes
+                .iter()
+                .map(|mime| batch_put(&options, &mut batch, mime))
+                .collect::<Result<Vec<_>>>()?;
+
+            db.lock().unwrap().write(batch, false)?;
+
+            Ok(cids)
+        })
+        .await
+    }
+
+    async fn put_raw_many(&self, objects: Vec<(Cid, Vec<u8>)>) -> Result<()> {
+        for (cid, data) in &objects {
+            codec::verify(cid, data)?;
         }
+
+        let db = self.db.clone();
+
+        unblock(move || {
+            let mut batch = WriteBatch::new();
+
+            for (cid, data) in &objects {
+                batch_put_raw(&mut batch, cid, data);
+            }
+
+            db.lock().unwrap().write(batch, false)?;
+
+            Ok(())
+        })
+        .await
     }
 
-    async fn put(&mut self, mime: dimsp_types::Mime) -> Result<Cid> {
-        let mut db = self.db.lock().unwrap();
+    async fn get_many(&self, cids: Vec<Cid>) -> Result<Vec<Option<Mime>>> {
+        let db = self.db.clone();
+        let options = self.options;
 
-        let data = DagCborCodec.encode(&mime)?;
+        unblock(move || {
+            let mut db = db.lock().unwrap();
 
-        let cid = Cid::new_v1(DagCborCodec.into(), Code::Keccak256.digest(&data));
+            cids.iter()
+                .map(|cid| decode_mime(&options, cid, db.get(&cid.to_bytes())))
+                .collect()
+        })
+        .await
+    }
 
-        let cid_bytes = cid.to_bytes();
+    async fn contains_many(&self, cids: Vec<Cid>) -> Result<Vec<bool>> {
+        let db = self.db.clone();
 
-        let key = keccack256(&cid_bytes);
+        unblock(move || {
+            let mut db = db.lock().unwrap();
 
-        db.put(&key, &[0u8; 1])?;
+            Ok(cids
+                .iter()
+                .map(|cid| db.get(&keccack256(&cid.to_bytes())).is_some())
+                .collect())
+        })
+        .await
+    }
+}
 
-        db.put(&cid_bytes, &data)?;
+#[async_trait]
+impl Snapshot for LeveldbMimeKV {
+    async fn snapshot(&self, path: PathBuf) -> Result<SnapshotManifest> {
+        let db = self.db.clone();
 
-        Ok(cid)
+        unblock(move || snapshot_db(&db, &path)).await
     }
 }
 
@@ -114,7 +318,11 @@ mod tests {
     };
     use rand::{rngs::OsRng, RngCore};
 
-    use crate::kv::MimeKV;
+    use crate::{
+        codec::{MimeCodec, MimeHash, MimeOptions},
+        error::StorageError,
+        kv::MimeKV,
+    };
 
     use super::LeveldbMimeKV;
 
@@ -124,8 +332,7 @@ mod tests {
 
         let mut buff = [0u8; 32];
         OsRng.fill_bytes(&mut buff);
-        let mut kv =
-            LeveldbMimeKV::local(env::temp_dir().join(buff.encode_hex::<String>())).unwrap();
+        let kv = LeveldbMimeKV::local(env::temp_dir().join(buff.encode_hex::<String>())).unwrap();
 
         let mime = Mime {
             id: Cid::new_v1(DagCborCodec.into(), Code::Blake2b256.digest(&b""[..])),
@@ -145,4 +352,76 @@ mod tests {
 
         log::debug!("elapsed {:?}", now.elapsed().unwrap() / 100);
     }
+
+    #[async_std::test]
+    async fn test_kv_batch() {
+        let kv = LeveldbMimeKV::memory().unwrap();
+
+        let mimes = (0..4u8)
+            .map(|i| Mime {
+                id: Cid::new_v1(DagCborCodec.into(), Code::Blake2b256.digest(&[i])),
+                length: 1,
+                content: vec![i],
+                multipart: vec![],
+            })
+            .collect::<Vec<_>>();
+
+        let cids = kv.put_many(mimes.clone()).await.unwrap();
+
+        assert_eq!(cids.len(), 4);
+
+        let mut query = cids.clone();
+
+        query.push(mimes[0].id);
+
+        assert_eq!(
+            kv.contains_many(query.clone()).await.unwrap(),
+            vec![true, true, true, true, false]
+        );
+
+        let loaded = kv.get_many(query).await.unwrap();
+
+        assert!(loaded[4].is_none());
+
+        for (mime, loaded) in mimes.iter().zip(loaded) {
+            assert_eq!(loaded.unwrap().content, mime.content);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_kv_verify_on_read() {
+        let options = MimeOptions {
+            hash: MimeHash::Blake2b256,
+            codec: MimeCodec::DagJson,
+            verify_on_read: true,
+        };
+
+        let kv = LeveldbMimeKV::memory_with_options(options).unwrap();
+
+        let mime = Mime {
+            id: Cid::new_v1(DagCborCodec.into(), Code::Blake2b256.digest(&b""[..])),
+            length: 1,
+            content: vec![1],
+            multipart: vec![],
+        };
+
+        let cid = kv.put(mime.clone()).await.unwrap();
+
+        assert_eq!(cid.codec(), u64::from(MimeCodec::DagJson));
+        assert_eq!(cid.hash().code(), u64::from(Code::Blake2b256));
+
+        assert_eq!(kv.get(cid).await.unwrap().unwrap().content, mime.content);
+
+        // overwrite stored content with another object.
+        let (_, rotten) = options.encode(&Mime { length: 2, ..mime }).unwrap();
+
+        kv.db.lock().unwrap().put(&cid.to_bytes(), &rotten).unwrap();
+
+        let err = kv.get(cid).await.unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::Integrity(expected, _)) if *expected == cid
+        ));
+    }
 }
@@ -0,0 +1,142 @@
+//! Schema version record and migration chain of leveldb backends.
+
+use anyhow::Result;
+use rusty_leveldb::{LdbIterator, WriteBatch, DB};
+
+use crate::error::StorageError;
+
+/// Reserved key of the schema version record, never a valid cid, offset or account key.
+pub const SCHEMA_VERSION_KEY: &[u8] = b"__dimsp_schema_version";
+
+/// One schema upgrade step, from `version - 1` to `version`.
+///
+/// A step that fails half way is run again on next open, so it must be idempotent.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub migrate: fn(&mut DB) -> Result<()>,
+}
+
+/// Returns schema version record of `db`, [`None`] for databases created before versioning.
+pub fn schema_version(db: &mut DB) -> Result<Option<u32>> {
+    match db.get(SCHEMA_VERSION_KEY) {
+        Some(buff) => Ok(Some(u32::from_be_bytes(buff.as_slice().try_into()?))),
+        None => Ok(None),
+    }
+}
+
+fn save_schema_version(db: &mut DB, version: u32) -> Result<()> {
+    let mut batch = WriteBatch::new();
+
+    batch.put(SCHEMA_VERSION_KEY, &version.to_be_bytes());
+
+    db.write(batch, true)?;
+
+    Ok(())
+}
+
+/// Bring `db` of `backend` up to the last version of ordered `migrations` chain.
+///
+/// New databases are stamped with the last version, unversioned ones start from version 0,
+/// and databases written by a newer release are refused.
+pub(crate) fn open_schema(db: &mut DB, backend: &str, migrations: &[Migration]) -> Result<u32> {
+    let latest = migrations.last().map(|m| m.version).unwrap_or(0);
+
+    let current = match schema_version(db)? {
+        Some(version) if version > latest => {
+            return Err(StorageError::SchemaTooNew(backend.to_owned(), version, latest).into());
+        }
+        Some(version) => version,
+        None if !db.new_iter()?.advance() => {
+            save_schema_version(db, latest)?;
+            return Ok(latest);
+        }
+        None => 0,
+    };
+
+    for migration in migrations.iter().filter(|m| m.version > current) {
+        log::info!(
+            "Migrate {} schema to version({}), {}",
+            backend,
+            migration.version,
+            migration.name
+        );
+
+        (migration.migrate)(db)?;
+
+        save_schema_version(db, migration.version)?;
+    }
+
+    Ok(latest)
+}
+
+/// Adopt the layout written before schema versioning, nothing to convert.
+pub(crate) fn adopt_unversioned(_: &mut DB) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use rusty_leveldb::DB;
+
+    use crate::error::StorageError;
+
+    use super::{open_schema, schema_version, Migration};
+
+    fn step1(db: &mut DB) -> Result<()> {
+        Ok(db.put(b"step1", b"")?)
+    }
+
+    fn step2(db: &mut DB) -> Result<()> {
+        if db.get(b"fail").is_some() {
+            return Err(anyhow::format_err!("interrupted"));
+        }
+
+        Ok(db.put(b"step2", b"")?)
+    }
+
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            name: "step1",
+            migrate: step1,
+        },
+        Migration {
+            version: 2,
+            name: "step2",
+            migrate: step2,
+        },
+    ];
+
+    #[test]
+    fn test_schema() {
+        let mut db = DB::open("::memory::", rusty_leveldb::in_memory()).unwrap();
+
+        // new database skips migrations.
+        assert_eq!(open_schema(&mut db, "test", MIGRATIONS).unwrap(), 2);
+        assert!(db.get(b"step1").is_none());
+
+        let mut db = DB::open("::memory::", rusty_leveldb::in_memory()).unwrap();
+
+        db.put(b"legacy", b"").unwrap();
+        db.put(b"fail", b"").unwrap();
+
+        // interrupted chain keeps finished steps.
+        assert!(open_schema(&mut db, "test", MIGRATIONS).is_err());
+        assert_eq!(schema_version(&mut db).unwrap(), Some(1));
+
+        db.delete(b"fail").unwrap();
+
+        assert_eq!(open_schema(&mut db, "test", MIGRATIONS).unwrap(), 2);
+        assert!(db.get(b"step2").is_some());
+
+        // newer schema is refused.
+        let err = open_schema(&mut db, "test", &MIGRATIONS[..1]).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::SchemaTooNew(_, 2, 1))
+        ));
+    }
+}
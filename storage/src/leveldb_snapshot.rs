@@ -0,0 +1,465 @@
+//! LevelDB snapshot copy and restore.
+//!
+//! A snapshot directory holds the copied database in `db` and its [`SnapshotManifest`] in `manifest.json`.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use rusty_leveldb::{DBIterator, LdbIterator, Options, WriteBatch, DB};
+
+use crate::{error::StorageError, snapshot::SnapshotManifest};
+
+const SNAPSHOT_DB: &str = "db";
+const SNAPSHOT_MANIFEST: &str = "manifest.json";
+
+/// Number of records copied per database lock acquisition.
+const SNAPSHOT_CHUNK: usize = 1024;
+
+/// Open the snapshot database in `path`, which must be missing or empty.
+fn create_target(path: &Path) -> Result<DB> {
+    if path.exists() && fs::read_dir(path)?.next().is_some() {
+        return Err(StorageError::SnapshotTarget(path.display().to_string()).into());
+    }
+
+    fs::create_dir_all(path)?;
+
+    Ok(DB::open(path.join(SNAPSHOT_DB), Options::default())?)
+}
+
+/// Drop `snapshot` of `db` while holding the database lock, snapshots share state with it.
+fn release<S>(db: &Mutex<DB>, snapshot: S) {
+    let _db = db.lock().unwrap();
+
+    drop(snapshot);
+}
+
+/// Copy records of a snapshot from live `db` into `target`, then write the manifest next to it.
+///
+/// `iter_at` opens an iterator at the snapshot. Records are read in chunks, so writers only wait
+/// for one chunk at a time.
+fn copy_snapshot<F>(
+    db: &Mutex<DB>,
+    iter_at: F,
+    mut target: DB,
+    path: &Path,
+) -> Result<SnapshotManifest>
+where
+    F: Fn(&mut DB) -> rusty_leveldb::Result<DBIterator>,
+{
+    let mut manifest = SnapshotManifest::default();
+    let mut last_key: Option<Vec<u8>> = None;
+    let mut key = vec![];
+    let mut value = vec![];
+
+    loop {
+        let mut batch = WriteBatch::new();
+
+        let done = {
+            let mut db = db.lock().unwrap();
+
+            let mut iter = iter_at(&mut db)?;
+
+            let mut valid = match &last_key {
+                // seek stops at the last copied key itself.
+                Some(last) => {
+                    iter.seek(last);
+                    iter.valid() && iter.advance()
+                }
+                None => iter.advance(),
+            };
+
+            let mut copied = 0;
+
+            while valid && copied < SNAPSHOT_CHUNK {
+                iter.current(&mut key, &mut value);
+
+                batch.put(&key, &value);
+                manifest.add(&key, &value);
+
+                copied += 1;
+                valid = iter.advance();
+            }
+
+            last_key = Some(key.clone());
+
+            !valid
+        };
+
+        target.write(batch, false)?;
+
+        if done {
+            break;
+        }
+    }
+
+    target.flush()?;
+
+    fs::write(path.join(SNAPSHOT_MANIFEST), serde_json::to_vec(&manifest)?)?;
+
+    Ok(manifest)
+}
+
+/// Copy point-in-time state of live `db` into new snapshot directory `path`.
+pub(crate) fn snapshot_db(db: &Mutex<DB>, path: &Path) -> Result<SnapshotManifest> {
+    let target = create_target(path)?;
+
+    let snapshot = db.lock().unwrap().get_snapshot();
+
+    let manifest = copy_snapshot(db, |db| db.new_iter_at(snapshot.clone()), target, path);
+
+    release(db, snapshot);
+
+    manifest
+}
+
+/// Snapshot both stores into `dir/kv` and `dir/timeline` at one point in time.
+///
+/// Both leveldb snapshots are taken while writers of both stores wait, so every timeline entry of
+/// the copy resolves in the kv copy as long as mimes are put before they are appended.
+#[cfg(all(feature = "leveldb_kv", feature = "leveldb_timeline"))]
+pub async fn snapshot_storage(
+    kv: &crate::leveldb_kv::LeveldbMimeKV,
+    timeline: &crate::leveldb_timeline::LeveldbTimeline,
+    dir: PathBuf,
+) -> Result<()> {
+    let kv = kv.db.clone();
+    let timeline = timeline.db.clone();
+
+    crate::blocking::unblock(move || {
+        let kv_path = dir.join("kv");
+        let timeline_path = dir.join("timeline");
+
+        let kv_target = create_target(&kv_path)?;
+        let timeline_target = create_target(&timeline_path)?;
+
+        let (kv_snapshot, timeline_snapshot) = {
+            let mut kv = kv.lock().unwrap();
+            let mut timeline = timeline.lock().unwrap();
+
+            (kv.get_snapshot(), timeline.get_snapshot())
+        };
+
+        let copied = copy_snapshot(
+            &kv,
+            |db| db.new_iter_at(kv_snapshot.clone()),
+            kv_target,
+            &kv_path,
+        )
+        .and_then(|kv| {
+            copy_snapshot(
+                &timeline,
+                |db| db.new_iter_at(timeline_snapshot.clone()),
+                timeline_target,
+                &timeline_path,
+            )
+            .map(|timeline| (kv, timeline))
+        });
+
+        release(&kv, kv_snapshot);
+        release(&timeline, timeline_snapshot);
+
+        let (kv, timeline) = copied?;
+
+        log::info!(
+            "Snapshot storage, kv records({}), timeline records({})",
+            kv.records,
+            timeline.records
+        );
+
+        Ok(())
+    })
+    .await
+}
+
+/// Recompute manifest of leveldb database at `path`.
+fn scan_db(path: &Path) -> Result<SnapshotManifest> {
+    let mut db = DB::open(path, Options::default())?;
+
+    let mut iter = db.new_iter()?;
+
+    let mut manifest = SnapshotManifest::default();
+    let mut key = vec![];
+    let mut value = vec![];
+
+    while iter.advance() {
+        iter.current(&mut key, &mut value);
+        manifest.add(&key, &value);
+    }
+
+    Ok(manifest)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+
+        fs::copy(entry.path(), to.join(entry.file_name()))?;
+    }
+
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+
+    path.push(suffix);
+
+    path.into()
+}
+
+/// Copy snapshot database of `snapshot` next to `live` and check it against the snapshot manifest.
+///
+/// Returns the path of the checked copy.
+fn stage(snapshot: &Path, live: &Path) -> Result<PathBuf> {
+    let manifest: SnapshotManifest =
+        serde_json::from_slice(&fs::read(snapshot.join(SNAPSHOT_MANIFEST))?)?;
+
+    let staged = with_suffix(live, ".restore");
+
+    if staged.exists() {
+        fs::remove_dir_all(&staged)?;
+    }
+
+    copy_dir(&snapshot.join(SNAPSHOT_DB), &staged)?;
+
+    let actual = scan_db(&staged)?;
+
+    if actual != manifest {
+        fs::remove_dir_all(&staged)?;
+
+        return Err(StorageError::SnapshotIntegrity(format!(
+            "{} has {} records, manifest expects {}",
+            snapshot.display(),
+            actual.records,
+            manifest.records
+        ))
+        .into());
+    }
+
+    Ok(staged)
+}
+
+/// Replace `live` database directory with checked copy `staged`, keeping the old one in `.old`.
+fn swap(staged: &Path, live: &Path) -> Result<()> {
+    let old = with_suffix(live, ".old");
+
+    if old.exists() {
+        fs::remove_dir_all(&old)?;
+    }
+
+    if live.exists() {
+        fs::rename(live, &old)?;
+    }
+
+    if let Err(err) = fs::rename(staged, live) {
+        if old.exists() {
+            fs::rename(&old, live)?;
+        }
+
+        return Err(err.into());
+    }
+
+    Ok(())
+}
+
+/// Undo [`swap`], moving `live` back to `staged` and the old directory back to `live`.
+fn unswap(staged: &Path, live: &Path) -> Result<()> {
+    let old = with_suffix(live, ".old");
+
+    fs::rename(live, staged)?;
+
+    if old.exists() {
+        fs::rename(&old, live)?;
+    }
+
+    Ok(())
+}
+
+/// Swap every `(staged, live)` pair, either all live directories are replaced or none.
+fn replace_all(pairs: &[(PathBuf, PathBuf)]) -> Result<()> {
+    for (index, (staged, live)) in pairs.iter().enumerate() {
+        if let Err(err) = swap(staged, live) {
+            for (staged, live) in pairs[..index].iter().rev() {
+                if let Err(err) = unswap(staged, live) {
+                    log::error!("Undo restore of {}, {}", live.display(), err);
+                }
+            }
+
+            return Err(err);
+        }
+    }
+
+    for (_, live) in pairs {
+        let old = with_suffix(live, ".old");
+
+        if old.exists() {
+            fs::remove_dir_all(&old)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Restore `live` database from `snapshot` directory.
+///
+/// The live database must be closed, it is only replaced after the copy passes integrity check.
+pub fn restore<S: Into<PathBuf>, L: Into<PathBuf>>(snapshot: S, live: L) -> Result<()> {
+    let live = live.into();
+
+    let staged = stage(&snapshot.into(), &live)?;
+
+    replace_all(&[(staged, live)])
+}
+
+/// Restore both stores from snapshot `dir` created by [`snapshot_storage`].
+///
+/// Both copies are staged and checked first, then swapped in together: if the second swap fails
+/// the first one is undone, so the stores never come from different snapshots.
+pub fn restore_storage<D, K, T>(dir: D, kv: K, timeline: T) -> Result<()>
+where
+    D: Into<PathBuf>,
+    K: Into<PathBuf>,
+    T: Into<PathBuf>,
+{
+    let dir = dir.into();
+    let kv = kv.into();
+    let timeline = timeline.into();
+
+    let staged_kv = stage(&dir.join("kv"), &kv)?;
+
+    let staged_timeline = match stage(&dir.join("timeline"), &timeline) {
+        Ok(staged) => staged,
+        Err(err) => {
+            fs::remove_dir_all(&staged_kv)?;
+            return Err(err);
+        }
+    };
+
+    replace_all(&[(staged_kv, kv), (staged_timeline, timeline)])
+}
+
+#[cfg(all(test, feature = "leveldb_kv", feature = "leveldb_timeline"))]
+mod tests {
+    use std::{env, fs};
+
+    use dimsp_types::{MNSAccount, Mime};
+    use hex::ToHex;
+    use libipld::{
+        cbor::DagCborCodec,
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+    use rand::{rngs::OsRng, RngCore};
+
+    use crate::{
+        error::StorageError, kv::MimeKV, leveldb_kv::LeveldbMimeKV,
+        leveldb_timeline::LeveldbTimeline, timeline::Timeline,
+    };
+
+    use super::{replace_all, restore_storage, snapshot_storage, SNAPSHOT_MANIFEST};
+
+    #[async_std::test]
+    async fn test_snapshot_restore() {
+        let mut buff = [0u8; 32];
+        OsRng.fill_bytes(&mut buff);
+        let root = env::temp_dir().join(buff.encode_hex::<String>());
+
+        let kv = LeveldbMimeKV::local(root.join("kv")).unwrap();
+        let timeline = LeveldbTimeline::local(root.join("timeline")).unwrap();
+
+        let mns = MNSAccount::default();
+
+        let mime = Mime {
+            id: Cid::new_v1(DagCborCodec.into(), Code::Blake2b256.digest(&b""[..])),
+            length: 1,
+            content: vec![1],
+            multipart: vec![],
+        };
+
+        let cid = kv.put(mime.clone()).await.unwrap();
+        timeline.append(mns.clone(), cid).await.unwrap();
+
+        snapshot_storage(&kv, &timeline, root.join("snapshot"))
+            .await
+            .unwrap();
+
+        // snapshots never merge into an existing one.
+        let err = snapshot_storage(&kv, &timeline, root.join("snapshot"))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::SnapshotTarget(_))
+        ));
+
+        // changes after snapshot are dropped by restore.
+        let later = kv.put(Mime { length: 2, ..mime }).await.unwrap();
+        timeline.append(mns.clone(), later).await.unwrap();
+
+        drop(kv);
+        drop(timeline);
+
+        restore_storage(
+            root.join("snapshot"),
+            root.join("kv_restored"),
+            root.join("timeline_restored"),
+        )
+        .unwrap();
+
+        let kv = LeveldbMimeKV::local(root.join("kv_restored")).unwrap();
+        let timeline = LeveldbTimeline::local(root.join("timeline_restored")).unwrap();
+
+        assert!(kv.contains_cid(cid).await.unwrap());
+        assert!(!kv.contains_cid(later).await.unwrap());
+        assert_eq!(timeline.get(mns, 10).await.unwrap(), vec![cid]);
+
+        // tampered manifest is rejected before anything is replaced.
+        let manifest = root.join("snapshot").join("kv").join(SNAPSHOT_MANIFEST);
+        fs::write(&manifest, br#"{"records":0,"digest":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]}"#).unwrap();
+
+        let err = restore_storage(
+            root.join("snapshot"),
+            root.join("kv_restored2"),
+            root.join("timeline_restored2"),
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::SnapshotIntegrity(_))
+        ));
+
+        assert!(!root.join("timeline_restored2").exists());
+    }
+
+    #[test]
+    fn test_replace_all_undo() {
+        let mut buff = [0u8; 32];
+        OsRng.fill_bytes(&mut buff);
+        let root = env::temp_dir().join(buff.encode_hex::<String>());
+
+        fs::create_dir_all(root.join("kv")).unwrap();
+        fs::create_dir_all(root.join("kv.restore")).unwrap();
+        fs::write(root.join("kv").join("live"), b"").unwrap();
+
+        // the second swap fails on a missing staged copy, the first one is undone.
+        replace_all(&[
+            (root.join("kv.restore"), root.join("kv")),
+            (root.join("timeline.restore"), root.join("timeline")),
+        ])
+        .unwrap_err();
+
+        assert!(root.join("kv").join("live").exists());
+        assert!(root.join("kv.restore").exists());
+        assert!(!root.join("kv.old").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
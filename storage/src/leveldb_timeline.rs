@@ -2,38 +2,125 @@ use anyhow::Result;
 use async_trait::async_trait;
 use dimsp_types::MNSAccount;
 use libipld::Cid;
-use rusty_leveldb::DB;
+use rusty_leveldb::{LdbIterator, WriteBatch, DB};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::BTreeMap,
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap},
     ops::Range,
     path::PathBuf,
     sync::{Arc, Mutex, MutexGuard},
 };
 
-use crate::timeline::Timeline;
+use crate::{
+    blocking::unblock,
+    leveldb_envelope::reindex_envelopes,
+    leveldb_schema::{adopt_unversioned, open_schema, Migration},
+    leveldb_snapshot::snapshot_db,
+    snapshot::{Snapshot, SnapshotManifest},
+    timeline::Timeline,
+};
+
+/// Number of account lock shards, accounts in different shards never wait on each other.
+const ACCOUNT_SHARDS: u64 = 64;
+
+/// Key prefix of account records.
+const ACCOUNT_PREFIX: &str = "account/";
+
+/// Timeline layout migrations, ordered by version.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "adopt unversioned layout",
+        migrate: adopt_unversioned,
+    },
+    Migration {
+        version: 2,
+        name: "prefix account records",
+        migrate: prefix_accounts,
+    },
+    Migration {
+        version: 3,
+        name: "newest first envelope columns",
+        migrate: reindex_envelopes,
+    },
+];
+
+/// Seals account records, entries and envelope records of [`LeveldbTimeline`] at rest.
+pub trait RecordCipher: Send + Sync {
+    fn seal(&self, mns_id: u64, record: &[u8]) -> Result<Vec<u8>>;
+
+    fn open(&self, mns_id: u64, sealed: &[u8]) -> Result<Vec<u8>>;
+
+    /// Returns deterministic keyed token of `value`, stands in for searchable values in index keys.
+    ///
+    /// Must not change with key rotation, or indexed keys can't be found anymore.
+    fn token(&self, mns_id: u64, value: &[u8]) -> Result<Vec<u8>>;
+}
 
+#[derive(Clone)]
 pub struct LeveldbTimeline {
-    db: Arc<Mutex<rusty_leveldb::DB>>,
+    pub(crate) db: Arc<Mutex<rusty_leveldb::DB>>,
+    pub(crate) accounts: Arc<Vec<Mutex<()>>>,
+    pub(crate) cipher: Option<Arc<dyn RecordCipher>>,
 }
 
 impl LeveldbTimeline {
     /// Create kv in memory
     pub fn memory() -> Result<Self> {
-        let db = rusty_leveldb::DB::open("::memory::", rusty_leveldb::in_memory())?;
+        let mut db = rusty_leveldb::DB::open("::memory::", rusty_leveldb::in_memory())?;
+
+        open_schema(&mut db, "timeline", MIGRATIONS)?;
+
         Ok(Self {
             db: Arc::new(Mutex::new(db)),
+            accounts: account_shards(),
+            cipher: None,
         })
     }
 
     /// Create kv database in local storage
     pub fn local<P: Into<PathBuf>>(path: P) -> Result<Self> {
-        let db = rusty_leveldb::DB::open(path.into(), Default::default())?;
+        let mut db = rusty_leveldb::DB::open(path.into(), Default::default())?;
+
+        open_schema(&mut db, "timeline", MIGRATIONS)?;
 
         Ok(Self {
             db: Arc::new(Mutex::new(db)),
+            accounts: account_shards(),
+            cipher: None,
         })
     }
+
+    /// Seal account records, entry cids and envelope records with `cipher`, and key the envelope
+    /// index by its tokens. Data written without it can't be read anymore.
+    pub fn with_cipher(mut self, cipher: Arc<dyn RecordCipher>) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+}
+
+fn account_shards() -> Arc<Vec<Mutex<()>>> {
+    Arc::new((0..ACCOUNT_SHARDS).map(|_| Mutex::new(())).collect())
+}
+
+/// Serialize read-modify-write of one account record.
+///
+/// The database lock itself is only held for single get/put calls.
+pub(crate) fn lock_account(accounts: &[Mutex<()>], mns_id: u64) -> MutexGuard<'_, ()> {
+    accounts[(mns_id % ACCOUNT_SHARDS) as usize].lock().unwrap()
+}
+
+/// Lock the shards of all `mns_ids` in shard order, so batch writers never deadlock each other.
+fn lock_accounts<'a>(accounts: &'a [Mutex<()>], mns_ids: &[u64]) -> Vec<MutexGuard<'a, ()>> {
+    let shards = mns_ids
+        .iter()
+        .map(|id| id % ACCOUNT_SHARDS)
+        .collect::<BTreeSet<_>>();
+
+    shards
+        .into_iter()
+        .map(|shard| accounts[shard as usize].lock().unwrap())
+        .collect()
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -57,11 +144,7 @@ impl Account {
             client_offset
         };
 
-        let end = if start + n > self.end {
-            self.end
-        } else {
-            start + n
-        };
+        let end = start.saturating_add(n).min(self.end);
 
         start..end
     }
@@ -96,40 +179,138 @@ impl Account {
 
         self.end - start
     }
+
+    fn set_cursor(&mut self, mns: &MNSAccount, offset: u64) {
+        let to = offset.max(self.start).min(self.end);
+
+        self.clients.insert(mns.client_id.to_string(), to);
+    }
 }
 
-fn get_account(db: &mut MutexGuard<DB>, mns: &MNSAccount) -> Account {
-    if let Some(buff) = db.get(&mns.uns.id.to_be_bytes()) {
-        serde_json::from_str(&String::from_utf8_lossy(&buff)).unwrap()
-    } else {
-        Default::default()
+fn account_key(mns_id: u64) -> String {
+    format!("{}{:020}", ACCOUNT_PREFIX, mns_id)
+}
+
+/// Move account records keyed by the bare be bytes id of version 1 under [`ACCOUNT_PREFIX`].
+fn prefix_accounts(db: &mut DB) -> Result<()> {
+    let mut batch = WriteBatch::new();
+
+    {
+        let mut iter = db.new_iter()?;
+
+        let mut key = vec![];
+        let mut value = vec![];
+
+        while iter.advance() {
+            iter.current(&mut key, &mut value);
+
+            // offset keys are strings, version 1 account records are json objects keyed by be bytes id.
+            if key.len() != 8 || serde_json::from_slice::<Account>(&value).is_err() {
+                continue;
+            }
+
+            let mns_id = u64::from_be_bytes(key.as_slice().try_into()?);
+
+            batch.put(account_key(mns_id).as_bytes(), &value);
+            batch.delete(&key);
+        }
     }
+
+    db.write(batch, true)?;
+
+    Ok(())
 }
 
-fn save_account(db: &mut MutexGuard<DB>, mns: &MNSAccount, account: &Account) -> Result<()> {
+fn get_account(db: &Mutex<DB>, cipher: Option<&dyn RecordCipher>, mns_id: u64) -> Result<Account> {
+    let Some(buff) = db.lock().unwrap().get(account_key(mns_id).as_bytes()) else {
+        return Ok(Default::default());
+    };
+
+    let buff = match cipher {
+        Some(cipher) => cipher.open(mns_id, &buff)?,
+        None => buff,
+    };
+
+    Ok(serde_json::from_slice(&buff)?)
+}
+
+fn encode_account(
+    cipher: Option<&dyn RecordCipher>,
+    mns_id: u64,
+    account: &Account,
+) -> Result<Vec<u8>> {
     let json_str = serde_json::to_string(&account)?;
 
     log::debug!("{}", json_str);
 
-    db.put(&mns.uns.id.to_be_bytes(), json_str.as_bytes())?;
+    match cipher {
+        Some(cipher) => cipher.seal(mns_id, json_str.as_bytes()),
+        None => Ok(json_str.into_bytes()),
+    }
+}
+
+fn save_account(
+    db: &Mutex<DB>,
+    cipher: Option<&dyn RecordCipher>,
+    mns_id: u64,
+    account: &Account,
+) -> Result<()> {
+    let buff = encode_account(cipher, mns_id, account)?;
+
+    db.lock()
+        .unwrap()
+        .put(account_key(mns_id).as_bytes(), &buff)?;
 
     Ok(())
 }
 
-fn save_cid(db: &mut MutexGuard<DB>, mns: &MNSAccount, offset: u64, cid: Cid) -> Result<()> {
-    let key = format!("{}_{}", mns.uns.id, offset);
-    db.put(key.as_bytes(), &cid.to_bytes())?;
+fn cid_key(mns_id: u64, offset: u64) -> String {
+    format!("{}_{}", mns_id, offset)
+}
+
+fn encode_cid(cipher: Option<&dyn RecordCipher>, mns_id: u64, cid: &Cid) -> Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher.seal(mns_id, &cid.to_bytes()),
+        None => Ok(cid.to_bytes()),
+    }
+}
+
+fn save_cid(
+    db: &Mutex<DB>,
+    cipher: Option<&dyn RecordCipher>,
+    mns_id: u64,
+    offset: u64,
+    cid: Cid,
+) -> Result<()> {
+    let key = cid_key(mns_id, offset);
+    let buff = encode_cid(cipher, mns_id, &cid)?;
+
+    db.lock().unwrap().put(key.as_bytes(), &buff)?;
 
     Ok(())
 }
 
-fn get_cid(db: &mut MutexGuard<DB>, mns: &MNSAccount, offset: u64) -> Result<Cid> {
-    let key = format!("{}_{}", mns.uns.id, offset);
-    let buff = db.get(key.as_bytes()).ok_or(anyhow::format_err!(
-        "Inner constraint: miss mns({}) offset({})",
-        mns.uns.id,
-        offset
-    ))?;
+fn get_cid(
+    db: &Mutex<DB>,
+    cipher: Option<&dyn RecordCipher>,
+    mns_id: u64,
+    offset: u64,
+) -> Result<Cid> {
+    let key = cid_key(mns_id, offset);
+    let buff = db
+        .lock()
+        .unwrap()
+        .get(key.as_bytes())
+        .ok_or(anyhow::format_err!(
+            "Inner constraint: miss mns({}) offset({})",
+            mns_id,
+            offset
+        ))?;
+
+    let buff = match cipher {
+        Some(cipher) => cipher.open(mns_id, &buff)?,
+        None => buff,
+    };
 
     Ok(Cid::try_from(buff)?)
 }
@@ -137,57 +318,227 @@ fn get_cid(db: &mut MutexGuard<DB>, mns: &MNSAccount, offset: u64) -> Result<Cid
 #[async_trait]
 impl Timeline for LeveldbTimeline {
     /// Append cid into account's timeline column.
-    async fn append(&mut self, mns: MNSAccount, cid: Cid) -> Result<()> {
-        let mut db = self.db.lock().unwrap();
+    async fn append(&self, mns: MNSAccount, cid: Cid) -> Result<()> {
+        let db = self.db.clone();
+        let accounts = self.accounts.clone();
+        let cipher = self.cipher.clone();
+
+        unblock(move || {
+            let _account = lock_account(&accounts, mns.uns.id);
 
-        let mut account = get_account(&mut db, &mns);
+            let mut account = get_account(&db, cipher.as_deref(), mns.uns.id)?;
 
-        let offset = account.end;
+            let offset = account.end;
 
-        account.end += 1;
+            account.end += 1;
 
-        save_cid(&mut db, &mns, offset, cid)?;
+            save_cid(&db, cipher.as_deref(), mns.uns.id, offset, cid)?;
 
-        save_account(&mut db, &mns, &account)?;
+            save_account(&db, cipher.as_deref(), mns.uns.id, &account)?;
 
-        Ok(())
+            Ok(())
+        })
+        .await
+    }
+
+    /// Append all entries with one write batch, either every entry is stored or none.
+    async fn append_many(&self, entries: Vec<(MNSAccount, Cid)>) -> Result<()> {
+        let db = self.db.clone();
+        let accounts = self.accounts.clone();
+        let cipher = self.cipher.clone();
+
+        unblock(move || {
+            let mns_ids = entries
+                .iter()
+                .map(|(mns, _)| mns.uns.id)
+                .collect::<Vec<_>>();
+
+            let _accounts = lock_accounts(&accounts, &mns_ids);
+
+            let mut changed = HashMap::new();
+            let mut batch = WriteBatch::new();
+
+            for (mns, cid) in &entries {
+                let mns_id = mns.uns.id;
+
+                let account = match changed.entry(mns_id) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        entry.insert(get_account(&db, cipher.as_deref(), mns_id)?)
+                    }
+                };
+
+                batch.put(
+                    cid_key(mns_id, account.end).as_bytes(),
+                    &encode_cid(cipher.as_deref(), mns_id, cid)?,
+                );
+
+                account.end += 1;
+            }
+
+            for (mns_id, account) in &changed {
+                batch.put(
+                    account_key(*mns_id).as_bytes(),
+                    &encode_account(cipher.as_deref(), *mns_id, account)?,
+                );
+            }
+
+            db.lock().unwrap().write(batch, false)?;
+
+            Ok(())
+        })
+        .await
     }
 
     /// Get account's first n cids.
-    async fn get(&mut self, mns: MNSAccount, first_n: u64) -> Result<Vec<Cid>> {
-        let mut db = self.db.lock().unwrap();
+    async fn get(&self, mns: MNSAccount, first_n: u64) -> Result<Vec<Cid>> {
+        let db = self.db.clone();
+        let accounts = self.accounts.clone();
+        let cipher = self.cipher.clone();
 
-        let account = get_account(&mut db, &mns);
+        unblock(move || {
+            let _account = lock_account(&accounts, mns.uns.id);
 
-        let mut cids = vec![];
+            let account = get_account(&db, cipher.as_deref(), mns.uns.id)?;
 
-        for i in account.first_n(&mns, first_n as u64) {
-            cids.push(get_cid(&mut db, &mns, i)?);
-        }
+            let mut cids = vec![];
+
+            for i in account.first_n(&mns, first_n) {
+                cids.push(get_cid(&db, cipher.as_deref(), mns.uns.id, i)?);
+            }
 
-        Ok(cids)
+            Ok(cids)
+        })
+        .await
     }
 
     /// Move timeline cursor to next `n` cid.
     /// if out of range, the cursor will be set to the end of timeline.
-    async fn advance(&mut self, mns: MNSAccount, steps: u64) -> Result<u64> {
-        let mut db = self.db.lock().unwrap();
+    async fn advance(&self, mns: MNSAccount, steps: u64) -> Result<u64> {
+        let db = self.db.clone();
+        let accounts = self.accounts.clone();
+        let cipher = self.cipher.clone();
+
+        unblock(move || {
+            let _account = lock_account(&accounts, mns.uns.id);
 
-        let mut account = get_account(&mut db, &mns);
+            let mut account = get_account(&db, cipher.as_deref(), mns.uns.id)?;
 
-        let length = account.advance(&mns, steps);
+            let length = account.advance(&mns, steps);
 
-        save_account(&mut db, &mns, &account)?;
+            save_account(&db, cipher.as_deref(), mns.uns.id, &account)?;
 
-        Ok(length)
+            Ok(length)
+        })
+        .await
     }
 
-    async fn length(&mut self, mns: MNSAccount) -> Result<u64> {
-        let mut db = self.db.lock().unwrap();
+    async fn length(&self, mns: MNSAccount) -> Result<u64> {
+        let db = self.db.clone();
+        let accounts = self.accounts.clone();
+        let cipher = self.cipher.clone();
+
+        unblock(move || {
+            let _account = lock_account(&accounts, mns.uns.id);
+
+            let mut account = get_account(&db, cipher.as_deref(), mns.uns.id)?;
+
+            Ok(account.length_of(&mns))
+        })
+        .await
+    }
+
+    async fn accounts(&self) -> Result<Vec<u64>> {
+        let db = self.db.clone();
+
+        unblock(move || {
+            let mut iter = db.lock().unwrap().new_iter()?;
+
+            let mut key = vec![];
+            let mut value = vec![];
+            let mut ids = vec![];
+
+            iter.seek(ACCOUNT_PREFIX.as_bytes());
+
+            while iter.valid() {
+                iter.current(&mut key, &mut value);
+
+                let Some(id) = key.strip_prefix(ACCOUNT_PREFIX.as_bytes()) else {
+                    break;
+                };
+
+                ids.push(String::from_utf8_lossy(id).parse()?);
+
+                if !iter.advance() {
+                    break;
+                }
+            }
+
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn entries(&self, mns_id: u64) -> Result<Vec<(u64, Cid)>> {
+        let db = self.db.clone();
+        let accounts = self.accounts.clone();
+        let cipher = self.cipher.clone();
+
+        unblock(move || {
+            let _account = lock_account(&accounts, mns_id);
+
+            let account = get_account(&db, cipher.as_deref(), mns_id)?;
+
+            (account.start..account.end)
+                .map(|offset| Ok((offset, get_cid(&db, cipher.as_deref(), mns_id, offset)?)))
+                .collect()
+        })
+        .await
+    }
+
+    async fn cursors(&self, mns_id: u64) -> Result<Vec<(Cid, u64)>> {
+        let db = self.db.clone();
+        let accounts = self.accounts.clone();
+        let cipher = self.cipher.clone();
+
+        unblock(move || {
+            let _account = lock_account(&accounts, mns_id);
+
+            let account = get_account(&db, cipher.as_deref(), mns_id)?;
+
+            account
+                .clients
+                .iter()
+                .map(|(client_id, offset)| Ok((Cid::try_from(client_id.as_str())?, *offset)))
+                .collect()
+        })
+        .await
+    }
+
+    async fn set_cursor(&self, mns: MNSAccount, offset: u64) -> Result<()> {
+        let db = self.db.clone();
+        let accounts = self.accounts.clone();
+        let cipher = self.cipher.clone();
+
+        unblock(move || {
+            let _account = lock_account(&accounts, mns.uns.id);
+
+            let mut account = get_account(&db, cipher.as_deref(), mns.uns.id)?;
+
+            account.set_cursor(&mns, offset);
 
-        let mut account = get_account(&mut db, &mns);
+            save_account(&db, cipher.as_deref(), mns.uns.id, &account)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl Snapshot for LeveldbTimeline {
+    async fn snapshot(&self, path: PathBuf) -> Result<SnapshotManifest> {
+        let db = self.db.clone();
 
-        Ok(account.length_of(&mns))
+        unblock(move || snapshot_db(&db, &path)).await
     }
 }
 
@@ -200,13 +551,13 @@ mod tests {
         Cid,
     };
 
-    use crate::timeline::Timeline;
+    use crate::{leveldb_schema::SCHEMA_VERSION_KEY, timeline::Timeline};
 
     use super::LeveldbTimeline;
 
     #[async_std::test]
     async fn test_timeline() {
-        let mut timeline = LeveldbTimeline::memory().unwrap();
+        let timeline = LeveldbTimeline::memory().unwrap();
 
         let mns = MNSAccount::default();
 
@@ -235,5 +586,79 @@ mod tests {
         let cids = timeline.get(mns.clone(), 4).await.unwrap();
 
         assert_eq!(cids, vec![]);
+
+        timeline
+            .append_many(vec![(mns.clone(), cid2), (mns.clone(), cid1)])
+            .await
+            .unwrap();
+
+        let cids = timeline.get(mns.clone(), 4).await.unwrap();
+
+        assert_eq!(cids, vec![cid2, cid1]);
+
+        let cids = timeline.get(mns.clone(), u64::MAX).await.unwrap();
+
+        assert_eq!(cids, vec![cid2, cid1]);
+    }
+
+    #[async_std::test]
+    async fn test_timeline_shared() {
+        let timeline = LeveldbTimeline::memory().unwrap();
+
+        let mut mns1 = MNSAccount::default();
+        mns1.uns.id = 1;
+
+        let mut mns2 = MNSAccount::default();
+        mns2.uns.id = 2;
+
+        let cid = Cid::new_v1(DagCborCodec.into(), Code::Blake2b256.digest(&b"1"[..]));
+
+        let append = |mns: MNSAccount| {
+            let timeline = &timeline;
+            async move {
+                for _ in 0..10 {
+                    timeline.append(mns.clone(), cid).await.unwrap();
+                }
+            }
+        };
+
+        futures::join!(
+            append(mns1.clone()),
+            append(mns2.clone()),
+            append(mns1.clone())
+        );
+
+        assert_eq!(timeline.length(mns1).await.unwrap(), 20);
+        assert_eq!(timeline.length(mns2).await.unwrap(), 10);
+    }
+
+    #[async_std::test]
+    async fn test_prefix_accounts() {
+        let path = std::env::temp_dir().join(format!("dimsp-timeline-{}", rand::random::<u64>()));
+
+        let cid = Cid::new_v1(DagCborCodec.into(), Code::Blake2b256.digest(&b"1"[..]));
+
+        // version 1 layout, account records keyed by be bytes id.
+        {
+            let mut db = rusty_leveldb::DB::open(path.clone(), Default::default()).unwrap();
+
+            db.put(SCHEMA_VERSION_KEY, &1u32.to_be_bytes()).unwrap();
+            db.put(&7u64.to_be_bytes(), br#"{"start":0,"end":1,"clients":{}}"#)
+                .unwrap();
+            db.put(b"7_0", &cid.to_bytes()).unwrap();
+            db.flush().unwrap();
+        }
+
+        let timeline = LeveldbTimeline::local(path.clone()).unwrap();
+
+        let mut mns = MNSAccount::default();
+        mns.uns.id = 7;
+
+        assert_eq!(timeline.accounts().await.unwrap(), vec![7]);
+        assert_eq!(timeline.get(mns, 10).await.unwrap(), vec![cid]);
+
+        drop(timeline);
+
+        std::fs::remove_dir_all(path).unwrap();
     }
 }
@@ -1,8 +1,35 @@
+pub mod blocking;
+pub mod cache;
+pub mod car;
+pub mod codec;
+pub mod envelope;
+pub mod error;
+pub mod fs_kv;
 pub mod kv;
+pub mod replicated;
+pub mod scrub;
+pub mod snapshot;
+pub mod tiered;
 pub mod timeline;
+pub mod txn;
+
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
 
 #[cfg(feature = "leveldb_kv")]
 pub mod leveldb_kv;
 
 #[cfg(feature = "leveldb_timeline")]
 pub mod leveldb_timeline;
+
+#[cfg(feature = "leveldb_timeline")]
+pub mod leveldb_envelope;
+
+#[cfg(any(feature = "leveldb_kv", feature = "leveldb_timeline"))]
+pub mod leveldb_snapshot;
+
+#[cfg(any(feature = "leveldb_kv", feature = "leveldb_timeline"))]
+pub mod leveldb_schema;
+
+#[cfg(feature = "encryption")]
+pub mod encryption;
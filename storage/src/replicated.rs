@@ -0,0 +1,550 @@
+//! [`MimeKV`] replicated over several backends with quorum writes and verified reads.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dimsp_types::Mime;
+use futures::{
+    future::{BoxFuture, Shared},
+    stream::FuturesUnordered,
+    FutureExt, StreamExt,
+};
+use libipld::Cid;
+use serde::Serialize;
+
+use crate::{
+    blocking::spawn,
+    codec::{self, MimeOptions},
+    error::StorageError,
+    kv::MimeKV,
+};
+
+/// Result of one [`ReplicatedMimeKV::repair`] pass.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RepairReport {
+    pub mimes: u64,
+    /// Replica copies written by the pass.
+    pub repaired: u64,
+    /// Replica copies found failing cid verification, included in `repaired`.
+    pub corrupted: u64,
+    /// Mime objects without any intact copy left.
+    pub unrecoverable: Vec<Cid>,
+    /// Replica reads and writes that failed, the pass skipped those replicas and went on.
+    pub failed: u64,
+    /// Pending deletes the pass finished on every replica.
+    pub deleted: u64,
+}
+
+/// Writes still running after quorum, by cid, with the generation of the write that started them.
+type InflightWrites = HashMap<Cid, (u64, Shared<BoxFuture<'static, ()>>)>;
+
+/// Write every mime object to all `replicas`, acknowledging after `write_quorum` of them stored it.
+///
+/// Writes still running after the quorum is reached finish on the storage thread pool,
+/// replicas they fail on are filled by [`repair`](ReplicatedMimeKV::repair). Deletes wait for
+/// them first, so a late write can't bring a deleted object back.
+///
+/// A delete failing on some replicas leaves a tombstone, [`repair`](ReplicatedMimeKV::repair)
+/// finishes the delete instead of copying the object back. Tombstones live in memory only.
+#[derive(Clone)]
+pub struct ReplicatedMimeKV<K> {
+    replicas: Arc<Vec<K>>,
+    write_quorum: usize,
+    options: MimeOptions,
+    inflight: Arc<Mutex<InflightWrites>>,
+    generation: Arc<AtomicU64>,
+    tombstones: Arc<Mutex<HashSet<Cid>>>,
+}
+
+impl<K> ReplicatedMimeKV<K>
+where
+    K: MimeKV + Clone + Send + Sync + 'static,
+{
+    pub fn new(replicas: Vec<K>, write_quorum: usize, options: MimeOptions) -> Result<Self> {
+        if write_quorum == 0 || write_quorum > replicas.len() {
+            return Err(anyhow::format_err!(
+                "write quorum({}) out of range 1..={}",
+                write_quorum,
+                replicas.len()
+            ));
+        }
+
+        Ok(Self {
+            replicas: Arc::new(replicas),
+            write_quorum,
+            options,
+            inflight: Default::default(),
+            generation: Default::default(),
+            tombstones: Default::default(),
+        })
+    }
+
+    async fn write(&self, cid: Cid, data: Vec<u8>) -> Result<()> {
+        // a new write of the object supersedes an unfinished delete.
+        self.tombstones.lock().unwrap().remove(&cid);
+
+        let mut writes = self
+            .replicas
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(index, replica)| {
+                let data = data.clone();
+
+                async move { (index, replica.put_raw(cid, data).await) }
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut acked = 0;
+
+        while acked < self.write_quorum {
+            match writes.next().await {
+                Some((_, Ok(()))) => acked += 1,
+                Some((index, Err(err))) => {
+                    log::warn!("Replica({}) put mime({}) failed, {}", index, cid, err)
+                }
+                None => break,
+            }
+        }
+
+        if acked < self.write_quorum {
+            return Err(StorageError::Quorum(cid, acked, self.write_quorum).into());
+        }
+
+        if !writes.is_empty() {
+            let remaining = async move {
+                while let Some((index, result)) = writes.next().await {
+                    if let Err(err) = result {
+                        log::warn!("Replica({}) put mime({}) failed, {}", index, cid, err);
+                    }
+                }
+            }
+            .boxed()
+            .shared();
+
+            let generation = self.generation.fetch_add(1, Ordering::SeqCst);
+
+            self.inflight
+                .lock()
+                .unwrap()
+                .insert(cid, (generation, remaining.clone()));
+
+            let inflight = self.inflight.clone();
+
+            spawn(async move {
+                remaining.await;
+
+                let mut inflight = inflight.lock().unwrap();
+
+                if inflight.get(&cid).map(|(g, _)| *g) == Some(generation) {
+                    inflight.remove(&cid);
+                }
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Wait for writes of `cid` still running after their quorum.
+    async fn settle(&self, cid: Cid) {
+        let remaining = self
+            .inflight
+            .lock()
+            .unwrap()
+            .get(&cid)
+            .map(|(_, remaining)| remaining.clone());
+
+        if let Some(remaining) = remaining {
+            remaining.await;
+        }
+    }
+
+    /// Read `cid` from the first replica holding an intact copy.
+    async fn read(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        let mut last_err = None;
+
+        for (index, replica) in self.replicas.iter().enumerate() {
+            match replica.get_raw(cid).await {
+                Ok(Some(data)) => match codec::verify(&cid, &data) {
+                    Ok(()) => return Ok(Some(data)),
+                    Err(err) => {
+                        log::warn!("Replica({}) mime({}) corrupted, {}", index, cid, err);
+                        last_err = Some(err);
+                    }
+                },
+                Ok(None) => {}
+                Err(err) => {
+                    log::warn!("Replica({}) get mime({}) failed, {}", index, cid, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        match last_err {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+
+    /// Union of the cids listed by every replica, with the number of replicas that failed.
+    ///
+    /// Fails only if no replica could be listed.
+    async fn list(&self) -> Result<(Vec<Cid>, u64)> {
+        let mut seen = HashSet::new();
+        let mut cids = vec![];
+        let mut failed = 0;
+        let mut last_err = None;
+
+        for (index, replica) in self.replicas.iter().enumerate() {
+            match replica.cids().await {
+                Ok(listed) => {
+                    for cid in listed {
+                        if seen.insert(cid) {
+                            cids.push(cid);
+                        }
+                    }
+                }
+                Err(err) => {
+                    log::warn!("Replica({}) list mimes failed, {}", index, err);
+                    failed += 1;
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        match last_err {
+            Some(err) if failed == self.replicas.len() as u64 => Err(err),
+            _ => Ok((cids, failed)),
+        }
+    }
+
+    /// Copy intact data of every mime object to the replicas missing it or holding a corrupted copy.
+    ///
+    /// Failing replicas are logged, counted in [`RepairReport::failed`] and skipped.
+    pub async fn repair(&self) -> Result<RepairReport> {
+        let (cids, failed) = self.list().await?;
+
+        let mut report = RepairReport {
+            failed,
+            ..Default::default()
+        };
+
+        let tombstones = self.tombstones.lock().unwrap().clone();
+
+        for cid in &tombstones {
+            match self.delete_raw(*cid).await {
+                Ok(_) => report.deleted += 1,
+                Err(_) => report.failed += 1,
+            }
+        }
+
+        for cid in cids {
+            // deleted, or still waiting for a replica to come back.
+            if tombstones.contains(&cid) {
+                continue;
+            }
+
+            report.mimes += 1;
+
+            let mut intact = None;
+            let mut broken = vec![];
+            let mut unreadable = false;
+
+            for (index, replica) in self.replicas.iter().enumerate() {
+                match replica.get_raw(cid).await {
+                    Ok(Some(data)) if codec::verify(&cid, &data).is_ok() => {
+                        intact.get_or_insert(data);
+                    }
+                    Ok(Some(_)) => {
+                        report.corrupted += 1;
+                        broken.push((index, replica));
+                    }
+                    Ok(None) => broken.push((index, replica)),
+                    Err(err) => {
+                        log::warn!("Replica({}) get mime({}) failed, {}", index, cid, err);
+                        report.failed += 1;
+                        unreadable = true;
+                    }
+                }
+            }
+
+            let data = match intact {
+                Some(data) => data,
+                // the failed replica may still hold an intact copy.
+                None if unreadable => continue,
+                None => {
+                    log::error!("Mime({}) has no intact replica", cid);
+                    report.unrecoverable.push(cid);
+                    continue;
+                }
+            };
+
+            for (index, replica) in broken {
+                match replica.put_raw(cid, data.clone()).await {
+                    Ok(()) => report.repaired += 1,
+                    Err(err) => {
+                        log::warn!("Replica({}) repair mime({}) failed, {}", index, cid, err);
+                        report.failed += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[async_trait]
+impl<K> MimeKV for ReplicatedMimeKV<K>
+where
+    K: MimeKV + Clone + Send + Sync + 'static,
+{
+    async fn put(&self, mime: Mime) -> Result<Cid> {
+        let (cid, data) = self.options.encode(&mime)?;
+
+        self.write(cid, data).await?;
+
+        Ok(cid)
+    }
+
+    async fn contains_cid(&self, cid: Cid) -> Result<bool> {
+        let mut failed = 0;
+        let mut last_err = None;
+
+        for (index, replica) in self.replicas.iter().enumerate() {
+            match replica.contains_cid(cid).await {
+                Ok(true) => return Ok(true),
+                Ok(false) => {}
+                Err(err) => {
+                    log::warn!("Replica({}) contains mime({}) failed, {}", index, cid, err);
+                    failed += 1;
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        match last_err {
+            Some(err) if failed == self.replicas.len() => Err(err),
+            _ => Ok(false),
+        }
+    }
+
+    async fn get(&self, cid: Cid) -> Result<Option<Mime>> {
+        // read already verified the copy.
+        self.read(cid)
+            .await?
+            .map(|data| codec::decode(&cid, &data))
+            .transpose()
+    }
+
+    async fn delete(&self, cid: Cid) -> Result<Option<Mime>> {
+        self.delete_raw(cid)
+            .await?
+            .map(|data| codec::decode(&cid, &data))
+            .transpose()
+    }
+
+    async fn cids(&self) -> Result<Vec<Cid>> {
+        Ok(self.list().await?.0)
+    }
+
+    async fn get_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        self.read(cid).await
+    }
+
+    async fn put_raw(&self, cid: Cid, data: Vec<u8>) -> Result<()> {
+        self.write(cid, data).await
+    }
+
+    async fn delete_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        self.tombstones.lock().unwrap().insert(cid);
+
+        self.settle(cid).await;
+
+        let mut removed = None;
+        let mut last_err = None;
+
+        // fails if any replica fails, the tombstone stays so repair retries instead of copying.
+        for (index, replica) in self.replicas.iter().enumerate() {
+            match replica.delete_raw(cid).await {
+                Ok(Some(data)) if removed.is_none() && codec::verify(&cid, &data).is_ok() => {
+                    removed = Some(data)
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    log::warn!("Replica({}) delete mime({}) failed, {}", index, cid, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        match last_err {
+            Some(err) => Err(err),
+            None => {
+                self.tombstones.lock().unwrap().remove(&cid);
+                Ok(removed)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "leveldb_kv"))]
+mod tests {
+    use dimsp_types::Mime;
+    use libipld::{
+        cbor::DagCborCodec,
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+
+    use crate::{kv::MimeKV, leveldb_kv::LeveldbMimeKV};
+
+    use super::ReplicatedMimeKV;
+
+    /// Replica failing every call while `down` is set.
+    #[derive(Clone)]
+    struct Flaky {
+        inner: LeveldbMimeKV,
+        down: Arc<AtomicBool>,
+    }
+
+    impl Flaky {
+        fn check(&self) -> Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(anyhow::format_err!("replica down"));
+            }
+
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl MimeKV for Flaky {
+        async fn put(&self, mime: Mime) -> Result<Cid> {
+            self.check()?;
+            self.inner.put(mime).await
+        }
+
+        async fn contains_cid(&self, cid: Cid) -> Result<bool> {
+            self.check()?;
+            self.inner.contains_cid(cid).await
+        }
+
+        async fn get(&self, cid: Cid) -> Result<Option<Mime>> {
+            self.check()?;
+            self.inner.get(cid).await
+        }
+
+        async fn delete(&self, cid: Cid) -> Result<Option<Mime>> {
+            self.check()?;
+            self.inner.delete(cid).await
+        }
+
+        async fn cids(&self) -> Result<Vec<Cid>> {
+            self.check()?;
+            self.inner.cids().await
+        }
+
+        async fn get_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+            self.check()?;
+            self.inner.get_raw(cid).await
+        }
+
+        async fn put_raw(&self, cid: Cid, data: Vec<u8>) -> Result<()> {
+            self.check()?;
+            self.inner.put_raw(cid, data).await
+        }
+    }
+
+    #[async_std::test]
+    async fn test_replicated() {
+        let replicas = (0..3)
+            .map(|_| LeveldbMimeKV::memory().unwrap())
+            .collect::<Vec<_>>();
+
+        assert!(ReplicatedMimeKV::new(replicas.clone(), 4, Default::default()).is_err());
+
+        // every replica acknowledges, so none is left to the background writer.
+        let kv = ReplicatedMimeKV::new(replicas.clone(), 3, Default::default()).unwrap();
+
+        let mime = Mime {
+            id: Cid::new_v1(DagCborCodec.into(), Code::Blake2b256.digest(&b""[..])),
+            length: 1,
+            content: vec![1],
+            multipart: vec![],
+        };
+
+        let cid = kv.put(mime.clone()).await.unwrap();
+
+        // corrupt first replica and lose the second one.
+        replicas[0].put_unchecked(cid, vec![0xff]).await.unwrap();
+        replicas[1].delete_raw(cid).await.unwrap();
+
+        assert_eq!(kv.get(cid).await.unwrap().unwrap().content, vec![1]);
+
+        let report = kv.repair().await.unwrap();
+
+        assert_eq!((report.mimes, report.repaired, report.corrupted), (1, 2, 1));
+        assert!(report.unrecoverable.is_empty());
+
+        for replica in &replicas {
+            assert_eq!(replica.get(cid).await.unwrap().unwrap().content, vec![1]);
+        }
+
+        assert!(kv.delete(cid).await.unwrap().is_some());
+        assert!(!kv.contains_cid(cid).await.unwrap());
+
+        // failing replicas are skipped and counted.
+        let replicas = (0..3)
+            .map(|_| Flaky {
+                inner: LeveldbMimeKV::memory().unwrap(),
+                down: Default::default(),
+            })
+            .collect::<Vec<_>>();
+
+        let kv = ReplicatedMimeKV::new(replicas.clone(), 3, Default::default()).unwrap();
+
+        let cid = kv.put(mime).await.unwrap();
+
+        replicas[1].inner.delete_raw(cid).await.unwrap();
+        replicas[2].down.store(true, Ordering::SeqCst);
+
+        assert!(kv.contains_cid(cid).await.unwrap());
+        assert_eq!(kv.cids().await.unwrap(), vec![cid]);
+
+        let report = kv.repair().await.unwrap();
+
+        assert_eq!((report.mimes, report.repaired, report.failed), (1, 1, 2));
+        assert!(replicas[1].inner.contains_cid(cid).await.unwrap());
+
+        // a delete missing a replica is finished by repair, not undone.
+        assert!(kv.delete_raw(cid).await.is_err());
+
+        let report = kv.repair().await.unwrap();
+
+        assert_eq!((report.mimes, report.repaired, report.deleted), (0, 0, 0));
+        assert!(!replicas[0].inner.contains_cid(cid).await.unwrap());
+
+        replicas[2].down.store(false, Ordering::SeqCst);
+
+        let report = kv.repair().await.unwrap();
+
+        assert_eq!((report.mimes, report.deleted), (0, 1));
+        assert!(!replicas[2].inner.contains_cid(cid).await.unwrap());
+    }
+}
@@ -0,0 +1,300 @@
+//! Integrity scrubber for stored mime objects and timelines.
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use libipld::Cid;
+use serde::Serialize;
+
+use crate::{blocking::unblock, codec, kv::MimeKV, timeline::Timeline};
+
+/// Scrub job options.
+#[derive(Debug, Clone, Default)]
+pub struct ScrubOptions {
+    /// Move corrupted mime objects out of the kv into this directory, one file per cid.
+    ///
+    /// Corrupted objects are only reported if [`None`].
+    pub quarantine: Option<PathBuf>,
+}
+
+/// Stored mime object whose content can't be verified or decoded.
+#[derive(Debug, Clone, Serialize)]
+pub struct CorruptedMime {
+    pub cid: Cid,
+    pub error: String,
+    /// True if the object was moved to quarantine directory.
+    pub quarantined: bool,
+}
+
+/// Multipart child that is referenced by `parent` but missing from the kv.
+#[derive(Debug, Clone, Serialize)]
+pub struct MissingPart {
+    pub parent: Cid,
+    pub child: Cid,
+}
+
+/// Timeline entry that doesn't resolve through [`MimeKV::contains_cid`].
+#[derive(Debug, Clone, Serialize)]
+pub struct DanglingEntry {
+    pub mns_id: u64,
+    pub offset: u64,
+    pub cid: Cid,
+}
+
+/// Result of one scrub pass.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScrubReport {
+    /// Number of checked mime objects.
+    pub mimes: u64,
+    /// Number of checked timeline entries.
+    pub entries: u64,
+    pub corrupted: Vec<CorruptedMime>,
+    pub missing_parts: Vec<MissingPart>,
+    pub dangling_entries: Vec<DanglingEntry>,
+    /// Accounts whose timeline column can't be read, with the read error.
+    pub broken_timelines: Vec<(u64, String)>,
+}
+
+impl ScrubReport {
+    /// Returns true if no problem was found.
+    pub fn is_clean(&self) -> bool {
+        self.corrupted.is_empty()
+            && self.missing_parts.is_empty()
+            && self.dangling_entries.is_empty()
+            && self.broken_timelines.is_empty()
+    }
+}
+
+/// Run one scrub pass over `kv` and `timeline`.
+pub async fn scrub<K, T>(kv: &K, timeline: &T, options: &ScrubOptions) -> Result<ScrubReport>
+where
+    K: MimeKV + Sync,
+    T: Timeline + Sync,
+{
+    let mut report = ScrubReport::default();
+
+    for cid in kv.cids().await? {
+        // removed after listing.
+        let data = match kv.get_raw(cid).await? {
+            Some(data) => data,
+            None => continue,
+        };
+
+        report.mimes += 1;
+
+        let mime = match codec::verify(&cid, &data).and_then(|_| codec::decode(&cid, &data)) {
+            Ok(mime) => mime,
+            Err(err) => {
+                log::warn!("Scrub: mime({}) corrupted, {}", cid, err);
+
+                let quarantined = match &options.quarantine {
+                    Some(dir) => quarantine(kv, dir.clone(), cid, data).await?,
+                    None => false,
+                };
+
+                report.corrupted.push(CorruptedMime {
+                    cid,
+                    error: err.to_string(),
+                    quarantined,
+                });
+
+                continue;
+            }
+        };
+
+        let found = kv.contains_many(mime.multipart.clone()).await?;
+
+        for (child, found) in mime.multipart.into_iter().zip(found) {
+            if !found {
+                report
+                    .missing_parts
+                    .push(MissingPart { parent: cid, child });
+            }
+        }
+    }
+
+    for mns_id in timeline.accounts().await? {
+        let entries = match timeline.entries(mns_id).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                log::warn!("Scrub: account({}) timeline broken, {}", mns_id, err);
+                report.broken_timelines.push((mns_id, err.to_string()));
+                continue;
+            }
+        };
+
+        report.entries += entries.len() as u64;
+
+        let found = kv
+            .contains_many(entries.iter().map(|(_, cid)| *cid).collect())
+            .await?;
+
+        for ((offset, cid), found) in entries.into_iter().zip(found) {
+            if !found {
+                report.dangling_entries.push(DanglingEntry {
+                    mns_id,
+                    offset,
+                    cid,
+                });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Write corrupted `data` into quarantine `dir` and remove it from `kv`.
+///
+/// Returns true if the object is gone from `kv`.
+async fn quarantine<K>(kv: &K, dir: PathBuf, cid: Cid, data: Vec<u8>) -> Result<bool>
+where
+    K: MimeKV + Sync,
+{
+    unblock(move || {
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(cid.to_string()), data)?;
+
+        Ok(())
+    })
+    .await?;
+
+    kv.delete_raw(cid).await?;
+
+    Ok(!kv.contains_cid(cid).await?)
+}
+
+/// Scrub job that keeps the report of its last pass, so the hub can expose it.
+#[derive(Clone)]
+pub struct Scrubber<K, T> {
+    kv: K,
+    timeline: T,
+    options: ScrubOptions,
+    last_report: Arc<Mutex<Option<ScrubReport>>>,
+}
+
+impl<K, T> Scrubber<K, T>
+where
+    K: MimeKV + Sync,
+    T: Timeline + Sync,
+{
+    pub fn new(kv: K, timeline: T, options: ScrubOptions) -> Self {
+        Self {
+            kv,
+            timeline,
+            options,
+            last_report: Default::default(),
+        }
+    }
+
+    /// Run one scrub pass and keep its report.
+    pub async fn run(&self) -> Result<ScrubReport> {
+        let report = scrub(&self.kv, &self.timeline, &self.options).await?;
+
+        if !report.is_clean() {
+            log::error!(
+                "Scrub: corrupted({}) missing_parts({}) dangling_entries({}) broken_timelines({})",
+                report.corrupted.len(),
+                report.missing_parts.len(),
+                report.dangling_entries.len(),
+                report.broken_timelines.len()
+            );
+        }
+
+        *self.last_report.lock().unwrap() = Some(report.clone());
+
+        Ok(report)
+    }
+
+    /// Returns the report of last finished pass.
+    pub fn last_report(&self) -> Option<ScrubReport> {
+        self.last_report.lock().unwrap().clone()
+    }
+}
+
+#[cfg(all(test, feature = "leveldb_kv", feature = "leveldb_timeline"))]
+mod tests {
+    use dimsp_types::{MNSAccount, Mime};
+    use libipld::{
+        cbor::DagCborCodec,
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use crate::{
+        kv::MimeKV, leveldb_kv::LeveldbMimeKV, leveldb_timeline::LeveldbTimeline,
+        timeline::Timeline,
+    };
+
+    use super::{ScrubOptions, Scrubber};
+
+    #[async_std::test]
+    async fn test_scrub() {
+        let kv = LeveldbMimeKV::memory().unwrap();
+        let timeline = LeveldbTimeline::memory().unwrap();
+
+        let missing = Cid::new_v1(DagCborCodec.into(), Code::Keccak256.digest(&b"missing"[..]));
+
+        let cid = kv
+            .put(Mime {
+                id: missing,
+                length: 1,
+                content: vec![1],
+                multipart: vec![missing],
+            })
+            .await
+            .unwrap();
+
+        let mut mns = MNSAccount::default();
+        mns.uns.id = 1;
+
+        timeline.append(mns.clone(), cid).await.unwrap();
+        timeline.append(mns.clone(), missing).await.unwrap();
+
+        let scrubber = Scrubber::new(kv, timeline, ScrubOptions::default());
+
+        assert!(scrubber.last_report().is_none());
+
+        let report = scrubber.run().await.unwrap();
+
+        assert_eq!(report.mimes, 1);
+        assert_eq!(report.entries, 2);
+        assert!(report.corrupted.is_empty());
+
+        assert_eq!(report.missing_parts.len(), 1);
+        assert_eq!(report.missing_parts[0].parent, cid);
+        assert_eq!(report.missing_parts[0].child, missing);
+
+        assert_eq!(report.dangling_entries.len(), 1);
+        assert_eq!(report.dangling_entries[0].offset, 1);
+        assert_eq!(report.dangling_entries[0].cid, missing);
+
+        assert!(!scrubber.last_report().unwrap().is_clean());
+
+        // corrupted objects are moved to quarantine.
+        let dir = std::env::temp_dir().join(format!("dimsp-quarantine-{}", rand::random::<u64>()));
+
+        let kv = LeveldbMimeKV::memory().unwrap();
+
+        kv.put_unchecked(cid, vec![0xff]).await.unwrap();
+
+        let scrubber = Scrubber::new(
+            kv.clone(),
+            LeveldbTimeline::memory().unwrap(),
+            ScrubOptions {
+                quarantine: Some(dir.clone()),
+            },
+        );
+
+        let report = scrubber.run().await.unwrap();
+
+        assert!(report.corrupted[0].quarantined);
+        assert!(!kv.contains_cid(cid).await.unwrap());
+        assert!(dir.join(cid.to_string()).exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,39 @@
+//! Online point-in-time snapshots of storage backends.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dimsp_types::keccack256;
+use serde::{Deserialize, Serialize};
+
+/// Record count and digest of one snapshot, used to check its integrity before restore.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub records: u64,
+    /// Chained keccak256 digest over all `(key, value)` records in key order.
+    pub digest: [u8; 32],
+}
+
+impl SnapshotManifest {
+    /// Chain one record into manifest.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        let mut buff = Vec::with_capacity(48 + key.len() + value.len());
+
+        buff.extend_from_slice(&self.digest);
+        buff.extend_from_slice(&(key.len() as u64).to_be_bytes());
+        buff.extend_from_slice(key);
+        buff.extend_from_slice(&(value.len() as u64).to_be_bytes());
+        buff.extend_from_slice(value);
+
+        self.records += 1;
+        self.digest = keccack256(&buff);
+    }
+}
+
+/// Storage backend that can be copied while serving.
+#[async_trait]
+pub trait Snapshot {
+    /// Write a point-in-time copy of the store into directory `path`.
+    async fn snapshot(&self, path: PathBuf) -> Result<SnapshotManifest>;
+}
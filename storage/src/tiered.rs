@@ -0,0 +1,454 @@
+//! Hot/cold tiered [`MimeKV`], new mime objects land in the hot backend and are demoted to the
+//! cold backend once they are old and rarely read.
+//!
+//! [`FsMimeKV`](crate::fs_kv::FsMimeKV) is the filesystem cold tier, demotion passes run in the
+//! background after [`TieredMimeKV::spawn_migrate`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dimsp_types::Mime;
+use futures::{
+    future::{abortable, AbortHandle},
+    lock::Mutex as AsyncMutex,
+};
+use futures_timer::Delay;
+use libipld::Cid;
+use serde::Serialize;
+
+use crate::{blocking::spawn, kv::MimeKV};
+
+/// Demotion rules and throttle of [`TieredMimeKV::migrate`].
+#[derive(Debug, Clone, Copy)]
+pub struct TierPolicy {
+    /// Mime objects stay hot at least this long after they are written.
+    pub max_age: Duration,
+    /// Mime objects read this many times within one `max_age` window stay hot for another window.
+    pub min_reads: u64,
+    /// Max demotion rate, a pass sleeps between moves to stay below it. 0 disables the throttle.
+    pub bytes_per_sec: u64,
+    /// A pass stops once it ran this long, every pass moves at least one mime object.
+    pub max_pass_time: Duration,
+}
+
+impl Default for TierPolicy {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(7 * 24 * 3600),
+            min_reads: 2,
+            bytes_per_sec: 8 * 1024 * 1024,
+            max_pass_time: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Tier counters, reported by [`TieredMimeKV::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TierStats {
+    pub hot_reads: u64,
+    pub cold_reads: u64,
+    pub demoted: u64,
+    pub demoted_bytes: u64,
+    /// Demotion candidates left over by the last pass because of throttle.
+    pub pending: u64,
+    pub passes: u64,
+}
+
+/// Access record of one hot mime object.
+#[derive(Debug, Clone, Copy)]
+struct Heat {
+    /// Unix seconds of write, or of the start of current read window.
+    since: u64,
+    reads: u64,
+}
+
+#[derive(Default)]
+struct TierState {
+    heat: HashMap<Cid, Heat>,
+    stats: TierStats,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Composite [`MimeKV`] over a fast `hot` backend and a large `cold` backend.
+///
+/// Access records live in memory, hot mime objects found after restart start a fresh age window.
+#[derive(Clone)]
+pub struct TieredMimeKV<H, C> {
+    hot: H,
+    cold: C,
+    policy: TierPolicy,
+    state: Arc<Mutex<TierState>>,
+    /// Serializes demotion moves with deletes, so a deleted mime is never copied back to cold.
+    moving: Arc<AsyncMutex<()>>,
+}
+
+impl<H, C> TieredMimeKV<H, C>
+where
+    H: MimeKV + Send + Sync,
+    C: MimeKV + Send + Sync,
+{
+    pub fn new(hot: H, cold: C, policy: TierPolicy) -> Self {
+        Self {
+            hot,
+            cold,
+            policy,
+            state: Default::default(),
+            moving: Default::default(),
+        }
+    }
+
+    pub fn stats(&self) -> TierStats {
+        self.state.lock().unwrap().stats
+    }
+
+    fn written(&self, cid: Cid) {
+        self.state.lock().unwrap().heat.insert(
+            cid,
+            Heat {
+                since: now(),
+                reads: 0,
+            },
+        );
+    }
+
+    fn read(&self, cid: Option<Cid>) {
+        let mut state = self.state.lock().unwrap();
+
+        match cid {
+            Some(cid) => {
+                state.stats.hot_reads += 1;
+
+                state
+                    .heat
+                    .entry(cid)
+                    .or_insert(Heat {
+                        since: now(),
+                        reads: 0,
+                    })
+                    .reads += 1;
+            }
+            None => state.stats.cold_reads += 1,
+        }
+    }
+
+    /// Collect hot mime objects due for demotion, starting a new read window for the busy ones.
+    async fn candidates(&self) -> Result<Vec<Cid>> {
+        let cids = self.hot.cids().await?;
+
+        let now = now();
+
+        let mut state = self.state.lock().unwrap();
+
+        let live = cids.iter().collect::<HashSet<_>>();
+
+        state.heat.retain(|cid, _| live.contains(cid));
+
+        let mut candidates = vec![];
+
+        for cid in &cids {
+            let heat = state.heat.entry(*cid).or_insert(Heat {
+                since: now,
+                reads: 0,
+            });
+
+            if now.saturating_sub(heat.since) < self.policy.max_age.as_secs() {
+                continue;
+            }
+
+            if heat.reads >= self.policy.min_reads {
+                *heat = Heat {
+                    since: now,
+                    reads: 0,
+                };
+                continue;
+            }
+
+            candidates.push((heat.since, *cid));
+        }
+
+        // oldest first.
+        candidates.sort();
+
+        Ok(candidates.into_iter().map(|(_, cid)| cid).collect())
+    }
+
+    /// Run one demotion pass, throttled to `bytes_per_sec` and stopped after `max_pass_time`.
+    pub async fn migrate(&self) -> Result<TierStats> {
+        let candidates = self.candidates().await?;
+
+        let started = Instant::now();
+
+        let mut visited = 0;
+        let mut moved = 0;
+        let mut bytes = 0;
+
+        for cid in &candidates {
+            if visited > 0 && started.elapsed() >= self.policy.max_pass_time {
+                break;
+            }
+
+            visited += 1;
+
+            {
+                let _moving = self.moving.lock().await;
+
+                self.state.lock().unwrap().heat.remove(cid);
+
+                // deleted since the candidates were listed.
+                let Some(data) = self.hot.get_raw(*cid).await? else {
+                    continue;
+                };
+
+                bytes += data.len() as u64;
+
+                self.cold.put_unchecked(*cid, data).await?;
+                self.hot.delete_raw(*cid).await?;
+
+                moved += 1;
+            }
+
+            if self.policy.bytes_per_sec == 0 {
+                continue;
+            }
+
+            let due = Duration::from_secs_f64(bytes as f64 / self.policy.bytes_per_sec as f64);
+
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                Delay::new(wait).await;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        state.stats.demoted += moved;
+        state.stats.demoted_bytes += bytes;
+        state.stats.pending = (candidates.len() - visited) as u64;
+        state.stats.passes += 1;
+
+        log::info!(
+            "Tier migrate: moved({}) bytes({}) pending({})",
+            moved,
+            bytes,
+            state.stats.pending
+        );
+
+        Ok(state.stats)
+    }
+}
+
+impl<H, C> TieredMimeKV<H, C>
+where
+    H: MimeKV + Clone + Send + Sync + 'static,
+    C: MimeKV + Clone + Send + Sync + 'static,
+{
+    /// Run [`migrate`](TieredMimeKV::migrate) every `interval` on the storage thread pool,
+    /// until the returned handle is aborted. Failed passes are logged and retried next interval.
+    pub fn spawn_migrate(&self, interval: Duration) -> Result<AbortHandle> {
+        let kv = self.clone();
+
+        let (task, handle) = abortable(async move {
+            loop {
+                Delay::new(interval).await;
+
+                if let Err(err) = kv.migrate().await {
+                    log::error!("Tier migrate failed, {}", err);
+                }
+            }
+        });
+
+        spawn(async move {
+            _ = task.await;
+        })?;
+
+        Ok(handle)
+    }
+}
+
+#[async_trait]
+impl<H, C> MimeKV for TieredMimeKV<H, C>
+where
+    H: MimeKV + Send + Sync,
+    C: MimeKV + Send + Sync,
+{
+    async fn put(&self, mime: Mime) -> Result<Cid> {
+        let cid = self.hot.put(mime).await?;
+
+        self.written(cid);
+
+        Ok(cid)
+    }
+
+    async fn contains_cid(&self, cid: Cid) -> Result<bool> {
+        Ok(self.hot.contains_cid(cid).await? || self.cold.contains_cid(cid).await?)
+    }
+
+    async fn get(&self, cid: Cid) -> Result<Option<Mime>> {
+        if let Some(mime) = self.hot.get(cid).await? {
+            self.read(Some(cid));
+            return Ok(Some(mime));
+        }
+
+        self.read(None);
+
+        self.cold.get(cid).await
+    }
+
+    async fn delete(&self, cid: Cid) -> Result<Option<Mime>> {
+        let _moving = self.moving.lock().await;
+
+        self.state.lock().unwrap().heat.remove(&cid);
+
+        let hot = self.hot.delete(cid).await?;
+        let cold = self.cold.delete(cid).await?;
+
+        Ok(hot.or(cold))
+    }
+
+    async fn cids(&self) -> Result<Vec<Cid>> {
+        let mut cids = self.hot.cids().await?;
+
+        let hot = cids.iter().copied().collect::<HashSet<_>>();
+
+        cids.extend(
+            self.cold
+                .cids()
+                .await?
+                .into_iter()
+                .filter(|cid| !hot.contains(cid)),
+        );
+
+        Ok(cids)
+    }
+
+    async fn get_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        if let Some(data) = self.hot.get_raw(cid).await? {
+            self.read(Some(cid));
+            return Ok(Some(data));
+        }
+
+        self.read(None);
+
+        self.cold.get_raw(cid).await
+    }
+
+    async fn put_raw(&self, cid: Cid, data: Vec<u8>) -> Result<()> {
+        self.hot.put_raw(cid, data).await?;
+
+        self.written(cid);
+
+        Ok(())
+    }
+
+    async fn put_unchecked(&self, key: Cid, data: Vec<u8>) -> Result<()> {
+        self.hot.put_unchecked(key, data).await?;
+
+        self.written(key);
+
+        Ok(())
+    }
+
+    async fn delete_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        let _moving = self.moving.lock().await;
+
+        self.state.lock().unwrap().heat.remove(&cid);
+
+        let hot = self.hot.delete_raw(cid).await?;
+        let cold = self.cold.delete_raw(cid).await?;
+
+        Ok(hot.or(cold))
+    }
+}
+
+#[cfg(all(test, feature = "leveldb_kv"))]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        conformance::{mime, temp_dir},
+        fs_kv::FsMimeKV,
+        kv::MimeKV,
+        leveldb_kv::LeveldbMimeKV,
+    };
+
+    use super::{TierPolicy, TieredMimeKV};
+
+    #[async_std::test]
+    async fn test_tiered() {
+        let dir = temp_dir();
+
+        let hot = LeveldbMimeKV::memory().unwrap();
+        let cold = FsMimeKV::local(dir.path()).unwrap();
+
+        let kv = TieredMimeKV::new(
+            hot.clone(),
+            cold.clone(),
+            TierPolicy {
+                max_age: Duration::ZERO,
+                min_reads: 1,
+                max_pass_time: Duration::ZERO,
+                ..Default::default()
+            },
+        );
+
+        let busy = kv.put(mime(1)).await.unwrap();
+        let idle1 = kv.put(mime(2)).await.unwrap();
+        let idle2 = kv.put(mime(3)).await.unwrap();
+
+        kv.get(busy).await.unwrap();
+
+        // out of pass time after the first move.
+        let stats = kv.migrate().await.unwrap();
+
+        assert_eq!((stats.demoted, stats.pending), (1, 1));
+
+        kv.get(busy).await.unwrap();
+
+        let stats = kv.migrate().await.unwrap();
+
+        assert_eq!((stats.demoted, stats.pending), (2, 0));
+
+        assert!(hot.contains_cid(busy).await.unwrap());
+
+        for cid in [idle1, idle2] {
+            assert!(!hot.contains_cid(cid).await.unwrap());
+            assert!(cold.contains_cid(cid).await.unwrap());
+            assert!(kv.get(cid).await.unwrap().is_some());
+        }
+
+        assert_eq!(kv.stats().cold_reads, 2);
+        assert_eq!(kv.cids().await.unwrap().len(), 3);
+
+        kv.delete(idle1).await.unwrap();
+
+        assert!(!kv.contains_cid(idle1).await.unwrap());
+
+        // background passes demote new idle objects.
+        let handle = kv.spawn_migrate(Duration::from_millis(10)).unwrap();
+
+        let idle3 = kv.put(mime(4)).await.unwrap();
+
+        for _ in 0..100 {
+            if !hot.contains_cid(idle3).await.unwrap() {
+                break;
+            }
+
+            async_std::task::sleep(Duration::from_millis(10)).await;
+        }
+
+        handle.abort();
+
+        assert!(!hot.contains_cid(idle3).await.unwrap());
+        assert!(cold.contains_cid(idle3).await.unwrap());
+    }
+}
@@ -4,18 +4,63 @@ use libipld::Cid;
 
 use anyhow::Result;
 
+use crate::error::StorageError;
+
 /// Ipld kv database.
+///
+/// Implementations must serialize concurrent appends and cursor moves of one account themselves.
 #[async_trait]
 pub trait Timeline {
     /// Append cid into account's timeline column.
-    async fn append(&mut self, mns: MNSAccount, cid: Cid) -> Result<()>;
+    async fn append(&self, mns: MNSAccount, cid: Cid) -> Result<()>;
 
     /// Get account's first n cids.
-    async fn get(&mut self, mns: MNSAccount, first_n: u64) -> Result<Vec<Cid>>;
+    async fn get(&self, mns: MNSAccount, first_n: u64) -> Result<Vec<Cid>>;
 
     /// Move timeline cursor to next `n` cid.
     /// if out of range, the cursor will be set to the end of timeline.
-    async fn advance(&mut self, mns: MNSAccount, steps: u64) -> Result<u64>;
+    async fn advance(&self, mns: MNSAccount, steps: u64) -> Result<u64>;
+
+    async fn length(&self, mns: MNSAccount) -> Result<u64>;
+
+    /// Returns ids of all accounts that own a timeline column.
+    ///
+    /// Like the other listing and cursor methods below, the default fails with
+    /// [`Unsupported`](StorageError::Unsupported).
+    async fn accounts(&self) -> Result<Vec<u64>> {
+        Err(StorageError::Unsupported("Timeline::accounts".to_owned()).into())
+    }
+
+    /// Returns all retained `(offset, cid)` entries of account `mns_id`, ignoring client cursors.
+    async fn entries(&self, _mns_id: u64) -> Result<Vec<(u64, Cid)>> {
+        Err(StorageError::Unsupported("Timeline::entries".to_owned()).into())
+    }
+
+    /// Returns `(client_id, offset)` cursors of account `mns_id`, offsets are absolute like [`entries`](Timeline::entries).
+    async fn cursors(&self, _mns_id: u64) -> Result<Vec<(Cid, u64)>> {
+        Err(StorageError::Unsupported("Timeline::cursors".to_owned()).into())
+    }
+
+    /// Move the cursor of `mns.client_id` to absolute `offset`, clamped to the retained entries.
+    async fn set_cursor(&self, _mns: MNSAccount, _offset: u64) -> Result<()> {
+        Err(StorageError::Unsupported("Timeline::set_cursor".to_owned()).into())
+    }
+
+    /// Append `(account, cid)` entries in order.
+    ///
+    /// Implementations that can apply the entries atomically should override this, the default
+    /// appends one by one and fails with [`PartialAppend`](StorageError::PartialAppend) once some entries are in.
+    async fn append_many(&self, entries: Vec<(MNSAccount, Cid)>) -> Result<()> {
+        for (index, (mns, cid)) in entries.into_iter().enumerate() {
+            if let Err(err) = self.append(mns, cid).await {
+                if index == 0 {
+                    return Err(err);
+                }
+
+                return Err(StorageError::PartialAppend(index, err.to_string()).into());
+            }
+        }
 
-    async fn length(&mut self, mns: MNSAccount) -> Result<u64>;
+        Ok(())
+    }
 }
@@ -0,0 +1,289 @@
+//! Unit of work spanning [`MimeKV`] and [`Timeline`].
+//!
+//! Mime objects are written first, in one [`put_raw_many`](MimeKV::put_raw_many) call, and
+//! timeline entries last, in one [`append_many`](Timeline::append_many) call. If a step fails
+//! before any entry is in, the mime objects created by the transaction are deleted again.
+//!
+//! Cids touched by a commit stay locked until it finishes, so a concurrent transaction never
+//! deduplicates against an object that may still be rolled back. Objects put outside of
+//! transactions are not covered by the lock.
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet, HashSet},
+    hash::{Hash, Hasher},
+};
+
+use anyhow::Result;
+use dimsp_types::{MNSAccount, Mime};
+use futures::lock::{Mutex, MutexGuard};
+use libipld::Cid;
+use once_cell::sync::Lazy;
+
+use crate::{codec::MimeOptions, error::StorageError, kv::MimeKV, timeline::Timeline};
+
+const CID_LOCK_SHARDS: usize = 64;
+
+/// Cid lock shards shared by every transaction of the process.
+static CID_LOCKS: Lazy<Vec<Mutex<()>>> =
+    Lazy::new(|| (0..CID_LOCK_SHARDS).map(|_| Mutex::new(())).collect());
+
+/// Lock the shards of `cids` in ascending order, so overlapping commits can't deadlock.
+async fn lock_cids<'a, I>(cids: I) -> Vec<MutexGuard<'static, ()>>
+where
+    I: IntoIterator<Item = &'a Cid>,
+{
+    let shards = cids
+        .into_iter()
+        .map(|cid| {
+            let mut hasher = DefaultHasher::new();
+            Hash::hash(cid, &mut hasher);
+            hasher.finish() as usize % CID_LOCK_SHARDS
+        })
+        .collect::<BTreeSet<_>>();
+
+    let mut guards = vec![];
+
+    for shard in shards {
+        guards.push(CID_LOCKS[shard].lock().await);
+    }
+
+    guards
+}
+
+/// Staged mime objects and timeline entries, applied by [`commit`](Transaction::commit).
+#[derive(Debug, Default)]
+pub struct Transaction {
+    options: MimeOptions,
+    puts: Vec<(Cid, Vec<u8>)>,
+    appends: Vec<(MNSAccount, Cid)>,
+}
+
+impl Transaction {
+    /// Create transaction encoding staged mime objects with `options`, which should match the kv backend.
+    pub fn new(options: MimeOptions) -> Self {
+        Self {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Stage `mime`, returns the cid it will be stored under.
+    pub fn put(&mut self, mime: &Mime) -> Result<Cid> {
+        let (cid, data) = self.options.encode(mime)?;
+
+        self.puts.push((cid, data));
+
+        Ok(cid)
+    }
+
+    /// Stage encoded `data` under `cid`, it is checked on commit like [`MimeKV::put_raw`].
+    pub fn put_raw(&mut self, cid: Cid, data: Vec<u8>) {
+        self.puts.push((cid, data));
+    }
+
+    /// Stage appending `cid` to the timeline of `mns`.
+    pub fn append(&mut self, mns: MNSAccount, cid: Cid) {
+        self.appends.push((mns, cid));
+    }
+
+    /// Apply staged writes, all-or-nothing when `timeline` appends batches atomically.
+    ///
+    /// If a non-atomic timeline fails part way, the mime objects are kept so the appended entries resolve.
+    pub async fn commit<K, T>(self, kv: &K, timeline: &T) -> Result<()>
+    where
+        K: MimeKV + Sync,
+        T: Timeline + Sync,
+    {
+        let _locks = lock_cids(
+            self.puts
+                .iter()
+                .map(|(cid, _)| cid)
+                .chain(self.appends.iter().map(|(_, cid)| cid)),
+        )
+        .await;
+
+        let cids = self.puts.iter().map(|(cid, _)| *cid).collect::<Vec<_>>();
+
+        let existed = kv.contains_many(cids).await?;
+
+        let mut objects = vec![];
+        let mut seen = HashSet::new();
+
+        for ((cid, data), existed) in self.puts.into_iter().zip(existed) {
+            if !existed && seen.insert(cid) {
+                objects.push((cid, data));
+            }
+        }
+
+        let created = objects.iter().map(|(cid, _)| *cid).collect::<Vec<_>>();
+
+        // created cids were absent and are locked, deleting them can't hit another transaction.
+        if let Err(err) = kv.put_raw_many(objects).await {
+            rollback(kv, &created).await;
+            return Err(err);
+        }
+
+        if let Err(err) = timeline.append_many(self.appends).await {
+            match err.downcast_ref::<StorageError>() {
+                Some(StorageError::PartialAppend(applied, _)) => {
+                    log::error!(
+                        "Transaction left {} timeline entries appended, {}",
+                        applied,
+                        err
+                    );
+                }
+                _ => rollback(kv, &created).await,
+            }
+
+            return Err(err);
+        }
+
+        Ok(())
+    }
+}
+
+/// Delete mime objects `created` by a failed transaction, cleanup errors are logged only.
+async fn rollback<K: MimeKV + Sync>(kv: &K, created: &[Cid]) {
+    for cid in created {
+        if let Err(err) = kv.delete_raw(*cid).await {
+            log::error!("Rollback mime({}) failed, {}", cid, err);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "leveldb_kv", feature = "leveldb_timeline"))]
+mod tests {
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use dimsp_types::MNSAccount;
+    use libipld::Cid;
+
+    use crate::{
+        codec::MimeOptions, conformance::mime, error::StorageError, kv::MimeKV,
+        leveldb_kv::LeveldbMimeKV, leveldb_timeline::LeveldbTimeline, timeline::Timeline,
+    };
+
+    use super::Transaction;
+
+    /// Timeline rejecting every batch append.
+    struct RejectingTimeline;
+
+    #[async_trait]
+    impl Timeline for RejectingTimeline {
+        async fn append(&self, _: MNSAccount, _: Cid) -> Result<()> {
+            Err(anyhow::format_err!("disk full"))
+        }
+
+        async fn get(&self, _: MNSAccount, _: u64) -> Result<Vec<Cid>> {
+            unreachable!()
+        }
+
+        async fn advance(&self, _: MNSAccount, _: u64) -> Result<u64> {
+            unreachable!()
+        }
+
+        async fn length(&self, _: MNSAccount) -> Result<u64> {
+            unreachable!()
+        }
+    }
+
+    #[async_std::test]
+    async fn test_transaction() {
+        let kv = LeveldbMimeKV::memory().unwrap();
+        let timeline = LeveldbTimeline::memory().unwrap();
+
+        let mut mns1 = MNSAccount::default();
+        mns1.uns.id = 1;
+
+        let mut mns2 = MNSAccount::default();
+        mns2.uns.id = 2;
+
+        let shared = kv.put(mime(1)).await.unwrap();
+
+        let mut txn = Transaction::default();
+
+        assert_eq!(txn.put(&mime(1)).unwrap(), shared);
+
+        let cid = txn.put(&mime(2)).unwrap();
+
+        txn.append(mns1.clone(), cid);
+        txn.append(mns2.clone(), cid);
+
+        txn.commit(&kv, &timeline).await.unwrap();
+
+        assert_eq!(timeline.get(mns1.clone(), 10).await.unwrap(), vec![cid]);
+        assert_eq!(timeline.get(mns2, 10).await.unwrap(), vec![cid]);
+
+        // failed timeline step removes new mime objects only.
+        let mut txn = Transaction::default();
+
+        txn.put(&mime(1)).unwrap();
+        let orphan = txn.put(&mime(3)).unwrap();
+
+        txn.append(mns1, orphan);
+
+        assert!(txn.commit(&kv, &RejectingTimeline).await.is_err());
+
+        assert!(kv.contains_cid(shared).await.unwrap());
+        assert!(!kv.contains_cid(orphan).await.unwrap());
+
+        // a corrupted object fails the whole mime batch.
+        let mut txn = Transaction::default();
+
+        let good = txn.put(&mime(5)).unwrap();
+        let (bad, _) = MimeOptions::default().encode(&mime(6)).unwrap();
+        txn.put_raw(bad, vec![0xff]);
+
+        assert!(txn.commit(&kv, &timeline).await.is_err());
+        assert!(!kv.contains_cid(good).await.unwrap());
+
+        // partial append keeps mime objects the applied entries point to.
+        let mut mns3 = MNSAccount::default();
+        mns3.uns.id = 3;
+
+        let mut txn = Transaction::default();
+
+        let kept = txn.put(&mime(4)).unwrap();
+
+        txn.append(mns3.clone(), kept);
+        txn.append(mns3, kept);
+
+        let err = txn
+            .commit(&kv, &PartialTimeline(timeline))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::PartialAppend(1, _))
+        ));
+
+        assert!(kv.contains_cid(kept).await.unwrap());
+    }
+
+    /// Timeline failing every append after its first one, through the default non-atomic batch.
+    struct PartialTimeline(LeveldbTimeline);
+
+    #[async_trait]
+    impl Timeline for PartialTimeline {
+        async fn append(&self, mns: MNSAccount, cid: Cid) -> Result<()> {
+            if self.0.length(mns.clone()).await? > 0 {
+                return Err(anyhow::format_err!("disk full"));
+            }
+
+            self.0.append(mns, cid).await
+        }
+
+        async fn get(&self, mns: MNSAccount, first_n: u64) -> Result<Vec<Cid>> {
+            self.0.get(mns, first_n).await
+        }
+
+        async fn advance(&self, mns: MNSAccount, steps: u64) -> Result<u64> {
+            self.0.advance(mns, steps).await
+        }
+
+        async fn length(&self, mns: MNSAccount) -> Result<u64> {
+            self.0.length(mns).await
+        }
+    }
+}
@@ -0,0 +1,30 @@
+mod fs {
+    use std::path::Path;
+
+    use dimsp_storage::fs_kv::FsMimeKV;
+
+    dimsp_storage::mime_kv_conformance!(|dir: &Path| FsMimeKV::local(dir).unwrap(), durable);
+}
+
+#[cfg(all(feature = "leveldb_kv", feature = "leveldb_timeline"))]
+mod leveldb_memory {
+    use dimsp_storage::{leveldb_kv::LeveldbMimeKV, leveldb_timeline::LeveldbTimeline};
+
+    dimsp_storage::mime_kv_conformance!(|_| LeveldbMimeKV::memory().unwrap());
+
+    dimsp_storage::timeline_conformance!(|_| LeveldbTimeline::memory().unwrap());
+}
+
+#[cfg(all(feature = "leveldb_kv", feature = "leveldb_timeline"))]
+mod leveldb_local {
+    use std::path::Path;
+
+    use dimsp_storage::{leveldb_kv::LeveldbMimeKV, leveldb_timeline::LeveldbTimeline};
+
+    dimsp_storage::mime_kv_conformance!(|dir: &Path| LeveldbMimeKV::local(dir).unwrap(), durable);
+
+    dimsp_storage::timeline_conformance!(
+        |dir: &Path| LeveldbTimeline::local(dir).unwrap(),
+        durable
+    );
+}
@@ -1 +1,181 @@
+//! In-memory [`MimeKV`] and [`Timeline`] mocks, checked against the conformance suite.
 
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dimsp_storage::{
+    codec::{self, MimeOptions},
+    kv::MimeKV,
+    timeline::Timeline,
+};
+use dimsp_types::{MNSAccount, Mime};
+use libipld::Cid;
+
+#[derive(Default)]
+struct MockMimeKV {
+    objects: Mutex<BTreeMap<Cid, Vec<u8>>>,
+}
+
+#[async_trait]
+impl MimeKV for MockMimeKV {
+    async fn put(&self, mime: Mime) -> Result<Cid> {
+        let (cid, data) = MimeOptions::default().encode(&mime)?;
+
+        self.objects.lock().unwrap().insert(cid, data);
+
+        Ok(cid)
+    }
+
+    async fn contains_cid(&self, cid: Cid) -> Result<bool> {
+        Ok(self.objects.lock().unwrap().contains_key(&cid))
+    }
+
+    async fn get(&self, cid: Cid) -> Result<Option<Mime>> {
+        self.get_raw(cid)
+            .await?
+            .map(|data| codec::decode(&cid, &data))
+            .transpose()
+    }
+
+    async fn delete(&self, cid: Cid) -> Result<Option<Mime>> {
+        self.delete_raw(cid)
+            .await?
+            .map(|data| codec::decode(&cid, &data))
+            .transpose()
+    }
+
+    async fn cids(&self) -> Result<Vec<Cid>> {
+        Ok(self.objects.lock().unwrap().keys().cloned().collect())
+    }
+
+    async fn get_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        Ok(self.objects.lock().unwrap().get(&cid).cloned())
+    }
+
+    async fn put_raw(&self, cid: Cid, data: Vec<u8>) -> Result<()> {
+        codec::verify(&cid, &data)?;
+
+        self.objects.lock().unwrap().insert(cid, data);
+
+        Ok(())
+    }
+
+    async fn delete_raw(&self, cid: Cid) -> Result<Option<Vec<u8>>> {
+        Ok(self.objects.lock().unwrap().remove(&cid))
+    }
+}
+
+#[derive(Default)]
+struct MockAccount {
+    entries: Vec<Cid>,
+    cursors: HashMap<Cid, u64>,
+}
+
+#[derive(Default)]
+struct MockTimeline {
+    accounts: Mutex<BTreeMap<u64, MockAccount>>,
+}
+
+#[async_trait]
+impl Timeline for MockTimeline {
+    async fn append(&self, mns: MNSAccount, cid: Cid) -> Result<()> {
+        self.accounts
+            .lock()
+            .unwrap()
+            .entry(mns.uns.id)
+            .or_default()
+            .entries
+            .push(cid);
+
+        Ok(())
+    }
+
+    async fn get(&self, mns: MNSAccount, first_n: u64) -> Result<Vec<Cid>> {
+        let accounts = self.accounts.lock().unwrap();
+
+        let Some(account) = accounts.get(&mns.uns.id) else {
+            return Ok(vec![]);
+        };
+
+        let cursor = account.cursors.get(&mns.client_id).cloned().unwrap_or(0);
+
+        Ok(account
+            .entries
+            .iter()
+            .skip(cursor as usize)
+            .take(first_n as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn advance(&self, mns: MNSAccount, steps: u64) -> Result<u64> {
+        let mut accounts = self.accounts.lock().unwrap();
+
+        let account = accounts.entry(mns.uns.id).or_default();
+
+        let end = account.entries.len() as u64;
+
+        let cursor = account.cursors.entry(mns.client_id).or_default();
+
+        *cursor = (*cursor + steps).min(end);
+
+        Ok(end - *cursor)
+    }
+
+    async fn length(&self, mns: MNSAccount) -> Result<u64> {
+        let accounts = self.accounts.lock().unwrap();
+
+        Ok(accounts
+            .get(&mns.uns.id)
+            .map(|account| {
+                let cursor = account.cursors.get(&mns.client_id).cloned().unwrap_or(0);
+
+                account.entries.len() as u64 - cursor
+            })
+            .unwrap_or(0))
+    }
+
+    async fn accounts(&self) -> Result<Vec<u64>> {
+        Ok(self.accounts.lock().unwrap().keys().cloned().collect())
+    }
+
+    async fn entries(&self, mns_id: u64) -> Result<Vec<(u64, Cid)>> {
+        Ok(self
+            .accounts
+            .lock()
+            .unwrap()
+            .get(&mns_id)
+            .map(|account| (0u64..).zip(account.entries.iter().cloned()).collect())
+            .unwrap_or_default())
+    }
+
+    async fn cursors(&self, mns_id: u64) -> Result<Vec<(Cid, u64)>> {
+        Ok(self
+            .accounts
+            .lock()
+            .unwrap()
+            .get(&mns_id)
+            .map(|account| account.cursors.iter().map(|(k, v)| (*k, *v)).collect())
+            .unwrap_or_default())
+    }
+
+    async fn set_cursor(&self, mns: MNSAccount, offset: u64) -> Result<()> {
+        let mut accounts = self.accounts.lock().unwrap();
+
+        let account = accounts.entry(mns.uns.id).or_default();
+
+        let end = account.entries.len() as u64;
+
+        account.cursors.insert(mns.client_id, offset.min(end));
+
+        Ok(())
+    }
+}
+
+dimsp_storage::mime_kv_conformance!(|_| MockMimeKV::default());
+
+dimsp_storage::timeline_conformance!(|_| MockTimeline::default());
@@ -0,0 +1,61 @@
+//! Envelope metadata of delivered mail and inbox search queries.
+
+use libipld::{Cid, DagCbor};
+use serde::{Deserialize, Serialize};
+
+/// Client has read the message.
+pub const ENVELOPE_SEEN: u32 = 1;
+/// Client has answered the message.
+pub const ENVELOPE_ANSWERED: u32 = 1 << 1;
+/// Client has marked the message for attention.
+pub const ENVELOPE_FLAGGED: u32 = 1 << 2;
+
+/// Indexed metadata of one delivered message.
+#[derive(Debug, Default, Clone, PartialEq, Eq, DagCbor, Serialize, Deserialize)]
+pub struct Envelope {
+    /// Root mime object of the message.
+    pub cid: Cid,
+    /// UNS id of the sender account.
+    pub sender: u64,
+    /// Unix seconds the SP received the message.
+    pub received: u64,
+    /// Total bytes of the message, multipart included.
+    pub size: u64,
+    /// Root cid of the conversation the message belongs to.
+    pub thread_id: Option<Cid>,
+    /// `ENVELOPE_*` bits.
+    pub flags: u32,
+}
+
+/// Inbox search filter, unset fields match everything.
+#[derive(Debug, Default, Clone, PartialEq, Eq, DagCbor, Serialize, Deserialize)]
+pub struct EnvelopeQuery {
+    pub sender: Option<u64>,
+    /// Inclusive lower bound of `received`.
+    pub received_after: Option<u64>,
+    /// Exclusive upper bound of `received`, pass the oldest hit of the last page to fetch the next one.
+    pub received_before: Option<u64>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub thread_id: Option<Cid>,
+    /// Flags every hit must have.
+    pub flags_set: u32,
+    /// Flags no hit may have, [`ENVELOPE_SEEN`] selects unread messages.
+    pub flags_clear: u32,
+    /// Max hits returned, 0 means no limit.
+    pub limit: u64,
+}
+
+impl EnvelopeQuery {
+    /// Returns true if `envelope` passes every filter of the query.
+    pub fn matches(&self, envelope: &Envelope) -> bool {
+        self.sender.is_none_or(|s| envelope.sender == s)
+            && self.received_after.is_none_or(|t| envelope.received >= t)
+            && self.received_before.is_none_or(|t| envelope.received < t)
+            && self.min_size.is_none_or(|s| envelope.size >= s)
+            && self.max_size.is_none_or(|s| envelope.size <= s)
+            && self.thread_id.is_none_or(|t| envelope.thread_id == Some(t))
+            && envelope.flags & self.flags_set == self.flags_set
+            && envelope.flags & self.flags_clear == 0
+    }
+}
@@ -0,0 +1,44 @@
+//! Gateway handshake messages proving control of an [`MNSAccount`] public key.
+
+use libipld::{Cid, DagCbor};
+use serde::{Deserialize, Serialize};
+
+use crate::{keccack256, MNSAccount, PublicKey};
+
+/// Current handshake protocol version.
+pub const HANDSHAKE_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, DagCbor, Serialize, Deserialize)]
+pub enum Handshake {
+    /// Server challenge: (protocol version, server name, random nonce)
+    Challenge(u32, String, Vec<u8>),
+    /// Client proof: (account public key, client id, signature of [`handshake_payload`])
+    Response(PublicKey, Cid, Vec<u8>),
+    /// Server accepted the proof and bound the connection to the account.
+    Accepted(MNSAccount),
+    /// Server rejected the proof, with the reason.
+    Rejected(String),
+}
+
+/// Returns the bytes a client signs to answer a [`Challenge`](Handshake::Challenge).
+///
+/// `binding` identifies the secure channel carrying the handshake, e.g. its noise handshake
+/// hash, so a signature can't be relayed into another channel. Plain transports pass it empty.
+/// `client_id` names the client device the account key is used from.
+pub fn handshake_payload(
+    version: u32,
+    server: &str,
+    nonce: &[u8],
+    binding: &[u8],
+    client_id: &Cid,
+) -> Vec<u8> {
+    let mut buff = b"dimsp-handshake".to_vec();
+
+    buff.extend_from_slice(&version.to_be_bytes());
+    buff.extend_from_slice(&keccack256(server.as_bytes()));
+    buff.extend_from_slice(&keccack256(binding));
+    buff.extend_from_slice(&keccack256(&client_id.to_bytes()));
+    buff.extend_from_slice(nonce);
+
+    buff
+}
@@ -21,6 +21,12 @@ pub use hash::*;
 mod sync;
 pub use sync::*;
 
+mod envelope;
+pub use envelope::*;
+
+mod handshake;
+pub use handshake::*;
+
 #[derive(Default)]
 pub struct IdGenerator(Arc<AtomicU64>);
 
@@ -22,6 +22,25 @@ pub struct MNSAccount {
     pub client_id: Cid,
 }
 
+/// UNS id in the context of unauthenticated peers, never assigned to an account.
+pub const ANONYMOUS_ID: u64 = u64::MAX;
+
+impl MNSAccount {
+    /// Context of peers a gateway accepted without authentication.
+    pub fn anonymous() -> Self {
+        let mut account = Self::default();
+
+        account.uns.id = ANONYMOUS_ID;
+
+        account
+    }
+
+    /// Returns true if the account is the context of an unauthenticated peer.
+    pub fn is_anonymous(&self) -> bool {
+        self.uns.id == ANONYMOUS_ID
+    }
+}
+
 /// MNS account types enum
 #[derive(Debug, Serialize, DagCbor, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -13,6 +13,8 @@ use serde::{Deserialize, Serialize};
 pub enum PublicKeyError {
     #[error("MNS public key deserialize length({0}) error, {1}")]
     DeserializeLength(usize, String),
+    #[error("MNS public key({0}) verify signature error, {1}")]
+    Signature(String, String),
 }
 
 #[derive(Debug, Serialize, DagCbor, Deserialize, Clone, Hash, PartialEq, Eq)]
@@ -41,6 +43,68 @@ impl Default for PublicKey {
     }
 }
 
+impl PublicKey {
+    /// Verify `signature` of `message` signed by the private key of this public key.
+    ///
+    /// * RSA keys hold the modulus with public exponent 65537, signatures are PKCS#1 v1.5 over
+    ///   the sha256 digest.
+    /// * ECDSA keys are compressed secp256k1 points, signatures are `r || s` over the keccak256
+    ///   digest, a trailing recovery id is ignored.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), PublicKeyError> {
+        let result = match self {
+            Self::RSA1024(value) => verify_rsa(&value.0, message, signature),
+            Self::RSA2048(value) => verify_rsa(&value.0, message, signature),
+            Self::RSA4096(value) => verify_rsa(&value.0, message, signature),
+            Self::Ed25519(value) => verify_ed25519(&value.0, message, signature),
+            Self::ECDSA(value) => verify_ecdsa(&value.0, message, signature),
+        };
+
+        result.map_err(|err| PublicKeyError::Signature(self.to_string(), err))
+    }
+}
+
+fn verify_rsa(modulus: &[u8], message: &[u8], signature: &[u8]) -> Result<(), String> {
+    use rsa::{
+        sha2::{Digest, Sha256},
+        BigUint, Pkcs1v15Sign, RsaPublicKey,
+    };
+
+    let key = RsaPublicKey::new(BigUint::from_bytes_be(modulus), BigUint::from(65537u32))
+        .map_err(|err| err.to_string())?;
+
+    key.verify(
+        Pkcs1v15Sign::new::<Sha256>(),
+        &Sha256::digest(message),
+        signature,
+    )
+    .map_err(|err| err.to_string())
+}
+
+fn verify_ed25519(key: &[u8; 32], message: &[u8], signature: &[u8]) -> Result<(), String> {
+    use ed25519_dalek::{Signature, VerifyingKey};
+
+    let key = VerifyingKey::from_bytes(key).map_err(|err| err.to_string())?;
+
+    let signature = Signature::from_slice(signature).map_err(|err| err.to_string())?;
+
+    key.verify_strict(message, &signature)
+        .map_err(|err| err.to_string())
+}
+
+fn verify_ecdsa(key: &[u8; 33], message: &[u8], signature: &[u8]) -> Result<(), String> {
+    use k256::ecdsa::{signature::hazmat::PrehashVerifier, Signature, VerifyingKey};
+
+    let key = VerifyingKey::from_sec1_bytes(key).map_err(|err| err.to_string())?;
+
+    let signature = match signature.len() {
+        64 | 65 => Signature::from_slice(&signature[..64]).map_err(|err| err.to_string())?,
+        len => return Err(format!("invalid signature length({})", len)),
+    };
+
+    key.verify_prehash(&crate::keccack256(message), &signature)
+        .map_err(|err| err.to_string())
+}
+
 impl Display for PublicKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
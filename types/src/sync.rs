@@ -1,14 +1,60 @@
 use libipld::{Cid, DagCbor};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, DagCbor, Serialize, Deserialize)]
+use crate::{Envelope, EnvelopeQuery};
+
+#[derive(Debug, Clone, DagCbor, Serialize, Deserialize)]
 pub enum SyncMessage {
-    /// Try push one message referenced by [`cid`](Cid)
-    Push(u64, Cid),
+    /// Try push one message referenced by [`cid`](Cid) to the inbox of the `u64` mns id.
+    Push(u64, u64, Cid),
     /// Pull cid content multipart
     PullMultipart(u64, Cid),
     /// [`PullMultipart`](SyncMessage::PullMultipart) response
     PullMultipartContent(u64, Mime),
+    /// Search inbox envelopes.
+    Search(u64, EnvelopeQuery),
+    /// [`Search`](SyncMessage::Search) response, newest first.
+    SearchResult(u64, Vec<Envelope>),
+    /// Replace `ENVELOPE_*` flags of the message referenced by [`cid`](Cid).
+    SetFlags(u64, Cid, u32),
+    /// Connection liveness probe, answered with a [`Pong`](SyncMessage::Pong) of the same id.
+    Ping(u64),
+    /// [`Ping`](SyncMessage::Ping) response.
+    Pong(u64),
+    /// Failure response to the request of the same id, with the reason.
+    Error(u64, String),
+}
+
+impl SyncMessage {
+    /// Returns request id of the message, responses carry the id of their request.
+    pub fn id(&self) -> u64 {
+        match self {
+            Self::Push(id, _, _)
+            | Self::PullMultipart(id, _)
+            | Self::PullMultipartContent(id, _)
+            | Self::Search(id, _)
+            | Self::SearchResult(id, _)
+            | Self::SetFlags(id, _, _)
+            | Self::Ping(id)
+            | Self::Pong(id)
+            | Self::Error(id, _) => *id,
+        }
+    }
+
+    /// Returns variant name of the message, for logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Push(..) => "Push",
+            Self::PullMultipart(..) => "PullMultipart",
+            Self::PullMultipartContent(..) => "PullMultipartContent",
+            Self::Search(..) => "Search",
+            Self::SearchResult(..) => "SearchResult",
+            Self::SetFlags(..) => "SetFlags",
+            Self::Ping(_) => "Ping",
+            Self::Pong(_) => "Pong",
+            Self::Error(..) => "Error",
+        }
+    }
 }
 
 #[derive(Debug, DagCbor, Clone, Serialize, Deserialize)]